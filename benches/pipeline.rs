@@ -0,0 +1,127 @@
+//! Wall time and peak heap usage of expanding multi-megabyte token streams, run with `cargo bench`.
+//!
+//! Compare `cargo bench` with `cargo bench --no-default-features`, which streams the unformatted
+//! tokens into the file. With feature `pretty`, the `whole file` case formats the complete syntax
+//! tree at once, as the pipeline did before formatting items one at a time.
+
+use expander::Expander;
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// Tracks the current and peak number of allocated bytes.
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn allocated(size: usize) {
+    let current = CURRENT.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(current, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+            allocated(new_size);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// `items` structs with an impl each, about 200 bytes per item once serialized.
+fn tokens(items: usize) -> TokenStream {
+    (0..items)
+        .map(|i| {
+            let name = format_ident!("Item{}", i);
+            quote! {
+                #[derive(Debug, Clone)]
+                pub struct #name {
+                    pub a: u32,
+                    pub b: Vec<String>,
+                }
+                impl #name {
+                    pub fn get(&self) -> u32 {
+                        self.a + #i as u32
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+fn measure(label: &str, items: usize, expand: impl FnOnce(TokenStream, &Path)) {
+    let dest_dir = std::env::temp_dir().join("expander-bench");
+    std::fs::create_dir_all(&dest_dir).expect("Temporary directory is writable. qed");
+    let tokens = tokens(items);
+    let size = tokens.to_string().len();
+
+    let baseline = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    let start = Instant::now();
+    expand(tokens, &dest_dir);
+    let elapsed = start.elapsed();
+    let peak = PEAK.load(Ordering::Relaxed) - baseline;
+
+    println!(
+        "{:<12} {:>7} items {:>8.1} MiB {:>10.1?} {:>8.1} MiB peak ({:.1}x)",
+        label,
+        items,
+        size as f64 / (1 << 20) as f64,
+        elapsed,
+        peak as f64 / (1 << 20) as f64,
+        peak as f64 / size as f64
+    );
+}
+
+/// Expand `tokens` into `dest_dir` with `expander`.
+fn write_to(expander: Expander) -> impl FnOnce(TokenStream, &Path) {
+    move |tokens, dest_dir| {
+        expander
+            .write_to(tokens, dest_dir)
+            .expect("Expansion succeeds. qed");
+    }
+}
+
+/// Parse and format `tokens` as a whole, then write them into `dest_dir`.
+#[cfg(feature = "pretty")]
+fn whole_file(tokens: TokenStream, dest_dir: &Path) {
+    let file = syn::parse2::<syn::File>(tokens).expect("Tokens form a file. qed");
+    let formatted = prettyplease::unparse(&file);
+    std::fs::write(dest_dir.join("bench-whole.rs"), formatted)
+        .expect("Temporary directory is writable. qed");
+}
+
+fn main() {
+    for items in [10_000, 100_000] {
+        #[cfg(feature = "pretty")]
+        measure("whole file", items, whole_file);
+        measure("default", items, write_to(Expander::new("bench")));
+        measure(
+            "line endings",
+            items,
+            write_to(Expander::new("bench").line_ending(expander::LineEnding::CrLf)),
+        );
+    }
+}
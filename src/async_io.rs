@@ -0,0 +1,218 @@
+//! Non-blocking writing for build scripts running within a `tokio` runtime, see
+//! [`Expander::write_to_async`].
+
+use crate::{
+    debug, diagnostics, escape, format_in_process, info, is_read_only, rustfmt_command,
+    rustfmt_formatted, sandbox, sync_dir, Expander, Formatter, RustFmt,
+};
+use proc_macro2::TokenStream;
+use quote::ToTokens;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+impl Expander {
+    /// Create a file with `self.filename` in `dest_dir` like [`write_to`](Self::write_to), but
+    /// spawn `rustfmt` and write the file without blocking the executor, with feature `tokio`.
+    ///
+    /// Formatting with prettyplease is CPU bound and runs on the current task. Configurations
+    /// beyond plainly writing a file, i.e. [`merge`](Self::merge), [`check_only`](Self::check_only),
+    /// [`dedup`](Self::dedup), [`cache`](Self::cache), [`memoize`](Self::memoize), protected files,
+    /// [`transform`](Self::transform)s and validation, fall back to the blocking
+    /// [`write_to`](Self::write_to). The future is not `Send`, just like token streams, so await it
+    /// rather than spawning it.
+    pub async fn write_to_async(
+        &self,
+        tokens: impl ToTokens,
+        dest_dir: &Path,
+    ) -> Result<TokenStream, std::io::Error> {
+        let _quiet = diagnostics::Quiet::enter(self.quiet);
+        let tokens = tokens.into_token_stream();
+        if self.dry || self.inline(&tokens) {
+            return Ok(tokens);
+        }
+        if !self.writes_async() || (self.sandbox_fallback && sandbox(dest_dir).is_some()) {
+            return self.write_to(tokens, dest_dir);
+        }
+        match self.expand_async(tokens.clone(), dest_dir).await {
+            Ok(_) if self.tee => Ok(tokens),
+            Ok(expanded) if self.inline_switch && self.emit.is_rust() => {
+                Ok(self.switch(tokens, expanded))
+            }
+            Ok(expanded) => Ok(expanded),
+            Err(e) if self.fallback_inline => self.fall_back(e, tokens),
+            Err(e) if self.sandbox_fallback && is_read_only(&e) => self
+                .write_sandboxed(tokens, dest_dir, "a read-only filesystem")
+                .map(|(_, tokens)| tokens),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// If nothing but formatting and writing a file is required, see
+    /// [`write_to_async`](Self::write_to_async).
+    fn writes_async(&self) -> bool {
+        let validates = {
+            #[cfg(any(feature = "validate", test))]
+            {
+                self.validate || self.read_back
+            }
+            #[cfg(not(any(feature = "validate", test)))]
+            false
+        };
+        let span_map = {
+            #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+            {
+                self.span_map
+            }
+            #[cfg(not(any(feature = "spans", all(test, feature = "pretty"))))]
+            false
+        };
+        !validates
+            && !span_map
+            && !(self.merge
+                || self.check_only
+                || self.cache
+                || self.dedup
+                || self.memoize
+                || self.debug_stages
+                || self.read_only)
+            && self.file_mode.is_none()
+            && self.format_chain.is_none()
+            && self.transforms.is_empty()
+    }
+
+    async fn expand_async(
+        &self,
+        tokens: TokenStream,
+        dest_dir: &Path,
+    ) -> Result<TokenStream, std::io::Error> {
+        let dest_dir = &self.dest_dir(dest_dir, true)?;
+        self.check_inner_attrs()?;
+        let tokens = escape::escape(self.attribute(tokens))?;
+        let input_digest = self.input_digest(|| tokens.to_string());
+        let input = self.hash_input.then(|| tokens.to_string());
+        let dest = dest_dir.join(&self.filename_base);
+        let started = std::time::Instant::now();
+        let (bytes, formatter) = match format_in_process(tokens, &dest, self.verbose, self.strict)?
+        {
+            Ok(formatted) => (formatted, Formatter::PrettyPlease),
+            Err((token_str, message)) => self.run_rustfmt_async(message, token_str).await?,
+        };
+        self.check_fmt_budget(started, &dest, formatter);
+        let bytes = self.convert_line_endings(bytes);
+        let header = self.report_size(self.render_header(input_digest.as_deref())?, &bytes);
+        let dest = self.prepare_destination(&dest, |digester| {
+            digester.update(input.as_ref().map_or(&bytes, |input| input.as_bytes()))
+        })?;
+        // a different digest than the one of the previous expansion
+        let changed = self.verbose && !dest.exists();
+        if self.verbose {
+            info(format_args!("writing {}", dest.display()));
+        }
+        write_file(&dest, header.as_deref(), &bytes, self.sync).await?;
+        if changed {
+            self.summarize_changes(&dest)?;
+        }
+        let tokens = self.emit.reference(&dest, self.anchor(&dest))?;
+        self.record(dest_dir, &dest, input_digest, formatter)?;
+        Ok(self.gate(tokens))
+    }
+
+    /// Format `token_str` with `rustfmt` if requested, announced by `message`.
+    async fn run_rustfmt_async(
+        &self,
+        message: &str,
+        token_str: String,
+    ) -> Result<(Vec<u8>, Formatter), std::io::Error> {
+        if let RustFmt::Yes {
+            channel,
+            edition,
+            allow_failure,
+            ref unstable,
+        } = self.rustfmt()
+        {
+            if self.verbose {
+                debug(message);
+            }
+            let command = rustfmt_command(channel, edition, unstable);
+            if let Some(formatted) =
+                run_rustfmt(token_str.as_bytes(), command, allow_failure).await?
+            {
+                return Ok((formatted, Formatter::RustFmt));
+            }
+        }
+        Ok((token_str.into_bytes(), Formatter::Raw))
+    }
+}
+
+/// Asynchronously run `rustfmt` on `content`, see `run_rustfmt_on_content`.
+async fn run_rustfmt(
+    content: &[u8],
+    command: std::process::Command,
+    allow_failure: bool,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let mut child = tokio::process::Command::from(command)
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdin = child.stdin.take();
+    let write = async move {
+        match stdin {
+            // Dropping stdin signals EOF to rustfmt
+            Some(mut stdin) => stdin.write_all(content).await,
+            None => Ok(()),
+        }
+    };
+    let (written, output) = tokio::join!(write, child.wait_with_output());
+    match written {
+        // rustfmt may exit early without reading everything, its status tells why
+        Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e),
+        _ => {}
+    }
+    rustfmt_formatted(content, output?, allow_failure)
+}
+
+/// Write the `header` followed by `bytes` to `dest`, via a partial file renamed into place, so
+/// concurrent readers never observe partial content.
+async fn write_file(
+    dest: &Path,
+    header: Option<&str>,
+    bytes: &[u8],
+    sync: bool,
+) -> Result<(), std::io::Error> {
+    let partial = partial_path(dest);
+    let written = async {
+        let mut file = tokio::fs::File::create(&partial).await?;
+        if let Some(header) = header {
+            file.write_all(header.as_bytes()).await?;
+        }
+        file.write_all(bytes).await?;
+        file.flush().await?;
+        if sync {
+            file.sync_all().await?;
+        }
+        drop(file);
+        match tokio::fs::rename(&partial, dest).await {
+            // identical content, opened by a different crate on windows
+            Err(_) if tokio::fs::try_exists(dest).await.unwrap_or(false) => {
+                tokio::fs::remove_file(&partial).await
+            }
+            renamed => renamed,
+        }
+    };
+    if let Err(e) = written.await {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+    if sync {
+        sync_dir(dest)?;
+    }
+    Ok(())
+}
+
+/// Hidden sibling of `dest` the content is written to first.
+fn partial_path(dest: &Path) -> PathBuf {
+    let name = dest
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    dest.with_file_name(format!(".{}.{}.partial", name, std::process::id()))
+}
@@ -0,0 +1,178 @@
+//! Inspect and clean files written by `expander`, based on the manifest recorded with
+//! `Expander::manifest(true)`.
+
+use expander::manifest::{manifest_file, Manifest};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: expander-cli [--manifest <path>] <command>
+
+Commands:
+    list                    List all recorded expansions
+    show <filename-base>    Print the most recently recorded expansion of <filename-base>
+    diff <old> <new>        Compare two manifests, i.e. of two different runs
+    clean [--dry-run]       Delete generated files superseded by a later run of the build
+
+The manifest defaults to `expander-manifest.json` in `$CARGO_TARGET_DIR` or `./target`.";
+
+fn main() -> ExitCode {
+    match run(std::env::args().skip(1).collect()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("expander-cli: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(mut args: Vec<String>) -> Result<(), String> {
+    let manifest = match args.iter().position(|arg| arg == "--manifest") {
+        Some(idx) => {
+            args.remove(idx);
+            if idx >= args.len() {
+                return Err("missing path for `--manifest`".to_owned());
+            }
+            PathBuf::from(args.remove(idx))
+        }
+        None => {
+            let target = std::env::var_os("CARGO_TARGET_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("target"));
+            manifest_file(&target)
+        }
+    };
+
+    let mut args = args.into_iter();
+    match (args.next().as_deref(), args.next(), args.next()) {
+        (Some("list"), None, None) => list(&read(&manifest)?),
+        (Some("show"), Some(filename_base), None) => show(&read(&manifest)?, &filename_base),
+        (Some("diff"), Some(old), Some(new)) => {
+            diff(&read(Path::new(&old))?, &read(Path::new(&new))?);
+            Ok(())
+        }
+        (Some("clean"), dry_run, None)
+            if dry_run.as_deref().map_or(true, |arg| arg == "--dry-run") =>
+        {
+            clean(&manifest, dry_run.is_some())
+        }
+        (Some("help") | Some("--help") | Some("-h"), None, None) => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("invalid arguments\n\n{}", USAGE)),
+    }
+}
+
+fn read(path: &Path) -> Result<Manifest, String> {
+    Manifest::read(path).map_err(|e| format!("failed to read manifest {}: {}", path.display(), e))
+}
+
+fn list(manifest: &Manifest) -> Result<(), String> {
+    for entry in manifest.entries() {
+        println!(
+            "{:>10}  {:<24}  {}",
+            entry.size,
+            entry.crate_name.as_deref().unwrap_or("-"),
+            entry.path.display()
+        );
+    }
+    println!(
+        "{} expansions, {} bytes in total",
+        manifest.entries().len(),
+        manifest.total_size()
+    );
+    Ok(())
+}
+
+fn show(manifest: &Manifest, filename_base: &str) -> Result<(), String> {
+    let entry = manifest
+        .by_filename_base(filename_base)
+        .last()
+        .ok_or_else(|| format!("no expansion recorded for `{}`", filename_base))?;
+    let content = std::fs::read_to_string(&entry.path)
+        .map_err(|e| format!("failed to read {}: {}", entry.path.display(), e))?;
+    print!("{}", content);
+    Ok(())
+}
+
+fn diff(old: &Manifest, new: &Manifest) {
+    let old_entries = old
+        .entries()
+        .iter()
+        .map(|entry| (&entry.path, entry))
+        .collect::<HashMap<_, _>>();
+    let new_entries = new
+        .entries()
+        .iter()
+        .map(|entry| (&entry.path, entry))
+        .collect::<HashMap<_, _>>();
+
+    for entry in old.entries() {
+        if !new_entries.contains_key(&entry.path) {
+            println!("- {} ({} bytes)", entry.path.display(), entry.size);
+        }
+    }
+    for entry in new.entries() {
+        match old_entries.get(&entry.path) {
+            None => println!("+ {} ({} bytes)", entry.path.display(), entry.size),
+            Some(old) if old.digest != entry.digest => println!(
+                "~ {} ({} -> {} bytes)",
+                entry.path.display(),
+                old.size,
+                entry.size
+            ),
+            Some(_) => {}
+        }
+    }
+    let (old_size, new_size) = (old.total_size(), new.total_size());
+    println!(
+        "{} -> {} expansions, {} -> {} bytes in total",
+        old.entries().len(),
+        new.entries().len(),
+        old_size,
+        new_size
+    );
+}
+
+/// Suffixes of the sidecars written next to a generated file.
+const SIDECARS: &[&str] = &[".meta.json", ".spans.json", ".diff"];
+
+/// Delete the files recorded in the manifest at `path` which a later run superseded, together
+/// with their sidecars, and drop them from the manifest.
+///
+/// Files the manifest never recorded are left alone, they might belong to expanders without
+/// manifest or other macros sharing the directory.
+fn clean(path: &Path, dry_run: bool) -> Result<(), String> {
+    let mut manifest = read(path)?;
+    let superseded = manifest
+        .superseded()
+        .map(|entry| entry.path.clone())
+        .collect::<Vec<_>>();
+    for file in superseded {
+        let sidecars = SIDECARS.iter().map(|suffix| {
+            let mut sidecar = file.clone().into_os_string();
+            sidecar.push(suffix);
+            PathBuf::from(sidecar)
+        });
+        for file in std::iter::once(file.clone()).chain(sidecars) {
+            if !file.is_file() {
+                continue;
+            }
+            println!("removing {}", file.display());
+            if !dry_run {
+                std::fs::remove_file(&file)
+                    .map_err(|e| format!("failed to remove {}: {}", file.display(), e))?;
+            }
+        }
+    }
+
+    if !dry_run {
+        manifest.retain_existing();
+        manifest
+            .write(path)
+            .map_err(|e| format!("failed to write manifest {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
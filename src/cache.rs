@@ -0,0 +1,136 @@
+//! Cache of formatted expansions shared by all crates of a workspace, see
+//! [`Expander::cache`](crate::Expander::cache).
+
+use crate::fs;
+use crate::{Channel, FormatChain, Formatter, Hasher, RustFmt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Formatted expansions keyed by the digest of the unformatted tokens, the formatter settings and
+/// the toolchain, stored as `{key}.rs` with the formatter on the first line.
+#[derive(Debug)]
+pub(crate) struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// The cache in the [`CACHE_DIR`](crate::CACHE_DIR) override or the `expander-cache` directory
+    /// within the cargo target directory `out_dir` is located in.
+    pub(crate) fn locate(out_dir: &Path) -> Option<Self> {
+        let dir = match std::env::var_os(crate::CACHE_DIR) {
+            Some(dir) => PathBuf::from(dir),
+            // `{target}/{profile}/build/{crate}-{hash}/out`
+            None => out_dir
+                .ancestors()
+                .nth(4)
+                .filter(|_| crate::profile_of(out_dir).is_some())?
+                .join("expander-cache"),
+        };
+        Some(Self { dir })
+    }
+
+    /// Return the cached formatting of `token_str`, or format it with `format` and cache the result.
+    ///
+    /// Concurrent invocations with identical keys wait for each other, rather than formatting twice.
+    pub(crate) fn get_or_format(
+        &self,
+        token_str: &str,
+        rustfmt: &RustFmt,
+        chain: Option<&FormatChain>,
+        format: impl FnOnce() -> Result<(Vec<u8>, Formatter), std::io::Error>,
+    ) -> Result<(Vec<u8>, Formatter), std::io::Error> {
+        let key = key(token_str, rustfmt, chain);
+        let locks = self.dir.join("locks");
+        fs::create_dir_all(&locks)?;
+        let mut lock = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(locks.join(format!("{}.lock", &key[..2])))?;
+        let _lock = crate::lock(&mut lock, 1)?;
+
+        let entry = self.dir.join(format!("{}.rs", key));
+        if let Some(cached) = read(&entry)? {
+            return Ok(cached);
+        }
+        let (bytes, formatter) = format()?;
+        // readers without lock never observe a partially written entry
+        let partial = entry.with_extension(format!("rs.{}", std::process::id()));
+        let mut content = format!("{}\n", formatter).into_bytes();
+        content.extend_from_slice(&bytes);
+        fs::write(&partial, content)?;
+        fs::rename(&partial, &entry)?;
+        Ok((bytes, formatter))
+    }
+}
+
+/// Read the cache `entry`, `None` if it does not exist or is not a valid entry.
+fn read(entry: &Path) -> Result<Option<(Vec<u8>, Formatter)>, std::io::Error> {
+    let mut content = match fs::read(entry) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let Some(newline) = content.iter().position(|&c| c == b'\n') else {
+        return Ok(None);
+    };
+    let formatter = match &content[..newline] {
+        b"prettyplease" => Formatter::PrettyPlease,
+        b"rustfmt" => Formatter::RustFmt,
+        b"raw" => Formatter::Raw,
+        b"transform" => Formatter::Transform,
+        _ => return Ok(None),
+    };
+    content.drain(..=newline);
+    Ok(Some((content, formatter)))
+}
+
+/// Hex encoded digest of everything the formatted content depends on.
+fn key(token_str: &str, rustfmt: &RustFmt, chain: Option<&FormatChain>) -> String {
+    let mut digester = Hasher::default().digester(&[]);
+    for part in [
+        env!("CARGO_PKG_VERSION"),
+        if cfg!(feature = "pretty") {
+            "pretty"
+        } else {
+            ""
+        },
+        &format!("{:?}", rustfmt),
+        &format!("{:?}", chain),
+        &toolchain(rustfmt),
+    ] {
+        digester.update(part.as_bytes());
+        digester.update(&[0]);
+    }
+    digester.update(token_str.as_bytes());
+    digester.suffix(64)
+}
+
+/// Identifies the `rustfmt` toolchain, queried once per channel and process.
+pub(crate) fn toolchain(rustfmt: &RustFmt) -> String {
+    static VERSIONS: Mutex<Vec<(Channel, String)>> = Mutex::new(Vec::new());
+
+    let RustFmt::Yes { channel, .. } = *rustfmt else {
+        return String::new();
+    };
+    let mut versions = VERSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, version)) = versions.iter().find(|(known, _)| *known == channel) {
+        return version.clone();
+    }
+    let mut process = std::process::Command::new("rustfmt");
+    if Channel::Default != channel {
+        process.arg(channel.to_string());
+    }
+    let version = process
+        .arg("--version")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .unwrap_or_default();
+    let version = format!(
+        "{} {}",
+        std::env::var("RUSTUP_TOOLCHAIN").unwrap_or_default(),
+        version.trim()
+    );
+    versions.push((channel, version.clone()));
+    version
+}
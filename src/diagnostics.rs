@@ -0,0 +1,126 @@
+//! Leveled messages on `stderr`, consistently prefixed to stand out in the interleaved output of
+//! parallel builds.
+
+use std::cell::Cell;
+#[cfg(test)]
+use std::cell::RefCell;
+use std::env;
+use std::fmt::Display;
+
+/// Severity of a message, determining its prefix and color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Level {
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+
+    /// ANSI SGR parameters of the prefix.
+    fn color(self) -> &'static str {
+        match self {
+            Self::Warn => "1;33",
+            Self::Info => "1;36",
+            Self::Debug => "2",
+        }
+    }
+}
+
+/// Print `message` at `level` to `stderr`.
+pub(crate) fn emit(level: Level, message: impl Display) {
+    if quiet() {
+        return;
+    }
+    #[cfg(test)]
+    {
+        let rendered = render(level, &message, false);
+        let captured = CAPTURED.with(|captured| {
+            captured
+                .borrow_mut()
+                .as_mut()
+                .map(|messages| messages.push(rendered))
+        });
+        if captured.is_some() {
+            return;
+        }
+    }
+    eprintln!("{}", render(level, message, colored()));
+}
+
+/// Print `message` at info level, for progress reported in verbose mode.
+pub(crate) fn info(message: impl Display) {
+    emit(Level::Info, message);
+}
+
+/// Print `message` at debug level, for details of the inner workings reported in verbose mode.
+pub(crate) fn debug(message: impl Display) {
+    emit(Level::Debug, message);
+}
+
+/// Prefix `message` with the `level`, colored if requested.
+pub(crate) fn render(level: Level, message: impl Display, colored: bool) -> String {
+    if colored {
+        format!(
+            "\x1b[{}mexpander {}:\x1b[0m {}",
+            level.color(),
+            level.label(),
+            message
+        )
+    } else {
+        format!("expander {}: {}", level.label(), message)
+    }
+}
+
+/// If [`COLOR`](crate::COLOR) asks for colors and `NO_COLOR` does not veto them.
+fn colored() -> bool {
+    let no_color = env::var_os("NO_COLOR").map_or(false, |no_color| !no_color.is_empty());
+    let color = env::var(crate::COLOR).map_or(false, |color| color == "always");
+    color && !no_color
+}
+
+thread_local! {
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Silences all messages of the current thread while alive, see
+/// [`Expander::quiet`](crate::Expander::quiet).
+pub(crate) struct Quiet(bool);
+
+impl Quiet {
+    pub(crate) fn enter(quiet: bool) -> Self {
+        Self(QUIET.with(|silenced| silenced.replace(silenced.get() || quiet)))
+    }
+}
+
+impl Drop for Quiet {
+    fn drop(&mut self) {
+        QUIET.with(|silenced| silenced.set(self.0));
+    }
+}
+
+/// If messages are silenced, by [`Quiet`] or the [`QUIET`](crate::QUIET) environment variable.
+pub(crate) fn quiet() -> bool {
+    QUIET.with(Cell::get) || env::var_os(crate::QUIET).map_or(false, |quiet| quiet != "0")
+}
+
+#[cfg(test)]
+thread_local! {
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, collecting the uncolored messages of the current thread rather than printing them.
+#[cfg(test)]
+pub(crate) fn capture<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+    let outer = CAPTURED.with(|captured| captured.replace(Some(Vec::new())));
+    let result = f();
+    let messages = CAPTURED.with(|captured| captured.replace(outer));
+    (result, messages.unwrap_or_default())
+}
@@ -0,0 +1,179 @@
+//! Line based diffs between two versions of generated content.
+
+/// Classification of a line in a diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Change {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Shortest edit script between `old` and `new`, using the algorithm by Myers.
+pub(crate) fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Change, &'a str)> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let offset = n + m + 1;
+    let mut v = vec![0_isize; 2 * offset as usize + 1];
+    // furthest reaching `x` per diagonal `k` in `-d..=d` before each round `d`
+    let mut trace = Vec::new();
+
+    'outer: for d in 0..=(n + m) {
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let idx = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+        }
+    }
+
+    let (mut x, mut y) = (n, m);
+    let mut changes = Vec::with_capacity((n + m) as usize);
+    for (d, snapshot) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        if d == 0 {
+            while x > 0 && y > 0 {
+                x -= 1;
+                y -= 1;
+                changes.push((Change::Equal, old[x as usize]));
+            }
+            break;
+        }
+        let furthest = |k: isize| snapshot[(k + d) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && furthest(k - 1) < furthest(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = furthest(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            changes.push((Change::Equal, old[x as usize]));
+        }
+        if x == prev_x {
+            changes.push((Change::Insert, new[(y - 1) as usize]));
+        } else {
+            changes.push((Change::Delete, old[(x - 1) as usize]));
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    changes.reverse();
+    changes
+}
+
+/// One line summary of the differences between `old` and `new`, the number of added and removed
+/// lines and the first differing line, `None` if both are identical.
+pub(crate) fn summary(old: &str, new: &str) -> Option<String> {
+    let old_lines = old.lines().collect::<Vec<_>>();
+    let new_lines = new.lines().collect::<Vec<_>>();
+    let changes = diff_lines(&old_lines, &new_lines);
+    let added = changes.iter().filter(|(c, _)| *c == Change::Insert).count();
+    let removed = changes.iter().filter(|(c, _)| *c == Change::Delete).count();
+    // line number in the new content
+    let mut line = 1;
+    for (change, content) in &changes {
+        match change {
+            Change::Equal => line += 1,
+            Change::Delete | Change::Insert => {
+                let marker = if *change == Change::Delete { '-' } else { '+' };
+                return Some(format!(
+                    "+{} -{} lines, first difference at line {}: {}{}",
+                    added,
+                    removed,
+                    line,
+                    marker,
+                    content.trim()
+                ));
+            }
+        }
+    }
+    None
+}
+
+/// Render the differences between `old` and `new` in unified diff format with three lines of context.
+///
+/// Returns an empty string if both are identical.
+pub(crate) fn unified(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    const CONTEXT: usize = 3;
+
+    let old_lines = old.lines().collect::<Vec<_>>();
+    let new_lines = new.lines().collect::<Vec<_>>();
+    let changes = diff_lines(&old_lines, &new_lines);
+
+    // line numbers of both sides before each change
+    let mut positions = Vec::with_capacity(changes.len() + 1);
+    let (mut old_line, mut new_line) = (0, 0);
+    for (change, _) in &changes {
+        positions.push((old_line, new_line));
+        match change {
+            Change::Equal => {
+                old_line += 1;
+                new_line += 1;
+            }
+            Change::Delete => old_line += 1,
+            Change::Insert => new_line += 1,
+        }
+    }
+    positions.push((old_line, new_line));
+
+    let mut out = String::new();
+    let mut idx = 0;
+    while idx < changes.len() {
+        if changes[idx].0 == Change::Equal {
+            idx += 1;
+            continue;
+        }
+        let start = idx.saturating_sub(CONTEXT);
+        // extend the hunk as long as changes are separated by at most twice the context
+        let mut end = idx;
+        let mut equal_run = 0;
+        while end < changes.len() && equal_run <= 2 * CONTEXT {
+            if changes[end].0 == Change::Equal {
+                equal_run += 1;
+            } else {
+                equal_run = 0;
+            }
+            end += 1;
+        }
+        let end = end - equal_run.saturating_sub(CONTEXT);
+
+        if out.is_empty() {
+            out.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+        }
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start + 1,
+            old_end - old_start,
+            new_start + 1,
+            new_end - new_start
+        ));
+        for (change, line) in &changes[start..end] {
+            let marker = match change {
+                Change::Equal => ' ',
+                Change::Delete => '-',
+                Change::Insert => '+',
+            };
+            out.push(marker);
+            out.push_str(line);
+            out.push('\n');
+        }
+        idx = end;
+    }
+    out
+}
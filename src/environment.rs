@@ -0,0 +1,144 @@
+//! Preflight check of everything expanding depends on, see [`check_environment`].
+
+use crate::fs;
+use std::path::PathBuf;
+
+/// Report of [`check_environment`], every check either succeeded or carries a description of the
+/// problem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Environment {
+    /// Output of `rustfmt --version`.
+    pub rustfmt: Result<String, String>,
+    /// Output directory of the `*_out_dir` variants, see [`OUT_DIR`](crate::OUT_DIR).
+    pub out_dir: PathBuf,
+    /// If files can be created within `out_dir`.
+    pub writable: Result<(), String>,
+    /// If files within `out_dir` can be locked, to serialize concurrent expansions.
+    pub locking: Result<(), String>,
+}
+
+impl Environment {
+    /// Descriptions of all failed checks alongside a hint how to fix them, empty if all succeeded.
+    ///
+    /// A missing `rustfmt` is only a problem without feature `pretty`, missing file locking only
+    /// with feature `locking`.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Err(ref e) = self.rustfmt {
+            if !cfg!(feature = "pretty") {
+                problems.push(format!(
+                    "{}, install it with `rustup component add rustfmt` or enable feature `pretty`",
+                    e
+                ));
+            }
+        }
+        if let Err(ref e) = self.writable {
+            problems.push(format!(
+                "{}, set `{}` to a writable directory",
+                e,
+                crate::OUT_DIR
+            ));
+        }
+        if self.locking.is_err() && cfg!(any(feature = "locking", test)) {
+            problems.extend(self.locking.clone().err());
+        }
+        problems
+    }
+}
+
+impl std::fmt::Display for Environment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = |result: Result<&str, &str>| match result {
+            Ok("") => "ok".to_owned(),
+            Ok(ok) => format!("ok, {}", ok),
+            Err(e) => format!("failed, {}", e),
+        };
+        writeln!(
+            f,
+            "rustfmt: {}",
+            status(self.rustfmt.as_deref().map_err(String::as_str))
+        )?;
+        writeln!(f, "out dir: {}", self.out_dir.display())?;
+        writeln!(
+            f,
+            "writable: {}",
+            status(self.writable.as_ref().map(|_| "").map_err(String::as_str))
+        )?;
+        write!(
+            f,
+            "locking: {}",
+            status(self.locking.as_ref().map(|_| "").map_err(String::as_str))
+        )
+    }
+}
+
+/// Probe the environment for `rustfmt`, a writable output directory and file locking, i.e. in a
+/// test or on the first invocation of a macro, to fail with an actionable error rather than deep
+/// within an expansion.
+///
+/// ```rust
+/// let environment = expander::check_environment();
+/// for problem in environment.problems() {
+///     eprintln!("{}", problem);
+/// }
+/// ```
+pub fn check_environment() -> Environment {
+    let out_dir = crate::out_dir();
+    let (writable, locking) = match probe(&out_dir) {
+        Ok(locking) => (Ok(()), locking),
+        Err(e) => {
+            let e = format!("can't create files in {}: {}", out_dir.display(), e);
+            (Err(e.clone()), Err(e))
+        }
+    };
+    Environment {
+        rustfmt: rustfmt_version(),
+        out_dir,
+        writable,
+        locking,
+    }
+}
+
+/// The version of the `rustfmt` in `PATH`.
+fn rustfmt_version() -> Result<String, String> {
+    match std::process::Command::new("rustfmt")
+        .arg("--version")
+        .output()
+    {
+        Ok(output) if output.status.success() => {
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        }
+        Ok(output) => Err(format!(
+            "rustfmt failed with exit code {}: {}",
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err("rustfmt executable not found".to_owned())
+        }
+        Err(e) => Err(format!("rustfmt can't be run: {}", e)),
+    }
+}
+
+/// Create and lock a file within `dir`, the outer error if it can't be created, the inner one if
+/// it can't be locked.
+fn probe(dir: &std::path::Path) -> Result<Result<(), String>, std::io::Error> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!(".expander-probe.{}", std::process::id()));
+    let mut file = fs::File::create(&path)?;
+    let locking = if cfg!(any(feature = "locking", test)) {
+        crate::try_lock(&mut file, 1).map(drop).map_err(|e| {
+            format!(
+                "can't lock files in {}: {}, i.e. on a network filesystem, expansions \
+                 of identical content may race",
+                dir.display(),
+                e
+            )
+        })
+    } else {
+        Err("feature `locking` is disabled, concurrent expansions are not serialized".to_owned())
+    };
+    drop(file);
+    fs::remove_file(&path)?;
+    Ok(locking)
+}
@@ -0,0 +1,139 @@
+//! Escaping of tokens whose serialization does not survive the round trip through a file and
+//! `include!` unchanged, see [`escape`].
+
+use crate::UnrepresentableLiteral;
+use proc_macro2::{Delimiter, Group, Literal, TokenStream, TokenTree};
+
+/// Rewrite `tokens`, so they are read back from their serialization with identical meaning.
+///
+/// * Literals containing a carriage return, lost to the newline normalization of `rustc`, or
+///   text direction codepoints, rejected by the lint `text_direction_codepoint_in_literal`, are
+///   escaped. Raw strings are turned into regular ones for that.
+/// * Doc comments containing any of them are wrapped in `concat!`, so prettyplease keeps them as
+///   attribute rather than printing them as comment verbatim.
+///
+/// Raw identifiers, non-ASCII identifiers and raw strings with `#` fences are serialized as is.
+/// Literals with unsafe characters which can't be escaped within their kind, i.e. a text direction
+/// codepoint within a byte string, are an error.
+pub(crate) fn escape(tokens: TokenStream) -> Result<TokenStream, UnrepresentableLiteral> {
+    let mut escaped = Vec::<TokenTree>::new();
+    for token in tokens {
+        let token = match token {
+            TokenTree::Group(group) => {
+                let attribute = match escaped.as_slice() {
+                    [.., TokenTree::Punct(pound), TokenTree::Punct(bang)]
+                        if pound.as_char() == '#' && bang.as_char() == '!' =>
+                    {
+                        true
+                    }
+                    [.., TokenTree::Punct(pound)] => pound.as_char() == '#',
+                    _ => false,
+                };
+                let mut stream = escape(group.stream())?;
+                if attribute && group.delimiter() == Delimiter::Bracket {
+                    stream = doc_attribute(stream);
+                }
+                let mut escaped = Group::new(group.delimiter(), stream);
+                escaped.set_span(group.span());
+                TokenTree::Group(escaped)
+            }
+            TokenTree::Literal(literal) => TokenTree::Literal(escape_literal(literal)?),
+            token => token,
+        };
+        escaped.push(token);
+    }
+    Ok(escaped.into_iter().collect())
+}
+
+/// Codepoints changing the direction of text, rejected by the deny-by-default lints
+/// `text_direction_codepoint_in_literal` and `text_direction_codepoint_in_comment`.
+fn is_text_direction(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// If `c` must not appear verbatim in the serialization.
+fn is_unsafe(c: char) -> bool {
+    c == '\r' || is_text_direction(c)
+}
+
+/// Escape the unsafe characters of `literal`.
+fn escape_literal(literal: Literal) -> Result<Literal, UnrepresentableLiteral> {
+    let repr = literal.to_string();
+    if !repr.contains(is_unsafe) {
+        return Ok(literal);
+    }
+    let mut escaped = escape_repr(repr)?;
+    escaped.set_span(literal.span());
+    Ok(escaped)
+}
+
+/// Escape the unsafe characters of the literal serialized as `repr`.
+pub(crate) fn escape_repr(repr: String) -> Result<Literal, UnrepresentableLiteral> {
+    let escaped = match raw_string(&repr) {
+        Some((prefix, content, suffix)) => {
+            let mut cooked = format!("{}\"", prefix);
+            for c in content.chars() {
+                match c {
+                    '"' => cooked.push_str("\\\""),
+                    '\\' => cooked.push_str("\\\\"),
+                    c => push_escaped(&mut cooked, c),
+                }
+            }
+            cooked.push('"');
+            cooked.push_str(suffix);
+            cooked
+        }
+        None => {
+            let mut cooked = String::with_capacity(repr.len());
+            repr.chars().for_each(|c| push_escaped(&mut cooked, c));
+            cooked
+        }
+    };
+    escaped
+        .parse::<Literal>()
+        .map_err(|_| UnrepresentableLiteral { literal: repr })
+}
+
+/// Push `c` to the content of a regular literal, escaped if unsafe.
+fn push_escaped(cooked: &mut String, c: char) {
+    match c {
+        '\r' => cooked.push_str("\\r"),
+        c if is_text_direction(c) => cooked.push_str(&format!("\\u{{{:x}}}", c as u32)),
+        c => cooked.push(c),
+    }
+}
+
+/// The prefix, content and suffix of a raw string literal, `None` if `repr` is not one.
+fn raw_string(repr: &str) -> Option<(&str, &str, &str)> {
+    let (prefix, fenced) = ["", "b", "c"]
+        .iter()
+        .find_map(|prefix| Some((*prefix, repr.strip_prefix(prefix)?.strip_prefix('r')?)))?;
+    let unfenced = fenced.trim_start_matches('#');
+    let fence = &fenced[..fenced.len() - unfenced.len()];
+    let content = unfenced.strip_prefix('"')?;
+    let end = content.rfind(&format!("\"{}", fence))?;
+    Some((prefix, &content[..end], &content[end + 1 + fence.len()..]))
+}
+
+/// Wrap the value of the attribute `doc = "…"` in `concat!`, if it contains unsafe characters.
+#[cfg(feature = "pretty")]
+fn doc_attribute(attribute: TokenStream) -> TokenStream {
+    let mut tokens = attribute.clone().into_iter();
+    let (Some(TokenTree::Ident(doc)), Some(TokenTree::Punct(eq)), Some(value), None) =
+        (tokens.next(), tokens.next(), tokens.next(), tokens.next())
+    else {
+        return attribute;
+    };
+    let unsafe_value = syn::parse2::<syn::LitStr>(value.clone().into())
+        .map_or(false, |value| value.value().contains(is_unsafe));
+    if doc != "doc" || eq.as_char() != '=' || !unsafe_value {
+        return attribute;
+    }
+    quote::quote! { #doc #eq concat!(#value) }
+}
+
+#[cfg(not(feature = "pretty"))]
+fn doc_attribute(attribute: TokenStream) -> TokenStream {
+    // only prettyplease prints doc attributes as comments
+    attribute
+}
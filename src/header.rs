@@ -0,0 +1,131 @@
+//! Structured header at the top of every generated file.
+
+use std::env;
+
+/// Fields rendered at the top of every generated file, one `// expander-{field}: {value}` line each,
+/// followed by an optional free-form message.
+///
+/// ```rust
+/// # use expander::{Expander, Header};
+/// let expander = Expander::new("baz").header(
+///     Header::new()
+///         .crate_name(true)
+///         .macro_name(true)
+///         .input_digest(true)
+///         .message("Generated for the `baz` derive"),
+/// );
+/// ```
+///
+/// renders as
+///
+/// ```text
+/// // expander-crate: foo
+/// // expander-macro: baz
+/// // expander-input-digest: 0f9a…
+/// /* Generated for the `baz` derive */
+/// ```
+///
+/// Values which can't be determined, i.e. the crate name outside of a cargo build, are omitted.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    crate_name: bool,
+    crate_version: bool,
+    macro_name: bool,
+    input_digest: bool,
+    expander_version: bool,
+    message: Option<String>,
+}
+
+impl Header {
+    /// An empty header, enable the fields to render.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A header with all fields enabled.
+    pub fn all() -> Self {
+        Self {
+            crate_name: true,
+            crate_version: true,
+            macro_name: true,
+            input_digest: true,
+            expander_version: true,
+            message: None,
+        }
+    }
+
+    /// Name of the crate being compiled.
+    pub fn crate_name(mut self, crate_name: bool) -> Self {
+        self.crate_name = crate_name;
+        self
+    }
+
+    /// Version of the crate being compiled.
+    pub fn crate_version(mut self, crate_version: bool) -> Self {
+        self.crate_version = crate_version;
+        self
+    }
+
+    /// The filename base of the [`Expander`](crate::Expander), commonly the name of the macro.
+    pub fn macro_name(mut self, macro_name: bool) -> Self {
+        self.macro_name = macro_name;
+        self
+    }
+
+    /// Hex encoded digest of the tokens before formatting.
+    pub fn input_digest(mut self, input_digest: bool) -> Self {
+        self.input_digest = input_digest;
+        self
+    }
+
+    /// Version of `expander` which wrote the file.
+    pub fn expander_version(mut self, expander_version: bool) -> Self {
+        self.expander_version = expander_version;
+        self
+    }
+
+    /// A free-form message, rendered as comment of the [`CommentStyle`](crate::CommentStyle) of the
+    /// [`Expander`](crate::Expander) after all fields.
+    pub fn message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    pub(crate) fn wants_input_digest(&self) -> bool {
+        self.input_digest
+    }
+
+    pub(crate) fn render(
+        &self,
+        macro_name: &str,
+        input_digest: Option<&str>,
+        style: crate::CommentStyle,
+    ) -> String {
+        let mut fields = Vec::new();
+        if self.crate_name {
+            fields.push(("crate", crate::crate_name()));
+        }
+        if self.crate_version {
+            fields.push(("crate-version", env::var("CARGO_PKG_VERSION").ok()));
+        }
+        if self.macro_name {
+            fields.push(("macro", Some(macro_name.to_owned())));
+        }
+        if self.input_digest {
+            fields.push(("input-digest", input_digest.map(str::to_owned)));
+        }
+        if self.expander_version {
+            fields.push(("version", Some(env!("CARGO_PKG_VERSION").to_owned())));
+        }
+        let mut header = String::new();
+        for (field, value) in fields {
+            if let Some(value) = value {
+                header += &format!("// expander-{}: {}\n", field, value);
+            }
+        }
+        if let Some(ref message) = self.message {
+            header += &style.render(message);
+        }
+        header
+    }
+}
@@ -1,26 +1,228 @@
+#![cfg_attr(feature = "nightly", feature(proc_macro_diagnostic))]
+#![cfg_attr(feature = "tracked-path", feature(proc_macro_tracked_path))]
+
+#[cfg(feature = "nightly")]
+extern crate proc_macro;
+
+#[cfg(any(feature = "fs-err", test))]
 use fs_err as fs;
-use proc_macro2::TokenStream;
-use quote::quote;
+use proc_macro2::{Delimiter, TokenStream, TokenTree};
+use quote::{format_ident, quote, ToTokens};
 use std::env;
-use std::io::Write;
+#[cfg(not(any(feature = "fs-err", test)))]
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::process::Stdio;
 
+/// Environment variable which, if set, enables [`Expander::stable_path`] for all expansions.
+pub const STABLE_PATHS: &str = "EXPANDER_STABLE_PATHS";
+
+/// Environment variable which, if set, overrides the output directory of the `*_out_dir` variants.
+pub const OUT_DIR: &str = "EXPANDER_OUT_DIR";
+
+/// Environment variable which, if set, overrides the directory of the [`Expander::cache`].
+pub const CACHE_DIR: &str = "EXPANDER_CACHE_DIR";
+
+/// `cfg` switching between the inlined tokens and the included file, see
+/// [`Expander::inline_switch`].
+pub const INLINE_CFG: &str = "expander_inline";
+
+/// Environment variable which, if set to `always`, colors the messages of expander on `stderr`,
+/// unless `NO_COLOR` is set.
+pub const COLOR: &str = "EXPANDER_COLOR";
+
+/// Environment variable which, if set to anything but `0`, enables [`Expander::quiet`] for all
+/// expansions.
+pub const QUIET: &str = "EXPANDER_QUIET";
+
+/// Output directory of the `*_out_dir` variants, [`OUT_DIR`] if set, `env!("OUT_DIR")` otherwise.
+pub(crate) fn out_dir() -> std::path::PathBuf {
+    env::var_os(OUT_DIR).map_or_else(|| std::path::PathBuf::from(env!("OUT_DIR")), Into::into)
+}
+
+/// Describes the sandbox `dest_dir` is located in, if it is known to be unwritable.
+fn sandbox(dest_dir: &Path) -> Option<&'static str> {
+    if dest_dir.starts_with("/nix/store") {
+        return Some("the read-only nix store");
+    }
+    if dest_dir
+        .components()
+        .any(|component| component.as_os_str() == "execroot")
+    {
+        return Some("a bazel execroot");
+    }
+    let read_only = fs::metadata(dest_dir).map_or(false, |metadata| {
+        metadata.is_dir() && metadata.permissions().readonly()
+    });
+    read_only.then_some("a read-only directory")
+}
+
+/// If `error` stems from writing to a read-only filesystem or directory.
+fn is_read_only(error: &std::io::Error) -> bool {
+    // `EROFS`, `ErrorKind::ReadOnlyFilesystem` requires rust 1.83
+    const EROFS: i32 = 30;
+    error.kind() == std::io::ErrorKind::PermissionDenied
+        || (cfg!(unix) && error.raw_os_error() == Some(EROFS))
+}
+
+/// The `(from, to)` pairs of all `--remap-path-prefix` arguments among the `rustc` `flags`.
+fn remap_flags(flags: &[String]) -> Vec<(String, String)> {
+    let mut mappings = Vec::new();
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let mapping = match flag.strip_prefix("--remap-path-prefix") {
+            Some("") => flags.next().map(String::as_str),
+            Some(joined) => joined.strip_prefix('='),
+            None => None,
+        };
+        // like `rustc`, split at the last `=`
+        if let Some((from, to)) = mapping.and_then(|mapping| mapping.rsplit_once('=')) {
+            mappings.push((from.to_owned(), to.to_owned()));
+        }
+    }
+    mappings
+}
+
+/// Directory written to by [`Expander::write_to_default_dirs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DefaultDir {
+    /// `OUT_DIR` of the crate being compiled, or the [`OUT_DIR`] override.
+    OutDir(std::path::PathBuf),
+    /// `CARGO_TARGET_TMPDIR`, set while compiling integration tests and benchmarks.
+    TargetTmpDir(std::path::PathBuf),
+    /// The `expander` directory within the temporary directory of the system.
+    TempDir(std::path::PathBuf),
+}
+
+impl DefaultDir {
+    /// The directory.
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::OutDir(path) | Self::TargetTmpDir(path) | Self::TempDir(path) => path,
+        }
+    }
+
+    /// All candidates available in the environment, in order of preference.
+    fn candidates() -> Vec<Self> {
+        let mut candidates = Vec::new();
+        if let Some(out_dir) = env::var_os(OUT_DIR).or_else(|| env::var_os("OUT_DIR")) {
+            candidates.push(Self::OutDir(out_dir.into()));
+        }
+        if let Some(tmp_dir) = env::var_os("CARGO_TARGET_TMPDIR") {
+            candidates.push(Self::TargetTmpDir(tmp_dir.into()));
+        }
+        candidates.push(Self::TempDir(env::temp_dir().join("expander")));
+        candidates
+    }
+}
+
+/// Environment variable required by `expander`, but absent.
+///
+/// Returned wrapped in a [`std::io::Error`] of kind [`std::io::ErrorKind::NotFound`], use
+/// [`get_ref`](std::io::Error::get_ref) and `downcast_ref` to access it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingEnv {
+    /// Name of the environment variable.
+    pub var: String,
+}
+
+impl std::fmt::Display for MissingEnv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expander: environment variable `{}` is not set, is this invoked by cargo?",
+            self.var
+        )?;
+        if self.var == "OUT_DIR" {
+            write!(
+                f,
+                " Only crates with a build script have one, add an empty `build.rs` or use \
+                 `write_to_default_dirs`"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for MissingEnv {}
+
+impl From<MissingEnv> for std::io::Error {
+    fn from(missing: MissingEnv) -> Self {
+        std::io::Error::new(std::io::ErrorKind::NotFound, missing)
+    }
+}
+
+/// Destination path which can't be embedded as literal into `include!`, as it is not valid UTF-8.
+///
+/// Returned wrapped in a [`std::io::Error`] of kind [`std::io::ErrorKind::InvalidInput`], use
+/// [`get_ref`](std::io::Error::get_ref) and `downcast_ref` to access it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnrepresentablePath {
+    /// The offending path.
+    pub path: std::path::PathBuf,
+}
+
+impl std::fmt::Display for UnrepresentablePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expander: path {} is not valid UTF-8 and can't be included, set `{}` to a UTF-8 directory",
+            self.path.display(),
+            OUT_DIR
+        )
+    }
+}
+
+impl std::error::Error for UnrepresentablePath {}
+
+impl From<UnrepresentablePath> for std::io::Error {
+    fn from(unrepresentable: UnrepresentablePath) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, unrepresentable)
+    }
+}
+
+/// Literal which can't be written to a file and read back unchanged, as it contains a carriage
+/// return or text direction codepoint which can't be escaped within its kind of literal, i.e. a
+/// byte string.
+///
+/// Returned wrapped in a [`std::io::Error`] of kind [`std::io::ErrorKind::InvalidData`], use
+/// [`get_ref`](std::io::Error::get_ref) and `downcast_ref` to access it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnrepresentableLiteral {
+    /// The offending literal, as serialized.
+    pub literal: String,
+}
+
+impl std::fmt::Display for UnrepresentableLiteral {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expander: literal {} can't be written to a file, as it contains a carriage return or \
+             text direction codepoint which can't be escaped",
+            self.literal.escape_debug()
+        )
+    }
+}
+
+impl std::error::Error for UnrepresentableLiteral {}
+
+impl From<UnrepresentableLiteral> for std::io::Error {
+    fn from(unrepresentable: UnrepresentableLiteral) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, unrepresentable)
+    }
+}
+
 /// Rust edition to format for.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub enum Edition {
+    #[default]
     Unspecified,
     _2015,
     _2018,
     _2021,
 }
 
-impl std::default::Default for Edition {
-    fn default() -> Self {
-        Self::Unspecified
-    }
-}
-
 impl std::fmt::Display for Edition {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
@@ -33,6 +235,329 @@ impl std::fmt::Display for Edition {
     }
 }
 
+/// License banner at the very top of every generated file.
+#[derive(Debug, Clone)]
+enum License {
+    /// `// SPDX-License-Identifier: …`
+    Spdx(String),
+    /// Verbatim content of a template file.
+    File(std::path::PathBuf),
+}
+
+impl License {
+    fn render(&self) -> Result<String, std::io::Error> {
+        let mut banner = match self {
+            Self::Spdx(expression) => format!("// SPDX-License-Identifier: {}", expression),
+            Self::File(path) => {
+                let path = match env::var_os("CARGO_MANIFEST_DIR") {
+                    Some(manifest_dir) if path.is_relative() => Path::new(&manifest_dir).join(path),
+                    _ => path.clone(),
+                };
+                fs::read_to_string(path)?
+            }
+        };
+        if !banner.ends_with('\n') {
+            banner.push('\n');
+        }
+        Ok(banner)
+    }
+}
+
+/// How comments added with [`Expander::add_comment`] are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CommentStyle {
+    /// `/* … */`, with any `/*` and `*/` in the text defused.
+    #[default]
+    Block,
+    /// `// …` for every line of the text.
+    Line,
+}
+
+impl CommentStyle {
+    /// Render `text` as a comment, terminated by a newline.
+    pub(crate) fn render(self, text: &str) -> String {
+        match self {
+            Self::Block => format!("/* {} */\n", text.replace("*/", "* /").replace("/*", "/ *")),
+            Self::Line => text
+                .lines()
+                .map(|line| format!("// {}\n", line).replace("// \n", "//\n"))
+                .collect(),
+        }
+    }
+}
+
+/// Hash function for the digest in filenames.
+///
+/// Defaults to `Blake2s` with feature `blake2`, to [`Sip`](Self::Sip) otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Hasher {
+    #[cfg(any(feature = "blake2", test))]
+    /// Blake2s with 256 bits.
+    #[default]
+    Blake2s,
+    #[cfg(any(feature = "xxhash", test))]
+    /// XXH3 with 128 bits, considerably faster for large expansions, but not cryptographic.
+    Xxh3,
+    /// The SipHash of `std` with 128 bits, from two differently keyed passes. Without any
+    /// dependency, but the algorithm is not guaranteed to be stable across toolchains.
+    #[cfg_attr(not(any(feature = "blake2", test)), default)]
+    Sip,
+}
+
+impl Hasher {
+    /// Number of hex characters of the full digest.
+    fn max_len(self) -> usize {
+        match self {
+            #[cfg(any(feature = "blake2", test))]
+            Self::Blake2s => 64,
+            #[cfg(any(feature = "xxhash", test))]
+            Self::Xxh3 => 32,
+            Self::Sip => 32,
+        }
+    }
+
+    /// The leading `len` hex characters of the digest of `bytes`, preceded by `salt`.
+    fn suffix(self, salt: &[u8], bytes: &[u8], len: usize) -> String {
+        let mut digester = self.digester(salt);
+        digester.update(bytes);
+        digester.suffix(len)
+    }
+
+    /// Incremental digest, preceded by `salt`.
+    fn digester(self, salt: &[u8]) -> Digester {
+        let mut digester = match self {
+            #[cfg(any(feature = "blake2", test))]
+            Self::Blake2s => Digester::Blake2s(<blake2::Blake2s256 as blake2::Digest>::new()),
+            #[cfg(any(feature = "xxhash", test))]
+            Self::Xxh3 => Digester::Xxh3(Box::new(xxhash_rust::xxh3::Xxh3::new())),
+            Self::Sip => {
+                let mut keyed = std::collections::hash_map::DefaultHasher::new();
+                std::hash::Hasher::write_u8(&mut keyed, 1);
+                Digester::Sip(Box::new([Default::default(), keyed]))
+            }
+        };
+        digester.update(salt);
+        digester
+    }
+}
+
+/// Incremental digest of a [`Hasher`], fed by writing to it.
+enum Digester {
+    #[cfg(any(feature = "blake2", test))]
+    Blake2s(blake2::Blake2s256),
+    #[cfg(any(feature = "xxhash", test))]
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+    Sip(Box<[std::collections::hash_map::DefaultHasher; 2]>),
+}
+
+impl Digester {
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            #[cfg(any(feature = "blake2", test))]
+            Self::Blake2s(hasher) => blake2::Digest::update(hasher, bytes),
+            #[cfg(any(feature = "xxhash", test))]
+            Self::Xxh3(hasher) => hasher.update(bytes),
+            // `write` is independent of how the bytes are split
+            Self::Sip(hashers) => hashers
+                .iter_mut()
+                .for_each(|hasher| std::hash::Hasher::write(hasher, bytes)),
+        }
+    }
+
+    /// The leading `len` hex characters of the digest.
+    fn suffix(self, len: usize) -> String {
+        match self {
+            #[cfg(any(feature = "blake2", test))]
+            Self::Blake2s(hasher) => make_suffix(blake2::Digest::finalize(hasher).as_ref(), len),
+            #[cfg(any(feature = "xxhash", test))]
+            Self::Xxh3(hasher) => make_suffix(&hasher.digest128().to_be_bytes(), len),
+            Self::Sip(hashers) => {
+                let [first, second] = hashers.map(|hasher| std::hash::Hasher::finish(&hasher));
+                make_suffix(
+                    &(u128::from(first) << 64 | u128::from(second)).to_be_bytes(),
+                    len,
+                )
+            }
+        }
+    }
+}
+
+impl Write for Digester {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Command run on the written file, see [`Expander::post_command`].
+#[derive(Debug, Clone)]
+struct PostCommand {
+    program: String,
+    args: Vec<String>,
+    allow_failure: bool,
+}
+
+impl PostCommand {
+    /// Run the command on `file`, a failure is only warned about with `allow_failure`.
+    fn run(&self, file: &Path, verbose: bool) -> Result<(), std::io::Error> {
+        let args = self.args.iter().map(|arg| {
+            let mut parts = arg.split("{file}");
+            let mut arg = std::ffi::OsString::from(parts.next().unwrap_or_default());
+            for part in parts {
+                arg.push(file);
+                arg.push(part);
+            }
+            arg
+        });
+        if verbose {
+            info(format_args!(
+                "running `{}` on {}",
+                self.program,
+                file.display()
+            ));
+        }
+        let output = std::process::Command::new(&self.program)
+            .args(args)
+            .stdin(Stdio::null())
+            .output();
+        let error = match output {
+            Ok(output) if output.status.success() => return Ok(()),
+            Ok(output) => std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!(
+                    "`{}` failed on {} with exit code {}\nstderr: {}",
+                    self.program,
+                    file.display(),
+                    output.status.code().unwrap_or(-1),
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+            ),
+            Err(e) => {
+                std::io::Error::new(e.kind(), format!("failed to run `{}`: {}", self.program, e))
+            }
+        };
+        if self.allow_failure {
+            warning(&error);
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+}
+
+/// Callback registered with [`Expander::after_write`].
+type Hook = dyn Fn(&Path) -> Result<(), std::io::Error> + Send + Sync;
+
+/// Callbacks registered with [`Expander::after_write`].
+#[derive(Clone, Default)]
+struct Hooks(Vec<std::sync::Arc<Hook>>);
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Hooks({})", self.0.len())
+    }
+}
+
+/// Tokens kept in their serialization, since `TokenStream` is neither `Send` nor `Sync`.
+#[derive(Debug, Clone)]
+struct Stored(String);
+
+impl Stored {
+    fn new(tokens: impl ToTokens) -> Self {
+        Self(tokens.into_token_stream().to_string())
+    }
+}
+
+impl ToTokens for Stored {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        tokens.extend(
+            self.0
+                .parse::<TokenStream>()
+                .expect("serialized tokens parse again"),
+        );
+    }
+}
+
+/// Module enclosing the generated code.
+#[derive(Debug, Clone)]
+struct Wrap {
+    name: String,
+    vis: Stored,
+    attrs: Vec<Stored>,
+}
+
+impl Wrap {
+    /// Enclose `tokens` in the module, with the `inner` attributes applied to it.
+    fn wrap(&self, inner: &[Stored], tokens: TokenStream) -> TokenStream {
+        let Self { name, vis, attrs } = self;
+        let name = format_ident!("{}", name);
+        quote! {
+            #( #[ #attrs ] )*
+            #vis mod #name {
+                #( #![ #inner ] )*
+                #tokens
+            }
+        }
+    }
+}
+
+/// Line endings of the written files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    /// `\r\n` on windows, `\n` everywhere else.
+    Native,
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    CrLf,
+}
+
+impl LineEnding {
+    /// Convert all line endings in `bytes`.
+    fn apply(self, bytes: Vec<u8>) -> Vec<u8> {
+        let bytes = normalize_line_endings(bytes);
+        match self {
+            Self::CrLf => to_crlf(&bytes),
+            Self::Native if cfg!(windows) => to_crlf(&bytes),
+            Self::Native | Self::Lf => bytes,
+        }
+    }
+}
+
+/// Sharing of the written file with other processes while it is being written, windows only.
+///
+/// On other platforms the lock on the file is advisory and readers are never blocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShareMode {
+    /// Other processes may read, write and delete the file, `rustc` can read it early.
+    #[default]
+    ReadWrite,
+    /// Other processes may only read the file.
+    Read,
+    /// Nobody else can open the file, so partial content is never observed. Concurrent writers of
+    /// identical content wait until the file can be opened.
+    Exclusive,
+}
+
+impl ShareMode {
+    /// The `FILE_SHARE_*` flags.
+    #[cfg(any(windows, test))]
+    fn flags(self) -> u32 {
+        const FILE_SHARE_READ: u32 = 0x1;
+        const FILE_SHARE_WRITE: u32 = 0x2;
+        const FILE_SHARE_DELETE: u32 = 0x4;
+        match self {
+            Self::ReadWrite => FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+            Self::Read => FILE_SHARE_READ,
+            Self::Exclusive => 0,
+        }
+    }
+}
+
 /// The channel to use for formatting.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum Channel {
@@ -55,34 +580,176 @@ impl std::fmt::Display for Channel {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 enum RustFmt {
     Yes {
         edition: Edition,
         channel: Channel,
         allow_failure: bool,
+        /// Unstable configuration, only passed on with [`Channel::Nightly`].
+        unstable: Vec<(String, String)>,
     },
+    #[default]
     No,
 }
 
-impl std::default::Default for RustFmt {
-    fn default() -> Self {
-        RustFmt::No
-    }
-}
-
 impl From<Edition> for RustFmt {
     fn from(edition: Edition) -> Self {
         RustFmt::Yes {
             edition,
             channel: Channel::Default,
             allow_failure: false,
+            unstable: Vec::new(),
+        }
+    }
+}
+
+/// Formatter which produced the written content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Formatter {
+    #[cfg_attr(not(feature = "pretty"), allow(dead_code))]
+    PrettyPlease,
+    RustFmt,
+    /// Written as is.
+    Raw,
+    /// Formatted by a [`Transform`].
+    Transform,
+}
+
+impl std::fmt::Display for Formatter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::PrettyPlease => "prettyplease",
+            Self::RustFmt => "rustfmt",
+            Self::Raw => "raw",
+            Self::Transform => "transform",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Formatter of an explicit [`format_chain`](Expander::format_chain).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Formatting {
+    /// In-process with prettyplease, skipped without feature `pretty`.
+    PrettyPlease,
+    /// The `rustfmt` executable, as configured by [`fmt_full`](Expander::fmt_full) if called.
+    RustFmt,
+    /// The serialized tokens as they are, which never fails.
+    Raw,
+}
+
+impl std::fmt::Display for Formatting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::PrettyPlease => "prettyplease",
+            Self::RustFmt => "rustfmt",
+            Self::Raw => "raw",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Formatters tried in order, see [`Expander::format_chain`].
+#[derive(Debug, Clone)]
+struct FormatChain {
+    formatters: Vec<Formatting>,
+    /// Warn about every fallback to the next formatter.
+    report: bool,
+}
+
+/// Removal of previously written files with the same filename base.
+#[derive(Debug, Clone, Copy, Default)]
+enum Gc {
+    #[default]
+    Off,
+    /// Remove all files with a different digest.
+    Stale,
+    /// Remove files with a different digest, which were not modified within the given duration.
+    OlderThan(std::time::Duration),
+}
+
+/// How the written file is referenced by the returned tokens.
+#[derive(Debug, Clone, Default)]
+enum Emit {
+    /// `include!("…");`
+    #[default]
+    Include,
+    /// `#[path = "…"] mod name;`
+    Module(String),
+    /// `const NAME: &str = include_str!("…");`
+    Str(String),
+    /// `const NAME: &[u8] = include_bytes!("…");`
+    Bytes(String),
+    /// Nothing, the file is a standalone artifact.
+    Artifact,
+}
+
+impl Emit {
+    /// If the referenced file is Rust source code.
+    fn is_rust(&self) -> bool {
+        matches!(self, Self::Include | Self::Module(_))
+    }
+
+    /// Tokens referencing the file at `dest`, relative to the directory of `anchor`.
+    fn reference(&self, dest: &Path, anchor: Anchor) -> std::io::Result<TokenStream> {
+        let path = relocatable_path(dest, anchor.vars())?;
+        Ok(match self {
+            Self::Include => quote! {
+                include!( #path );
+            },
+            Self::Module(name) => {
+                // `#[path]` only accepts literals
+                let path = path_literal(dest)?;
+                let name = format_ident!("{}", name);
+                quote! {
+                    #[path = #path]
+                    mod #name;
+                }
+            }
+            Self::Str(binding) => {
+                let binding = format_ident!("{}", binding);
+                quote! {
+                    const #binding: &str = include_str!( #path );
+                }
+            }
+            Self::Bytes(binding) => {
+                let binding = format_ident!("{}", binding);
+                quote! {
+                    const #binding: &[u8] = include_bytes!( #path );
+                }
+            }
+            Self::Artifact => TokenStream::new(),
+        })
+    }
+}
+
+/// Directory the paths of included files are relative to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Anchor {
+    /// Absolute paths.
+    #[default]
+    Absolute,
+    /// `concat!(env!("CARGO_MANIFEST_DIR"), "/…")`
+    ManifestDir,
+    /// `concat!(env!("OUT_DIR"), "/…")`, `CARGO_MANIFEST_DIR` for crates without build script.
+    OutDir,
+}
+
+impl Anchor {
+    /// The environment variables holding the directory, both at expansion and compile time, in
+    /// order of preference.
+    fn vars(self) -> &'static [&'static str] {
+        match self {
+            Self::Absolute => &[],
+            Self::ManifestDir => &["CARGO_MANIFEST_DIR"],
+            Self::OutDir => &["OUT_DIR", "CARGO_MANIFEST_DIR"],
         }
     }
 }
 
 /// Expander to replace a tokenstream by a include to a file
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct Expander {
     /// Determines if the whole file `include!` should be done (`false`) or not (`true`).
     dry: bool,
@@ -92,8 +759,135 @@ pub struct Expander {
     filename_base: String,
     /// Additional comment to be added.
     comment: Option<String>,
+    /// Rendering of the comment.
+    comment_style: CommentStyle,
+    /// Structured fields preceding the comment.
+    header: Option<Header>,
+    /// License banner preceding everything else.
+    license: Option<License>,
     /// Format using `rustfmt` in your path.
     rustfmt: RustFmt,
+    /// Unstable `rustfmt` configuration, kept apart so it survives a later [`fmt`](Self::fmt).
+    fmt_unstable: Vec<(String, String)>,
+    /// Append to a shared file as region rather than writing a dedicated file.
+    merge: bool,
+    /// Register the written file in the aggregate file of the destination directory.
+    aggregate: bool,
+    /// How to reference the written file.
+    emit: Emit,
+    /// File extension of the written file.
+    extension: String,
+    /// Remove previously written files with the same filename base.
+    gc: Gc,
+    /// Number of previous versions to retain, `0` disables the history.
+    history: usize,
+    /// Write the diff to the previous version to `{file}.diff` rather than `stderr`.
+    diff_to_file: bool,
+    /// Only verify the existing file matches rather than writing it.
+    check_only: bool,
+    /// Omit the digest from the filename.
+    stable_path: bool,
+    /// Return the tokens as is if writing fails.
+    fallback_inline: bool,
+    /// Return the tokens as is, even though the file is written.
+    tee: bool,
+    /// Return the tokens alongside the reference, switched by `cfg(expander_inline)`.
+    inline_switch: bool,
+    /// Dump the intermediate artifacts of every formatting stage.
+    debug_stages: bool,
+    /// Suppress all non-fatal output.
+    quiet: bool,
+    /// Fail if prettyplease can't parse the generated code.
+    strict: bool,
+    /// Formatters replacing the built-in fallbacks.
+    format_chain: Option<FormatChain>,
+    /// Report the size of the expansion.
+    stats: Option<Stats>,
+    /// Warn about expansions larger than this.
+    warn_above_bytes: Option<usize>,
+    /// Warn if formatting takes longer than this.
+    fmt_budget: Option<std::time::Duration>,
+    /// Return expansions smaller than this many bytes inline.
+    min_size: usize,
+    /// Produce identical output regardless of the machine.
+    reproducible: bool,
+    /// Line endings to convert the formatted code to.
+    line_ending: Option<LineEnding>,
+    /// Content of inner attributes prepended to the file.
+    inner_attrs: Vec<Stored>,
+    #[cfg(feature = "pretty")]
+    /// Content of outer attributes prepended to every item.
+    outer_attrs: Vec<Stored>,
+    /// Module enclosing the generated code.
+    wrap: Option<Wrap>,
+    /// Predicate gating the returned tokens.
+    cfg: Option<Stored>,
+    /// Number of hex characters of the digest in the filename.
+    digest_len: usize,
+    /// Hash function of the digest in the filename.
+    hasher: Hasher,
+    /// Derive the digest in the filename from the unformatted tokens.
+    hash_input: bool,
+    /// Mixed into the digest in the filename.
+    salt: Vec<u8>,
+    /// Write into a subdirectory per crate.
+    per_crate_dir: bool,
+    /// Write into a subdirectory per filename base.
+    per_macro_dir: bool,
+    /// Write into a subdirectory per leading two characters of the digest.
+    shard: bool,
+    /// Unix permission bits of the written file.
+    file_mode: Option<u32>,
+    /// Mark the written file read-only.
+    read_only: bool,
+    /// Sharing of the file while writing, windows only.
+    share_mode: ShareMode,
+    /// Flush the written file and its directory to disk before returning.
+    sync: bool,
+    /// Maintain a `{filename_base}-latest.{extension}` alias of the last written file.
+    latest: bool,
+    /// Pipeline of transforms applied before writing.
+    transforms: Vec<transform::Step>,
+    /// Run on the written file.
+    post_commands: Vec<PostCommand>,
+    /// Directories the written file is copied to.
+    mirrors: Vec<std::path::PathBuf>,
+    /// Called with the path of the written file.
+    after_write: Hooks,
+    /// Directory the paths of included files are relative to.
+    anchor: Anchor,
+    /// Used from a build script, writing to its `OUT_DIR`.
+    build_script: bool,
+    /// `--remap-path-prefix` mappings in addition to the ones passed to `rustc`.
+    path_prefixes: Vec<(String, String)>,
+    /// Write elsewhere if the destination is within a sandbox.
+    sandbox_fallback: bool,
+    /// Environment variables the configuration was derived from.
+    env_vars: Vec<String>,
+    /// Write into the content addressed store shared by all filename bases.
+    dedup: bool,
+    /// Reuse formatted expansions across the crates of a workspace.
+    cache: bool,
+    /// Skip formatting and writing if identical input was expanded before.
+    memoize: bool,
+    #[cfg(any(feature = "call-site", test))]
+    /// Location of the macro invocation, recorded in the header.
+    call_site: Option<String>,
+    /// Parse the written file to ensure it is valid Rust.
+    #[cfg(any(feature = "validate", test))]
+    read_back: bool,
+    /// Parse the tokens to ensure they are valid Rust before writing.
+    #[cfg(any(feature = "validate", test))]
+    validate: bool,
+    /// Write a sidecar mapping lines of the written file to the spans of the input.
+    #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+    span_map: bool,
+    /// Write a metadata sidecar next to the written file.
+    #[cfg(any(feature = "metadata", test))]
+    metadata: bool,
+    /// Record the written file in the build-wide manifest.
+    #[cfg(any(feature = "metadata", test))]
+    manifest: bool,
 }
 
 impl Expander {
@@ -107,22 +901,195 @@ impl Expander {
             verbose: false,
             filename_base: filename_base.as_ref().to_owned(),
             comment: None,
+            comment_style: CommentStyle::Block,
+            header: None,
+            license: None,
             rustfmt: RustFmt::No,
+            fmt_unstable: Vec::new(),
+            merge: false,
+            aggregate: false,
+            emit: Emit::Include,
+            extension: "rs".to_owned(),
+            gc: Gc::Off,
+            history: 0,
+            diff_to_file: false,
+            check_only: false,
+            stable_path: false,
+            fallback_inline: false,
+            tee: false,
+            inline_switch: false,
+            debug_stages: false,
+            quiet: false,
+            strict: false,
+            format_chain: None,
+            stats: None,
+            warn_above_bytes: None,
+            fmt_budget: None,
+            min_size: 0,
+            reproducible: false,
+            line_ending: None,
+            inner_attrs: Vec::new(),
+            #[cfg(feature = "pretty")]
+            outer_attrs: Vec::new(),
+            wrap: None,
+            cfg: None,
+            digest_len: DEFAULT_DIGEST_LEN,
+            hasher: Hasher::default(),
+            hash_input: false,
+            salt: Vec::new(),
+            per_crate_dir: false,
+            per_macro_dir: false,
+            shard: false,
+            file_mode: None,
+            read_only: false,
+            share_mode: ShareMode::ReadWrite,
+            sync: false,
+            latest: false,
+            transforms: Vec::new(),
+            post_commands: Vec::new(),
+            mirrors: Vec::new(),
+            after_write: Hooks::default(),
+            anchor: Anchor::Absolute,
+            build_script: false,
+            path_prefixes: Vec::new(),
+            sandbox_fallback: true,
+            env_vars: Vec::new(),
+            dedup: false,
+            cache: false,
+            memoize: false,
+            #[cfg(any(feature = "call-site", test))]
+            call_site: None,
+            #[cfg(any(feature = "validate", test))]
+            read_back: false,
+            #[cfg(any(feature = "validate", test))]
+            validate: false,
+            #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+            span_map: false,
+            #[cfg(any(feature = "metadata", test))]
+            metadata: false,
+            #[cfg(any(feature = "metadata", test))]
+            manifest: false,
         }
     }
 
-    /// Add a header comment.
+    /// Add a structured header to the generated file, rendered after the call site and before
+    /// the comment of [`add_comment`](Self::add_comment).
+    pub fn header(mut self, header: impl Into<Option<Header>>) -> Self {
+        self.header = header.into();
+        self
+    }
+
+    /// Prepend `// SPDX-License-Identifier: {expression}` to every generated file.
+    pub fn spdx(mut self, expression: impl Into<String>) -> Self {
+        self.license = Some(License::Spdx(expression.into()));
+        self
+    }
+
+    /// Prepend the content of the file at `path` verbatim to every generated file, i.e. a license banner.
+    ///
+    /// A relative `path` is resolved against the `CARGO_MANIFEST_DIR` of the crate being compiled.
+    /// Like all header comments, the banner does not contribute to the digest in the filename.
+    pub fn header_file(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.license = Some(License::File(path.into()));
+        self
+    }
+
+    /// Add a free-form header comment, see [`header`](Self::header) for a structured one.
     pub fn add_comment(mut self, comment: impl Into<Option<String>>) -> Self {
-        self.comment = comment.into().map(|comment| format!("/* {} */\n", comment));
+        self.comment = comment.into();
+        self
+    }
+
+    /// Render comments as `/* … */` block or `// …` line comments, see [`CommentStyle`].
+    pub fn comment_style(mut self, comment_style: CommentStyle) -> Self {
+        self.comment_style = comment_style;
+        self
+    }
+
+    #[cfg(any(feature = "call-site", test))]
+    /// Record the location of the macro invocation in the header, i.e.
+    /// `/* generated from src/lib.rs:42:1 by `baz` */`, to trace a generated file back to its origin.
+    ///
+    /// Must be called during the macro invocation. Files are shared between invocations with
+    /// identical expansions, the header names the invocation which wrote it last. The location
+    /// is only available on toolchains exposing it to proc-macros.
+    pub fn call_site(mut self, call_site: bool) -> Self {
+        self.call_site = call_site.then(|| {
+            let span = proc_macro2::Span::call_site();
+            let start = span.start();
+            format!("{}:{}:{}", span.file(), start.line, start.column + 1)
+        });
         self
     }
 
+    /// Append the [`stats`](Self::stats) of the formatted `bytes` to the `header`, or print them,
+    /// and warn if they exceed [`warn_above_bytes`](Self::warn_above_bytes).
+    fn report_size(&self, header: Option<String>, bytes: &[u8]) -> Option<String> {
+        if let Some(limit) = self.warn_above_bytes.filter(|limit| bytes.len() > *limit) {
+            warning(format_args!(
+                "expansion of `{}` takes {} bytes, more than {}, consider splitting it up as \
+                 large files slow down the compiler front-end",
+                self.filename_base,
+                bytes.len(),
+                limit
+            ));
+        }
+        match self.stats {
+            Some(Stats::Header) => {
+                let summary = stats::Summary::of(bytes);
+                let comment = self
+                    .comment_style
+                    .render(&format!("expander-stats: {}", summary));
+                let comment = self.convert_line_endings(comment.into_bytes());
+                Some(header.unwrap_or_default() + &String::from_utf8_lossy(&comment))
+            }
+            Some(Stats::Stderr) => {
+                let summary = stats::Summary::of(bytes);
+                info(format_args!(
+                    "{} expanded to {}",
+                    self.filename_base, summary
+                ));
+                header
+            }
+            None => header,
+        }
+    }
+
+    /// The header comment preceding the generated code.
+    fn render_header(&self, input_digest: Option<&str>) -> Result<Option<String>, std::io::Error> {
+        let mut header = match self.license {
+            Some(ref license) => license.render()?,
+            None => String::new(),
+        };
+        #[cfg(any(feature = "call-site", test))]
+        if let Some(ref call_site) = self.call_site {
+            header += &format!(
+                "/* generated from {} by `{}` */\n",
+                call_site, self.filename_base
+            );
+        }
+        if let Some(ref structured) = self.header {
+            header += &structured.render(&self.filename_base, input_digest, self.comment_style);
+        }
+        if let Some(ref comment) = self.comment {
+            header += &self.comment_style.render(comment);
+        }
+        if header.is_empty() {
+            return Ok(None);
+        }
+        let header = self.remap_paths(&header);
+        String::from_utf8(self.convert_line_endings(header.into_bytes()))
+            .map(Some)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
     /// Format the resulting file, for readability.
     pub fn fmt(mut self, edition: impl Into<Edition>) -> Self {
         self.rustfmt = RustFmt::Yes {
             edition: edition.into(),
             channel: Channel::Default,
             allow_failure: false,
+            unstable: Vec::new(),
         };
         self
     }
@@ -142,185 +1109,3439 @@ impl Expander {
             edition: edition.into(),
             channel: channel.into(),
             allow_failure,
+            unstable: Vec::new(),
         };
         self
     }
 
-    /// Do not modify the provided tokenstream.
-    pub fn dry(mut self, dry: bool) -> Self {
-        self.dry = dry;
+    /// Format with the first of the `formatters` to succeed, rather than the built-in chain of
+    /// prettyplease with feature `pretty`, then `rustfmt` if enabled, then the raw tokens, in which
+    /// a failure of `rustfmt` is fatal unless allowed.
+    ///
+    /// A failure falls back to the next formatter, reported as warning if `report`, silently
+    /// otherwise. Only a failure of the last one is an error, i.e. `[RustFmt]` requires `rustfmt`
+    /// while `[RustFmt, Raw]` falls back to the unformatted tokens.
+    pub fn format_chain(
+        mut self,
+        formatters: impl IntoIterator<Item = Formatting>,
+        report: bool,
+    ) -> Self {
+        self.format_chain = Some(FormatChain {
+            formatters: formatters.into_iter().collect(),
+            report,
+        });
+        self
+    }
+
+    /// Pass unstable `rustfmt` configuration, i.e. `("wrap_comments", "true")`, alongside
+    /// `--unstable-features`, to format like the rest of a project formatted with nightly.
+    ///
+    /// Only applies to [`Channel::Nightly`] selected with [`fmt_full`](Self::fmt_full), before or
+    /// after this call, other channels reject unstable features.
+    pub fn fmt_unstable(
+        mut self,
+        config: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+    ) -> Self {
+        self.fmt_unstable.extend(
+            config
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    /// The `rustfmt` settings including the [`fmt_unstable`](Self::fmt_unstable) configuration.
+    fn rustfmt(&self) -> RustFmt {
+        let mut rustfmt = self.rustfmt.clone();
+        if let RustFmt::Yes {
+            ref mut unstable, ..
+        } = rustfmt
+        {
+            unstable.clone_from(&self.fmt_unstable);
+        }
+        rustfmt
+    }
+
+    /// Format the resulting file with the channel pinned by the `rust-toolchain.toml` of the
+    /// workspace, and the edition of the crate being compiled, rather than hard-coding either.
+    ///
+    /// Both files are searched for from `CARGO_MANIFEST_DIR` upwards. A pinned version is left to
+    /// `rustup` to resolve. Without an edition in the manifest, the newest edition supported by a
+    /// pinned version is used.
+    pub fn fmt_detected(self) -> Self {
+        let manifest_dir = env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default();
+        let (channel, edition) = toolchain::detect(Path::new(&manifest_dir));
+        self.fmt_full(channel, edition, false)
+    }
+
+    /// Append `transform` to the pipeline of transforms, see [`Transform`].
+    ///
+    /// Transforms take precedence over streaming the tokens into the file, a
+    /// [`memoize`](Self::memoize)d expansion and a span map.
+    pub fn transform(mut self, transform: impl Transform + 'static) -> Self {
+        self.transforms
+            .push(transform::Step::Transform(std::sync::Arc::new(transform)));
+        self
+    }
+
+    /// Map the tokens right before they are formatted, i.e. to append a test module or strip items,
+    /// as a [`transform`](Self::transform) of [`Stage::Tokens`].
+    pub fn map_tokens(
+        self,
+        map: impl Fn(TokenStream) -> TokenStream + Send + Sync + 'static,
+    ) -> Self {
+        self.transform(move |stage| match stage {
+            Stage::Tokens(tokens) => Ok(Stage::Tokens(map(tokens))),
+            source => Ok(source),
+        })
+    }
+
+    /// Apply the built-in formatting at this point of the pipeline of transforms, rather than
+    /// after the last one, so the following transforms receive the formatted [`Stage::Source`],
+    /// i.e. to validate it.
+    pub fn transform_format(mut self) -> Self {
+        self.transforms.push(transform::Step::Format);
+        self
+    }
+
+    /// Run `program` with `args` on the written file, i.e. a custom formatter or linter, after
+    /// formatting. Occurrences of `{file}` in `args` are replaced with the path of the file.
+    ///
+    /// A non-zero exit status fails the expansion. Commands run in the order they were added,
+    /// before any [`after_write`](Self::after_write) hook.
+    pub fn post_command(
+        self,
+        program: impl AsRef<str>,
+        args: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.post_command_full(program, args, false)
+    }
+
+    /// Run `program` with `args` on the written file, see [`post_command`](Self::post_command).
+    ///
+    /// Allows to specify if a failure is fatal in addition, like [`fmt_full`](Self::fmt_full).
+    pub fn post_command_full(
+        mut self,
+        program: impl AsRef<str>,
+        args: impl IntoIterator<Item = impl AsRef<str>>,
+        allow_failure: bool,
+    ) -> Self {
+        self.post_commands.push(PostCommand {
+            program: program.as_ref().to_owned(),
+            args: args
+                .into_iter()
+                .map(|arg| arg.as_ref().to_owned())
+                .collect(),
+            allow_failure,
+        });
+        self
+    }
+
+    /// Do not modify the provided tokenstream.
+    pub fn dry(mut self, dry: bool) -> Self {
+        self.dry = dry;
+        self
+    }
+
+    /// Stay dry unless the environment variable `var` is set for the compilation.
+    ///
+    /// Allows shipping a macro with file expansion disabled, which users enable for debugging
+    /// without recompiling the macro crate, i.e. `EXPAND_DEBUG=1 cargo build`. Changing the
+    /// variable does not cause a rebuild of the consuming crate by itself.
+    pub fn dry_unless_env(mut self, var: &str) -> Self {
+        self.env_vars.push(var.to_owned());
+        self.dry(env::var_os(var).is_none())
+    }
+
+    /// Stay dry if the environment variable `var` is set for the compilation, see [`dry_unless_env`](Self::dry_unless_env).
+    pub fn dry_if_env(mut self, var: &str) -> Self {
+        self.env_vars.push(var.to_owned());
+        self.dry(env::var_os(var).is_some())
+    }
+
+    /// Stay dry unless the crate is built with a debug profile, i.e. `dev` or `test`.
+    ///
+    /// The file indirection is a debugging aid, release builds take the path without any IO.
+    /// The profile is derived from the cargo target directory the build writes to.
+    pub fn dry_unless_debug(self) -> Self {
+        let out_dir = env::var_os("OUT_DIR")
+            .map_or_else(|| std::path::PathBuf::from(env!("OUT_DIR")), Into::into);
+        let debug =
+            profile_of(&out_dir).map_or(cfg!(debug_assertions), |profile| profile == "debug");
+        self.dry(!debug)
+    }
+
+    /// Suppress all output which is not an error, i.e. warnings about falling back to `rustfmt`
+    /// or waiting for a lock, for byte-exact comparisons of build logs. Takes precedence over
+    /// [`verbose`](Self::verbose), enabled for all expansions by the [`QUIET`] environment variable.
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Fail the expansion if prettyplease can't parse the generated code, rather than warning
+    /// and falling back to `rustfmt`, to catch broken codegen loudly. The unparsable tokens are
+    /// dumped next to the destination for inspection. Combine with
+    /// [`write_to_or_compile_error`](Self::write_to_or_compile_error) to surface the error as
+    /// `compile_error!`.
+    #[cfg(feature = "pretty")]
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Print the path of the generated file to `stderr` during the proc-macro invocation.
+    ///
+    /// If the content changed since the previous expansion, a summary of the added and removed
+    /// lines and the first differing line is printed as well.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Append the expansion as uniquely delimited region to the shared file
+    /// `{filename_base}-merged.rs` instead of writing a dedicated file per expansion.
+    ///
+    /// Each region is wrapped in a `macro_rules!` definition named after the digest of its content,
+    /// the returned tokens invoke the macro of their region as
+    /// `crate::__expander_merged_{filename_base}::__expander_region_{digest}! {}`. Hence this is only
+    /// applicable for expansions in item position. Content containing a `$` can not be embedded into
+    /// a macro definition, and is written to a dedicated file instead.
+    ///
+    /// The shared file is included only once per crate, by the tokens of
+    /// [`include_merged`](Self::include_merged), which must be expanded after all merged
+    /// expansions, i.e. by a macro invoked as last item of the crate root. The shared file only
+    /// grows by regions of distinct content and is never cleaned up.
+    pub fn merge(mut self, merge: bool) -> Self {
+        self.merge = merge;
+        self
+    }
+
+    /// The module `__expander_merged_{filename_base}` including the shared file of
+    /// [`merge`](Self::merge) within `dest_dir`, to be returned by a macro invoked as last item of
+    /// the crate root, after all merged expansions appended their regions.
+    ///
+    /// The shared file is created if no expansion was merged yet.
+    pub fn include_merged(&self, dest_dir: &Path) -> Result<TokenStream, std::io::Error> {
+        let dest_dir = self.dest_dir(dest_dir, !self.check_only)?;
+        let merged = dest_dir.join(self.filename_base.clone() + "-merged.rs");
+        if !self.check_only {
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&merged)?;
+        }
+        track(&merged);
+        let module = merged_module(&self.filename_base);
+        let include = path_literal(&merged)?;
+        Ok(self.gate(quote! {
+            #[doc(hidden)]
+            mod #module {
+                include!( #include );
+            }
+        }))
+    }
+
+    /// Like [`include_merged`](Self::include_merged), for the shared file within the `OUT_DIR` of
+    /// the crate being compiled, see [`write_to_out_dir`](Self::write_to_out_dir).
+    pub fn include_merged_out_dir(&self) -> Result<TokenStream, std::io::Error> {
+        self.include_merged(&self.out_dir())
+    }
+
+    /// Register the written file as module in `expander_generated/mod.rs` next to it, see [`aggregate_file`].
+    pub fn aggregate(mut self, aggregate: bool) -> Self {
+        self.aggregate = aggregate;
+        self
+    }
+
+    /// Declare the written file as module `name` with `#[path = "…"] mod name;` instead of `include!`-ing it.
+    ///
+    /// The generated items are then only reachable via `name::…` rather than being spliced into
+    /// the current scope. Has no effect in [`merge`](Self::merge) mode.
+    pub fn include_as_module(mut self, name: impl AsRef<str>) -> Self {
+        self.emit = Emit::Module(name.as_ref().to_owned());
+        self
+    }
+
+    /// Prepend inner attributes to the generated file, i.e. `quote!(allow(clippy::all))` for
+    /// `#![allow(clippy::all)]`, to suppress lints for all of the generated code.
+    ///
+    /// `include!` rejects inner attributes, so they require [`include_as_module`](Self::include_as_module),
+    /// writing returns an error otherwise.
+    pub fn inner_attrs(mut self, attrs: impl IntoIterator<Item = impl ToTokens>) -> Self {
+        self.inner_attrs = attrs.into_iter().map(Stored::new).collect();
+        self
+    }
+
+    #[cfg(feature = "pretty")]
+    /// Attach outer attributes to every top level item of the generated code, i.e.
+    /// `quote!(doc(hidden))` for `#[doc(hidden)]`.
+    ///
+    /// Spares threading attributes such as `#[automatically_derived]` or `#[cfg(feature = "…")]`
+    /// through all `quote!` invocations. Tokens which don't parse as a file are written unchanged.
+    pub fn outer_attrs(mut self, attrs: impl IntoIterator<Item = impl ToTokens>) -> Self {
+        self.outer_attrs = attrs.into_iter().map(Stored::new).collect();
+        self
+    }
+
+    #[cfg(feature = "pretty")]
+    /// Prepend the outer attributes to each item of `file`.
+    fn attribute_items(&self, file: &mut syn::File) {
+        for item in file.items.iter_mut() {
+            if let Some(attrs) = item_attrs_mut(item) {
+                let outer = self.outer_attrs.iter().map(|attr| -> syn::Attribute {
+                    syn::parse_quote! { #[ #attr ] }
+                });
+                attrs.splice(0..0, outer);
+            }
+        }
+    }
+
+    /// Apply the inner and outer attributes to `tokens` and wrap them in the module.
+    fn attribute(&self, tokens: TokenStream) -> TokenStream {
+        let tokens = self.attribute_outer(tokens);
+        let inner = &self.inner_attrs;
+        match self.wrap {
+            Some(ref wrap) => wrap.wrap(inner, tokens),
+            None if inner.is_empty() => tokens,
+            None => quote! {
+                #( #![ #inner ] )*
+                #tokens
+            },
+        }
+    }
+
+    /// Apply the outer attributes to every item of `tokens`.
+    fn attribute_outer(&self, tokens: TokenStream) -> TokenStream {
+        #[cfg(feature = "pretty")]
+        let tokens = if self.outer_attrs.is_empty() {
+            tokens
+        } else {
+            match syn::parse2::<syn::File>(tokens.clone()) {
+                Ok(mut file) => {
+                    self.attribute_items(&mut file);
+                    file.into_token_stream()
+                }
+                Err(_) => tokens,
+            }
+        };
+        tokens
+    }
+
+    #[cfg(feature = "pretty")]
+    /// Apply the inner and outer attributes to `file` and wrap it in the module.
+    fn attribute_file(&self, mut file: syn::File) -> syn::File {
+        self.attribute_items(&mut file);
+        let inner = &self.inner_attrs;
+        match self.wrap {
+            Some(ref wrap) => {
+                let wrapped = wrap.wrap(inner, file.into_token_stream());
+                syn::parse_quote! { #wrapped }
+            }
+            None => {
+                let inner = inner.iter().map(|attr| -> syn::Attribute {
+                    syn::parse_quote! { #![ #attr ] }
+                });
+                file.attrs.splice(0..0, inner);
+                file
+            }
+        }
+    }
+
+    /// Verify the inner attributes can be applied to the file as it is referenced.
+    fn check_inner_attrs(&self) -> Result<(), std::io::Error> {
+        if self.inner_attrs.is_empty()
+            || self.wrap.is_some()
+            || (matches!(self.emit, Emit::Module(_)) && !self.merge)
+        {
+            return Ok(());
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "expander: inner attributes require `include_as_module` or `wrap_in_module`, `include!` does not permit them",
+        ))
+    }
+
+    /// Use `len` hex characters of the digest of the content in the filename, 12 by default.
+    ///
+    /// Clamped to `8..=64`, 64 being the full Blake2s digest. Content with the same digest is
+    /// assumed to be identical and written to the same file, so two distinct expansions of the same
+    /// filename base colliding within the leading `len` characters overwrite each other. With `n`
+    /// expansions per filename base the probability of that is about `n² / 2^(4 · len + 1)`, for 12
+    /// characters about one in a million for 24000 expansions, full digests are collision free in practice.
+    pub fn digest_len(mut self, len: usize) -> Self {
+        self.digest_len = len.clamp(*DIGEST_LENS.start(), *DIGEST_LENS.end());
+        self
+    }
+
+    /// Derive the digest in the filename from the tokens before formatting, rather than from the
+    /// formatted code.
+    ///
+    /// Filenames then stay the same when switching between `rustfmt` versions or toggling the
+    /// `pretty` feature, though they still change with the `rustc` version serializing the tokens.
+    /// Has no effect in [`merge`](Self::merge) mode.
+    pub fn hash_input(mut self, hash_input: bool) -> Self {
+        self.hash_input = hash_input;
+        self
+    }
+
+    /// Write into `{dest_dir}/{crate}-{digest}/`, with the name of the crate being compiled and a
+    /// digest of its manifest dir and version, rather than into `dest_dir` directly.
+    ///
+    /// Expansions of different crates never share a directory and can be removed per crate.
+    pub fn per_crate_dir(mut self, per_crate_dir: bool) -> Self {
+        self.per_crate_dir = per_crate_dir;
+        self
+    }
+
+    /// Write into `{dest_dir}/{filename_base}/` rather than into `dest_dir` directly, nested within the
+    /// directory of [`per_crate_dir`](Self::per_crate_dir) if enabled.
+    ///
+    /// Keeps the output of many different macros browsable, removing the expansions of one macro
+    /// is a matter of removing its directory.
+    pub fn per_macro_dir(mut self, per_macro_dir: bool) -> Self {
+        self.per_macro_dir = per_macro_dir;
+        self
+    }
+
+    /// Shard the written files by the first two hex characters of their digest, i.e.
+    /// `{dest_dir}/ab/{filename_base}-abcdef….rs`, like the object store of `git`.
+    ///
+    /// For generators with thousands of expansions per build, which slow down some filesystems
+    /// if written into a single directory.
+    pub fn shard(mut self, shard: bool) -> Self {
+        self.shard = shard;
+        self
+    }
+
+    /// Set the unix permission bits of the written file once the write completed, i.e. `0o444`.
+    /// Ignored on other platforms.
+    pub fn file_mode(mut self, mode: u32) -> Self {
+        self.file_mode = Some(mode);
+        self
+    }
+
+    /// Mark the written file read-only once the write completed, so accidental manual edits fail
+    /// rather than being silently clobbered by the next build.
+    ///
+    /// An existing file with identical content is left as is, a differing one is replaced.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Control whether other processes, i.e. `rustc`, can open the file while it is being written.
+    /// Only has an effect on windows, see [`ShareMode`].
+    pub fn share_mode(mut self, share_mode: ShareMode) -> Self {
+        self.share_mode = share_mode;
+        self
+    }
+
+    /// Flush the written file, and on unix its directory, to disk before returning.
+    ///
+    /// Guards against truncated files after a crash of the machine, which would be considered up to
+    /// date by their digest based name otherwise. Costs some write performance.
+    /// Not applied to the shared file of [`merge`](Self::merge).
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Also maintain `{filename_base}-latest.{extension}` next to the written files, a hard link of
+    /// or, where not supported, a copy of the last written file.
+    ///
+    /// One predictable path to open in an editor, regardless of the current digest.
+    pub fn latest(mut self, latest: bool) -> Self {
+        self.latest = latest;
+        self
+    }
+
+    /// Also copy the written file into each of `dirs`, i.e. a shared directory for review, after it
+    /// was written and post-processed. The directories are created if missing.
+    pub fn also_write_to(
+        mut self,
+        dirs: impl IntoIterator<Item = impl Into<std::path::PathBuf>>,
+    ) -> Self {
+        self.mirrors.extend(dirs.into_iter().map(Into::into));
+        self
+    }
+
+    /// Call `hook` with the path of the written file, once it is completely written, unlocked and
+    /// all bookkeeping is done, i.e. to copy, index or check it. Hooks run in the order they were
+    /// added, an error fails the expansion.
+    ///
+    /// Not called if nothing was written, i.e. in [`check_only`](Self::check_only) mode or for a
+    /// [`memoize`](Self::memoize)d expansion.
+    pub fn after_write(
+        mut self,
+        hook: impl Fn(&Path) -> Result<(), std::io::Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.after_write.0.push(std::sync::Arc::new(hook));
+        self
+    }
+
+    /// Include the files relative to the `OUT_DIR` of the crate being compiled, its manifest dir
+    /// if it has no build script, i.e. `include!(concat!(env!("OUT_DIR"), "/../../…/baz-….rs"))`.
+    ///
+    /// The includes remain valid if the target directory moves, i.e. with remote caches like
+    /// `sccache` replaying builds on other machines.
+    pub fn relocatable(mut self, relocatable: bool) -> Self {
+        self.anchor = if relocatable {
+            Anchor::OutDir
+        } else {
+            Anchor::Absolute
+        };
+        self
+    }
+
+    /// Write into a content addressed store within the destination directory, `store/blob-{digest}.rs`,
+    /// shared by all filename bases, so identical expansions of different invocations are written
+    /// once.
+    ///
+    /// Every filename base references a single stored file, a stored file is removed once it is no
+    /// longer referenced, which supersedes [`gc`](Self::gc) and [`history`](Self::history).
+    /// Not applied in [`merge`](Self::merge) mode.
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Reuse the formatting of identical tokens across all crates of a workspace, from a cache in the
+    /// `expander-cache` directory of the cargo target directory, or the directory in [`CACHE_DIR`].
+    ///
+    /// Entries are keyed by the digest of the tokens, the formatter settings and the toolchain.
+    /// Concurrent builds wait for each other instead of formatting the same tokens twice.
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Skip formatting and writing entirely if an earlier build expanded identical tokens with an
+    /// identical configuration, and reference the file written back then.
+    ///
+    /// An index in the `memo` directory of the destination directory maps the digest of the input
+    /// tokens, the configuration affecting the written file and the toolchain to the written file,
+    /// toggling i.e. [`verbose`](Self::verbose) keeps hitting. Entries whose file was removed since
+    /// are ignored, as are entries written with a different [`header_file`](Self::header_file)
+    /// content. Not applied in [`merge`](Self::merge) or [`check_only`](Self::check_only) mode.
+    ///
+    /// A hit is still registered in the aggregate file, the history and the manifest, and copied
+    /// to the mirrors. Post commands, the metadata sidecar and [`after_write`](Self::after_write)
+    /// hooks are skipped, since nothing is written.
+    pub fn memoize(mut self, memoize: bool) -> Self {
+        self.memoize = memoize;
+        self
+    }
+
+    /// Use from a build script: the `*_out_dir` variants write to the `OUT_DIR` of the build script
+    /// and include the files with `include!(concat!(env!("OUT_DIR"), "/baz-….rs"))`.
+    ///
+    /// The tokens are meant to be written into a file included by the crate, which keeps working
+    /// wherever the crate is built, i.e. when published to crates.io.
+    ///
+    /// ```rust,no_run
+    /// # use expander::Expander;
+    /// // build.rs
+    /// let tokens = Expander::new("schema")
+    ///     .for_build_script()
+    ///     .write_to_out_dir(quote::quote! { pub struct Schema; })?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn for_build_script(mut self) -> Self {
+        self.build_script = true;
+        self.anchor = Anchor::OutDir;
+        self
+    }
+
+    /// The `cargo:rerun-if-changed` directives for the `written` files and `cargo:rerun-if-env-changed`
+    /// directives for all environment variables `expander` is configured by, for use in a build script.
+    ///
+    /// The written files are tracked by an [`ExpansionSession`], see [`ExpansionSession::written`].
+    /// Uses the single colon syntax, which is understood by all versions of cargo.
+    pub fn rerun_directives(
+        &self,
+        written: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Vec<String> {
+        let mut directives = written
+            .into_iter()
+            .map(|path| format!("cargo:rerun-if-changed={}", path.as_ref().display()))
+            .collect::<Vec<_>>();
+        let mut vars = vec![STABLE_PATHS, OUT_DIR];
+        if self.reproducible {
+            vars.push("SOURCE_DATE_EPOCH");
+        }
+        vars.extend(self.env_vars.iter().map(String::as_str));
+        directives.extend(
+            vars.into_iter()
+                .map(|var| format!("cargo:rerun-if-env-changed={}", var)),
+        );
+        directives
+    }
+
+    /// Print the [`rerun_directives`](Self::rerun_directives) to `stdout`, where cargo picks them up.
+    pub fn rerun_if_changed(&self, written: impl IntoIterator<Item = impl AsRef<Path>>) {
+        for directive in self.rerun_directives(written) {
+            println!("{}", directive);
+        }
+    }
+
+    /// The directory of the `*_out_dir` variants, the `OUT_DIR` of the build script if
+    /// [`for_build_script`](Self::for_build_script) unless overridden by [`OUT_DIR`].
+    fn out_dir(&self) -> std::path::PathBuf {
+        match env::var_os("OUT_DIR") {
+            Some(out_dir) if self.build_script && env::var_os(OUT_DIR).is_none() => out_dir.into(),
+            _ => out_dir(),
+        }
+    }
+
+    /// Directory the paths of included files are relative to, the manifest dir if
+    /// [`reproducible`](Self::reproducible), the out dir if `dest` is subject to path remapping.
+    fn anchor(&self, dest: &Path) -> Anchor {
+        match self.anchor {
+            Anchor::Absolute if self.reproducible => Anchor::ManifestDir,
+            Anchor::Absolute if self.remapped(&dest.display().to_string()).is_some() => {
+                Anchor::OutDir
+            }
+            anchor => anchor,
+        }
+    }
+
+    /// Remap paths starting with `from` to `to` in header comments, like
+    /// `rustc --remap-path-prefix {from}={to}`, which is also picked up from `RUSTFLAGS` and
+    /// `CARGO_ENCODED_RUSTFLAGS`.
+    ///
+    /// Files with a remapped path are included relative to `OUT_DIR`, as with
+    /// [`relocatable`](Self::relocatable), since `rustc` resolves the path of `include!` on disk.
+    pub fn remap_path_prefix(mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Self {
+        self.path_prefixes.push((
+            from.as_ref().display().to_string(),
+            to.as_ref().display().to_string(),
+        ));
+        self
+    }
+
+    /// All path prefix mappings, the last matching one takes precedence like with `rustc`.
+    fn path_prefixes(&self) -> Vec<(String, String)> {
+        let flags: Vec<String> = match env::var("CARGO_ENCODED_RUSTFLAGS") {
+            Ok(encoded) => encoded.split('\x1f').map(str::to_owned).collect(),
+            Err(_) => env::var("RUSTFLAGS")
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect(),
+        };
+        let mut path_prefixes = remap_flags(&flags);
+        path_prefixes.extend(self.path_prefixes.iter().cloned());
+        path_prefixes
+    }
+
+    /// `path` with its prefix remapped, if any mapping applies.
+    fn remapped(&self, path: &str) -> Option<String> {
+        self.path_prefixes()
+            .into_iter()
+            .rev()
+            .filter(|(from, _)| !from.is_empty())
+            .find_map(|(from, to)| {
+                let rest = path.strip_prefix(from.as_str())?;
+                Some(to + rest)
+            })
+    }
+
+    /// `text` with all paths remapped, see [`remap_path_prefix`](Self::remap_path_prefix).
+    fn remap_paths(&self, text: &str) -> String {
+        let path_prefixes = self.path_prefixes();
+        if path_prefixes.is_empty() {
+            return text.to_owned();
+        }
+        let mut remapped = String::with_capacity(text.len());
+        let mut rest = text;
+        'scan: while !rest.is_empty() {
+            for (from, to) in path_prefixes.iter().rev() {
+                if !from.is_empty() && rest.starts_with(from.as_str()) {
+                    remapped += to;
+                    rest = &rest[from.len()..];
+                    continue 'scan;
+                }
+            }
+            let next = rest.chars().next().expect("Not empty. qed");
+            remapped.push(next);
+            rest = &rest[next.len_utf8()..];
+        }
+        remapped
+    }
+
+    /// The directory to write to within `dest_dir`, created if `create` is set.
+    fn dest_dir(
+        &self,
+        dest_dir: &Path,
+        create: bool,
+    ) -> Result<std::path::PathBuf, std::io::Error> {
+        let mut dir = dest_dir.to_owned();
+        if self.per_crate_dir {
+            let unit = format!(
+                "{}\0{}",
+                env::var("CARGO_MANIFEST_DIR").unwrap_or_default(),
+                env::var("CARGO_PKG_VERSION").unwrap_or_default()
+            );
+            let suffix = Hasher::default().suffix(&[], unit.as_bytes(), DEFAULT_DIGEST_LEN);
+            let crate_name = crate_name().unwrap_or_else(|| "unknown".to_owned());
+            dir.push(format!("{}-{}", crate_name, suffix));
+        }
+        if self.per_macro_dir {
+            dir.push(&self.filename_base);
+        }
+        if create && dir != dest_dir {
+            fs::create_dir_all(&dir)?;
+        }
+        Ok(dir)
+    }
+
+    /// Mix `salt` into the digest in the filename, in addition to any previous salt.
+    ///
+    /// Forces distinct files for identical content, i.e. per crate or per feature set.
+    pub fn salt(mut self, salt: impl AsRef<[u8]>) -> Self {
+        self.salt.extend_from_slice(salt.as_ref());
+        self.salt.push(0);
+        self
+    }
+
+    /// Mix the values of the environment variables `vars` at the time of the macro invocation into
+    /// the digest in the filename, i.e. `["CARGO_PKG_NAME", "CARGO_PKG_VERSION"]`, see [`salt`](Self::salt).
+    pub fn salt_env(mut self, vars: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        for var in vars {
+            let var = var.as_ref();
+            self.env_vars.push(var.to_owned());
+            let value = env::var_os(var).unwrap_or_default();
+            self = self.salt(format!("{}={}", var, value.to_string_lossy()));
+        }
+        self
+    }
+
+    /// Hash function for the digest in the filename, see [`Hasher`].
+    ///
+    /// The digest only disambiguates files, `Hasher::Xxh3` of feature `xxhash` is sufficient
+    /// and saves time for large expansions. Its digest has at most 32 hex characters.
+    pub fn hasher(mut self, hasher: Hasher) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Gate the returned `include!` with `#[cfg(predicate)]`, i.e. `quote!(feature = "codegen")`.
+    ///
+    /// The file is written regardless, the code only participates in builds matching the predicate.
+    /// In [`dry`](Self::dry) mode, or if returned inline otherwise, the tokens are returned as is.
+    pub fn cfg(mut self, predicate: impl ToTokens) -> Self {
+        self.cfg = Some(Stored::new(predicate));
+        self
+    }
+
+    /// Enclose the generated code in `pub(crate) mod {name} { … }`.
+    ///
+    /// Keeps the generated items apart from the ones at the call site and makes them easy to spot
+    /// in symbol search. [`inner_attrs`](Self::inner_attrs) are applied within the module.
+    pub fn wrap_in_module(self, name: impl AsRef<str>) -> Self {
+        self.wrap_in_module_with(name, quote!(pub(crate)), None::<TokenStream>)
+    }
+
+    /// Enclose the generated code in `{attrs} {vis} mod {name} { … }`, i.e. with `quote!(pub)` as
+    /// visibility and `[quote!(doc(hidden))]` as attributes, see [`wrap_in_module`](Self::wrap_in_module).
+    pub fn wrap_in_module_with(
+        mut self,
+        name: impl AsRef<str>,
+        vis: impl ToTokens,
+        attrs: impl IntoIterator<Item = impl ToTokens>,
+    ) -> Self {
+        self.wrap = Some(Wrap {
+            name: name.as_ref().to_owned(),
+            vis: Stored::new(vis),
+            attrs: attrs.into_iter().map(Stored::new).collect(),
+        });
         self
     }
 
-    /// Print the path of the generated file to `stderr` during the proc-macro invocation.
-    pub fn verbose(mut self, verbose: bool) -> Self {
-        self.verbose = verbose;
-        self
+    /// Reference the written file as `const {binding}: &str = include_str!("…");`.
+    ///
+    /// Intended for string assets written with [`write_bytes_to`](Self::write_bytes_to).
+    pub fn include_str(mut self, binding: impl AsRef<str>) -> Self {
+        self.emit = Emit::Str(binding.as_ref().to_owned());
+        self
+    }
+
+    /// Reference the written file as `const {binding}: &[u8] = include_bytes!("…");`.
+    ///
+    /// Intended for binary assets written with [`write_bytes_to`](Self::write_bytes_to).
+    pub fn include_bytes(mut self, binding: impl AsRef<str>) -> Self {
+        self.emit = Emit::Bytes(binding.as_ref().to_owned());
+        self
+    }
+
+    /// Write a sidecar artifact with extension `extension`, i.e. `.sql` or `.json`, rather than Rust code.
+    ///
+    /// Use [`write_artifact_to`](Self::write_artifact_to) to obtain the path of the written file, no
+    /// comment is prepended and no tokens are emitted to reference it.
+    pub fn raw_artifact(mut self, extension: impl AsRef<str>) -> Self {
+        self.emit = Emit::Artifact;
+        self.extension(extension)
+    }
+
+    /// Use `extension` instead of `rs` for the written file.
+    pub fn extension(mut self, extension: impl AsRef<str>) -> Self {
+        self.extension = extension.as_ref().to_owned();
+        self
+    }
+
+    /// Remove files with the same filename base but a different digest after writing.
+    ///
+    /// Every change of the generated content leaves the previous `{filename_base}-{digest}.rs` behind
+    /// otherwise. Note that different crates might write different content with the same filename base
+    /// to the same directory in parallel, in which case the files of the other crates are removed too.
+    /// Prefer [`gc_older_than`](Self::gc_older_than) if that is a concern.
+    pub fn gc(mut self, gc: bool) -> Self {
+        self.gc = if gc { Gc::Stale } else { Gc::Off };
+        self
+    }
+
+    /// Like [`gc`](Self::gc), but only remove files which were not modified within `ttl`.
+    pub fn gc_older_than(mut self, ttl: std::time::Duration) -> Self {
+        self.gc = Gc::OlderThan(ttl);
+        self
+    }
+
+    /// Retain the previous `versions` of the written file in `{filename_base}.history/` and print a
+    /// unified diff to `stderr` whenever the content changes.
+    pub fn history(mut self, versions: usize) -> Self {
+        self.history = versions;
+        self
+    }
+
+    /// Write the diff of [`history`](Self::history) to `{file}.diff` instead of printing it.
+    pub fn diff_to_file(mut self, diff_to_file: bool) -> Self {
+        self.diff_to_file = diff_to_file;
+        self
+    }
+
+    /// Verify the file that would be written already exists with identical content, without touching it.
+    ///
+    /// Returns an error of kind [`std::io::ErrorKind::InvalidData`] naming the file if it is missing or
+    /// differs, i.e. to ensure generated code is up to date in CI. In [`merge`](Self::merge) mode the
+    /// presence of the region is verified. No bookkeeping is done.
+    pub fn check_only(mut self, check_only: bool) -> Self {
+        self.check_only = check_only;
+        self
+    }
+
+    /// Write to `{filename_base}.{extension}` without the digest of the content, i.e. for UI tests.
+    ///
+    /// Paths in diagnostics pointing into the generated file then don't change with its content.
+    /// Expansions with the same filename base overwrite each other, so every invocation requires a
+    /// distinct filename base. Also enabled by setting the environment variable [`STABLE_PATHS`]
+    /// for the compilation, i.e. from within a `trybuild` test.
+    pub fn stable_path(mut self, stable_path: bool) -> Self {
+        self.stable_path = stable_path;
+        self
+    }
+
+    /// Return the tokens inline with a warning, rather than an error, if writing the file fails.
+    ///
+    /// The file indirection is a debugging aid, failing the build in read-only or sandboxed
+    /// environments is the wrong trade-off. Errors of kind [`std::io::ErrorKind::InvalidData`],
+    /// i.e. of [`check_only`](Self::check_only) or validation, are returned regardless.
+    pub fn fallback_inline(mut self, fallback_inline: bool) -> Self {
+        self.fallback_inline = fallback_inline;
+        self
+    }
+
+    /// Only write expansions of at least `bytes` bytes to a file, return smaller ones inline.
+    ///
+    /// The size is measured on the unformatted tokens. Small expansions skip the file IO and the
+    /// formatter entirely, so only large outputs pay for the indirection.
+    pub fn min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Produce byte identical output across machines and operating systems.
+    ///
+    /// Line endings of the formatted code are normalized to `\n`, so the digest in the filename
+    /// doesn't depend on the formatter of the platform, metadata sidecars record the timestamp of
+    /// `SOURCE_DATE_EPOCH` or none, and files are included relative to `CARGO_MANIFEST_DIR`
+    /// rather than by absolute path. Files referenced with
+    /// [`include_as_module`](Self::include_as_module) and [`merge`](Self::merge) regions are still
+    /// referenced by absolute path.
+    pub fn reproducible(mut self, reproducible: bool) -> Self {
+        self.reproducible = reproducible;
+        self
+    }
+
+    /// Convert the line endings of the generated code, before the digest for the filename is determined.
+    ///
+    /// By default the output of the formatter is written as is, which is `\r\n` for `rustfmt` on
+    /// windows, but `\n` for `prettyplease`. [`reproducible`](Self::reproducible) mode implies
+    /// [`LineEnding::Lf`].
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = Some(line_ending);
+        self
+    }
+
+    /// Apply the configured line endings to `bytes`.
+    fn convert_line_endings(&self, bytes: Vec<u8>) -> Vec<u8> {
+        match self.line_ending {
+            Some(line_ending) => line_ending.apply(bytes),
+            None if self.reproducible => LineEnding::Lf.apply(bytes),
+            None => bytes,
+        }
+    }
+
+    /// Whether `tokens` are below [`min_size`](Self::min_size) and to be returned inline.
+    fn inline(&self, tokens: &TokenStream) -> bool {
+        self.min_size > 0 && tokens.to_string().len() < self.min_size
+    }
+
+    #[cfg(any(feature = "validate", test))]
+    /// Read the written file back and parse it, returning [`validate::InvalidCode`] if it does not parse.
+    ///
+    /// Catches truncated writes or a corrupting formatter before `rustc` reports errors pointing
+    /// into the generated file. Only applies to files `include!`-ed or declared as module.
+    pub fn read_back(mut self, read_back: bool) -> Self {
+        self.read_back = read_back;
+        self
+    }
+
+    #[cfg(any(feature = "validate", test))]
+    /// Parse the tokens as a Rust file before writing, rather than writing known broken code to disk.
+    ///
+    /// [`maybe_write_any_to`](Self::maybe_write_any_to) returns the `syn::Error`, carrying the span
+    /// of the offending token, as inner error (see [`MacroError::from_invalid`]), all other writers a
+    /// [`validate::InvalidCode`].
+    pub fn validate(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+    /// Write a `{file}.spans.json` sidecar mapping the lines of each top level item in the written
+    /// file back to the spans of its input tokens, see [`spans::SpanMap`].
+    ///
+    /// Has no effect in [`merge`](Self::merge) mode.
+    pub fn span_map(mut self, span_map: bool) -> Self {
+        self.span_map = span_map;
+        self
+    }
+
+    #[cfg(any(feature = "metadata", test))]
+    /// Write a `{file}.meta.json` sidecar next to each written file, see [`metadata::Metadata`].
+    pub fn metadata(mut self, metadata: bool) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    #[cfg(any(feature = "metadata", test))]
+    /// Record each written file in the build-wide manifest, see [`manifest::Manifest`].
+    pub fn manifest(mut self, manifest: bool) -> Self {
+        self.manifest = manifest;
+        self
+    }
+
+    #[cfg(any(feature = "syndicate", test))]
+    /// Create a file with `filename` under `env!("OUT_DIR")` if it's not an `Err(_)`.
+    pub fn maybe_write_to_out_dir(
+        &self,
+        tokens: impl Into<Result<TokenStream, syn::Error>>,
+    ) -> Result<syn::Result<TokenStream>, std::io::Error> {
+        self.maybe_write_any_to_out_dir(tokens.into())
+    }
+
+    /// Create a file with `filename` under `env!("OUT_DIR")` if it's not an `Err(_)`, for any error
+    /// type implementing [`MacroError`].
+    pub fn maybe_write_any_to_out_dir<E: MacroError>(
+        &self,
+        tokens: Result<TokenStream, E>,
+    ) -> Result<Result<TokenStream, E>, std::io::Error> {
+        self.maybe_write_any_to(tokens, self.out_dir().as_path())
+    }
+
+    /// Create a file with `filename` under `env!("OUT_DIR")`.
+    pub fn write_to_out_dir(&self, tokens: impl ToTokens) -> Result<TokenStream, std::io::Error> {
+        let out = self.out_dir();
+        self.write_to(tokens, out.as_path())
+    }
+
+    /// Create a file with `filename` under the `OUT_DIR` of the crate being compiled, looked up at
+    /// runtime unless overridden by [`OUT_DIR`], rather than the one of `expander` itself.
+    ///
+    /// Fails with [`MissingEnv`] if the crate has none, i.e. as it lacks a build script.
+    pub fn try_write_to_out_dir(
+        &self,
+        tokens: impl ToTokens,
+    ) -> Result<TokenStream, std::io::Error> {
+        let out_dir = env::var_os(OUT_DIR)
+            .or_else(|| env::var_os("OUT_DIR"))
+            .ok_or_else(|| MissingEnv {
+                var: "OUT_DIR".to_owned(),
+            })?;
+        self.write_to(tokens, Path::new(&out_dir))
+    }
+
+    /// Like [`write_to_out_dir`](Self::write_to_out_dir), but report a failure as `compile_error!`.
+    pub fn write_to_out_dir_or_compile_error(&self, tokens: impl ToTokens) -> TokenStream {
+        let out = self.out_dir();
+        self.write_to_or_compile_error(tokens, out.as_path())
+    }
+
+    /// Like [`write_to`](Self::write_to), but report a failure as `compile_error!`, rather than
+    /// requiring every proc-macro to panic with a poor message.
+    pub fn write_to_or_compile_error(&self, tokens: impl ToTokens, dest_dir: &Path) -> TokenStream {
+        self.write_to(tokens, dest_dir)
+            .unwrap_or_else(|e| self.compile_error(&e))
+    }
+
+    /// Like [`maybe_write_any_to_out_dir`](Self::maybe_write_any_to_out_dir), but report the error
+    /// of the proc-macro as well as a failure to write as `compile_error!`.
+    pub fn maybe_write_to_out_dir_or_compile_error<E: MacroError>(
+        &self,
+        maybe_tokens: Result<TokenStream, E>,
+    ) -> TokenStream {
+        match self.maybe_write_any_to_out_dir(maybe_tokens) {
+            Ok(Ok(tokens)) => tokens,
+            Ok(Err(e)) => e.into_compile_error(),
+            Err(e) => self.compile_error(&e),
+        }
+    }
+
+    /// Tokens reporting the failure to write `error` as `compile_error!`.
+    fn compile_error(&self, error: &std::io::Error) -> TokenStream {
+        let message = format!(
+            "expander: failed to write `{}`: {}",
+            self.filename_base, error
+        );
+        quote! {
+            ::core::compile_error!(#message);
+        }
+    }
+
+    #[cfg(any(feature = "syndicate", test))]
+    /// Create a file with `filename` at `dest` if it's not an `Err(_)`.
+    pub fn maybe_write_to(
+        &self,
+        maybe_tokens: impl Into<Result<TokenStream, syn::Error>>,
+        dest_dir: &Path,
+    ) -> Result<syn::Result<TokenStream>, std::io::Error> {
+        self.maybe_write_any_to(maybe_tokens.into(), dest_dir)
+    }
+
+    /// Create a file with `filename` at `dest` if it's not an `Err(_)`, for any error type
+    /// implementing [`MacroError`], which is passed through as is.
+    pub fn maybe_write_any_to<E: MacroError>(
+        &self,
+        maybe_tokens: Result<TokenStream, E>,
+        dest_dir: &Path,
+    ) -> Result<Result<TokenStream, E>, std::io::Error> {
+        match maybe_tokens {
+            #[cfg(any(feature = "validate", test))]
+            Ok(tokens) if self.validate && !self.dry => {
+                match validate::parse(&tokens) {
+                    // no need to parse again when writing
+                    Ok(()) => Ok(Ok(self
+                        .clone()
+                        .validate(false)
+                        .write_to(tokens, dest_dir)?)),
+                    Err(e) => {
+                        let dump = dump_invalid(
+                            tokens.to_string().as_bytes(),
+                            &dest_dir.join(&self.filename_base),
+                        )?;
+                        let message =
+                            format!("{} (generated code dumped to {})", e, dump.display());
+                        match E::from_invalid(syn::Error::new(e.span(), message)) {
+                            Ok(e) => Ok(Err(e)),
+                            Err(e) => Ok(Ok(e.to_compile_error())),
+                        }
+                    }
+                }
+            }
+            Ok(tokens) => Ok(Ok(self.write_to(tokens, dest_dir)?)),
+            err => Ok(err),
+        }
+    }
+
+    /// Report the size of the formatted expansion, its bytes and lines and, with feature `pretty`
+    /// or `validate`, the number of top-level items, functions and impl blocks.
+    pub fn stats(mut self, stats: Stats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Warn if the formatted expansion exceeds `limit` bytes, as a single huge file slows down the
+    /// compiler front-end, unnoticed until builds crawl.
+    pub fn warn_above_bytes(mut self, limit: usize) -> Self {
+        self.warn_above_bytes = Some(limit);
+        self
+    }
+
+    /// Warn if formatting an expansion takes longer than `budget`, with prettyplease or `rustfmt`.
+    pub fn fmt_budget(mut self, budget: std::time::Duration) -> Self {
+        self.fmt_budget = Some(budget);
+        self
+    }
+
+    /// Dump the output of each formatting stage next to the written file, to find the stage which
+    /// mangled the code: `{filename}.0.raw.rs` with the serialized tokens, `{filename}.1.pretty.rs`
+    /// with the output of prettyplease and `{filename}.2.rustfmt.rs` with the one of `rustfmt`.
+    ///
+    /// Only the stages that ran are dumped, dumps of previous runs are removed. Bypasses the
+    /// [`cache`](Self::cache).
+    pub fn debug_stages(mut self, debug_stages: bool) -> Self {
+        self.debug_stages = debug_stages;
+        self
+    }
+
+    /// Return the tokens alongside the reference to the file, switched by the
+    /// [`INLINE_CFG`] `cfg`: `--cfg expander_inline` compiles the tokens inline, without it the
+    /// file is included as usual, to compare both without touching the macro crate.
+    ///
+    /// Declare the `cfg` to the `unexpected_cfgs` lint of the calling crate, i.e. with
+    /// `cargo::rustc-check-cfg=cfg(expander_inline)` from its build script.
+    pub fn inline_switch(mut self, inline_switch: bool) -> Self {
+        self.inline_switch = inline_switch;
+        self
+    }
+
+    /// Write the file, but return the tokens as is rather than referencing the file, so it is a
+    /// human-readable artifact only and nothing depends on include paths.
+    ///
+    /// Failing to write is still an error, unless [`fallback_inline`](Self::fallback_inline).
+    pub fn tee(mut self, tee: bool) -> Self {
+        self.tee = tee;
+        self
+    }
+
+    /// Create a file with `self.filename` in  `dest_dir`.
+    ///
+    /// Accepts anything implementing `ToTokens`, i.e. a `syn::ItemFn` or custom codegen types, besides
+    /// a `TokenStream`, which is used as is.
+    pub fn write_to(
+        &self,
+        tokens: impl ToTokens,
+        dest_dir: &Path,
+    ) -> Result<TokenStream, std::io::Error> {
+        self.write_to_path(tokens.into_token_stream(), dest_dir)
+            .map(|(_, tokens)| tokens)
+    }
+
+    /// Like [`write_to`](Self::write_to), but also return the path of the written file, `None` if
+    /// the tokens are returned inline.
+    pub(crate) fn write_to_path(
+        &self,
+        tokens: TokenStream,
+        dest_dir: &Path,
+    ) -> Result<(Option<std::path::PathBuf>, TokenStream), std::io::Error> {
+        let _quiet = diagnostics::Quiet::enter(self.quiet);
+        if self.dry || self.inline(&tokens) {
+            return Ok((None, tokens));
+        }
+        let (dest, expanded) = self.write_referenced(tokens.clone(), dest_dir)?;
+        let tokens = if self.tee {
+            tokens
+        } else if self.inline_switch && self.emit.is_rust() {
+            self.switch(tokens, expanded)
+        } else {
+            expanded
+        };
+        Ok((dest, tokens))
+    }
+
+    /// Write the file and return its path, if written, alongside the tokens referencing it, see
+    /// [`write_to`](Self::write_to).
+    fn write_referenced(
+        &self,
+        tokens: TokenStream,
+        dest_dir: &Path,
+    ) -> Result<(Option<std::path::PathBuf>, TokenStream), std::io::Error> {
+        if self.sandbox_fallback {
+            if let Some(sandbox) = sandbox(dest_dir) {
+                return self.write_sandboxed(tokens, dest_dir, sandbox);
+            }
+        }
+        if self.fallback_inline {
+            return self
+                .expand(tokens.clone(), dest_dir)
+                .map(|(dest, expanded)| (Some(dest), expanded))
+                .or_else(|e| self.fall_back(e, tokens).map(|tokens| (None, tokens)));
+        }
+        match self.expand(tokens.clone(), dest_dir) {
+            Ok((dest, tokens)) => Ok((Some(dest), tokens)),
+            Err(e) if self.sandbox_fallback && is_read_only(&e) => {
+                self.write_sandboxed(tokens, dest_dir, "a read-only filesystem")
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write elsewhere if the destination is within a sandbox, enabled by default.
+    ///
+    /// Some build systems provide an unwritable `OUT_DIR`, i.e. nix within the read-only store,
+    /// bazel as input of the compile action. The file is written to the temporary directory of the
+    /// system instead, or, if that fails as well, the tokens are returned inline, with a warning.
+    pub fn sandbox_fallback(mut self, sandbox_fallback: bool) -> Self {
+        self.sandbox_fallback = sandbox_fallback;
+        self
+    }
+
+    /// Write to the temporary directory, since `dest_dir` is in `sandbox`, see
+    /// [`sandbox_fallback`](Self::sandbox_fallback).
+    fn write_sandboxed(
+        &self,
+        tokens: TokenStream,
+        dest_dir: &Path,
+        sandbox: &str,
+    ) -> Result<(Option<std::path::PathBuf>, TokenStream), std::io::Error> {
+        let fallback = env::temp_dir().join("expander");
+        let written = fs::create_dir_all(&fallback)
+            .and_then(|()| self.expand(tokens.clone(), fallback.as_path()));
+        match written {
+            Ok((dest, tokens)) => {
+                warning(format_args!(
+                    "{} is within {}, wrote `{}` to {} instead",
+                    dest_dir.display(),
+                    sandbox,
+                    self.filename_base,
+                    dest.display()
+                ));
+                Ok((Some(dest), tokens))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Err(e),
+            Err(e) => {
+                warning(format_args!(
+                    "{} is within {}, returning `{}` inline: {}",
+                    dest_dir.display(),
+                    sandbox,
+                    self.filename_base,
+                    e
+                ));
+                Ok((None, tokens))
+            }
+        }
+    }
+
+    /// Create a file with `self.filename` in the first usable of the `OUT_DIR` of the crate being
+    /// compiled, `CARGO_TARGET_TMPDIR` and the temporary directory of the system.
+    ///
+    /// For proc-macros used by crates without a build script, which lack an `OUT_DIR`. Returns the
+    /// directory used alongside the tokens.
+    pub fn write_to_default_dirs(
+        &self,
+        tokens: impl ToTokens,
+    ) -> Result<(DefaultDir, TokenStream), std::io::Error> {
+        let _quiet = diagnostics::Quiet::enter(self.quiet);
+        let tokens = tokens.into_token_stream();
+        let mut error = None;
+        for dir in DefaultDir::candidates() {
+            if let DefaultDir::TempDir(ref path) = dir {
+                fs::create_dir_all(path)?;
+            }
+            match self.write_to(tokens.clone(), dir.path()) {
+                Ok(tokens) => return Ok((dir, tokens)),
+                // the content is at fault, not the directory
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => return Err(e),
+                Err(e) => {
+                    if self.verbose {
+                        debug(format_args!(
+                            "failed to write to {}: {}",
+                            dir.path().display(),
+                            e
+                        ));
+                    }
+                    error = Some(e);
+                }
+            }
+        }
+        Err(error.expect("The temporary directory is always a candidate. qed"))
+    }
+
+    /// Create a file with `self.filename` in `relative` beneath the `CARGO_MANIFEST_DIR` of the crate
+    /// being compiled, i.e. `generated/`, which is created if missing.
+    ///
+    /// The file is included relative to `CARGO_MANIFEST_DIR`, so it can be committed alongside the
+    /// sources. Fails with [`MissingEnv`] outside of cargo.
+    pub fn write_to_manifest_dir(
+        &self,
+        tokens: impl ToTokens,
+        relative: &Path,
+    ) -> Result<TokenStream, std::io::Error> {
+        if relative.is_absolute() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "expander: {} must be relative to the manifest directory",
+                    relative.display()
+                ),
+            ));
+        }
+        let manifest_dir = env::var_os("CARGO_MANIFEST_DIR").ok_or_else(|| MissingEnv {
+            var: "CARGO_MANIFEST_DIR".to_owned(),
+        })?;
+        let dest_dir = Path::new(&manifest_dir).join(relative);
+        if !self.dry {
+            fs::create_dir_all(&dest_dir)?;
+        }
+        let mut expander = self.clone();
+        expander.anchor = Anchor::ManifestDir;
+        expander.write_to(tokens, &dest_dir)
+    }
+
+    /// Return `tokens` inline in place of the failed write, see [`fallback_inline`](Self::fallback_inline).
+    fn fall_back(
+        &self,
+        error: std::io::Error,
+        tokens: TokenStream,
+    ) -> Result<TokenStream, std::io::Error> {
+        if error.kind() == std::io::ErrorKind::InvalidData {
+            return Err(error);
+        }
+        warning(format_args!(
+            "failed to write `{}`, returning the tokens inline: {}",
+            self.filename_base, error
+        ));
+        Ok(tokens)
+    }
+
+    /// Write the file regardless of [`dry`](Self::dry) and return its path alongside the tokens referencing it.
+    fn expand(
+        &self,
+        tokens: TokenStream,
+        dest_dir: &Path,
+    ) -> Result<(std::path::PathBuf, TokenStream), std::io::Error> {
+        let dest_dir = &self.dest_dir(dest_dir, true)?;
+        let key = (self.memoize
+            && !self.merge
+            && !self.check_only
+            && !self.debug_stages
+            && self.transforms.is_empty())
+        .then(|| memo::key(self, &tokens))
+        .transpose()?;
+        if let Some(ref key) = key {
+            if let Some(dest) = memo::lookup(dest_dir, key) {
+                if self.verbose {
+                    info(format_args!(
+                        "identical input already expanded to {}",
+                        dest.display()
+                    ));
+                }
+                track(&dest);
+                self.bookkeep(dest_dir, &dest)?;
+                let tokens = self.emit.reference(&dest, self.anchor(&dest))?;
+                return Ok((dest, self.gate(tokens)));
+            }
+        }
+        let (dest, tokens) = self.expand_unmemoized(tokens, dest_dir)?;
+        if let Some(ref key) = key {
+            memo::insert(dest_dir, key, &dest)?;
+        }
+        Ok((dest, tokens))
+    }
+
+    /// Validate, format and write the `tokens` into the prepared `dest_dir`, see [`expand`](Self::expand).
+    fn expand_unmemoized(
+        &self,
+        tokens: TokenStream,
+        dest_dir: &Path,
+    ) -> Result<(std::path::PathBuf, TokenStream), std::io::Error> {
+        let dest = dest_dir.join(&self.filename_base);
+        #[cfg(any(feature = "validate", test))]
+        if self.validate {
+            if let Err(e) = validate::parse(&tokens) {
+                let content = tokens.to_string();
+                let dump = dump_invalid(content.as_bytes(), &dest)?;
+                return Err(validate::dumped(&dump, &content, &e).into());
+            }
+        }
+        self.check_inner_attrs()?;
+        let tokens = escape::escape(self.attribute(tokens))?;
+        if self.streams() {
+            return self.write_streamed(&tokens, dest_dir);
+        }
+        let input_digest = self.input_digest(|| tokens.to_string());
+        let input = self.hash_input.then(|| tokens.to_string());
+        #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+        let items =
+            if self.span_map && !self.merge && !self.check_only && self.transforms.is_empty() {
+                syn::parse2::<syn::File>(tokens.clone()).ok()
+            } else {
+                None
+            };
+
+        let (bytes, formatter) = self.transformed(tokens, &dest)?;
+        #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+        let span_map = items
+            .map(|items| self.span_map_of(&items, &bytes, input_digest.as_deref()))
+            .transpose()?;
+        self.write_formatted(
+            bytes,
+            dest_dir,
+            input_digest,
+            input,
+            formatter,
+            #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+            span_map,
+        )
+    }
+
+    /// Pass the `tokens` through the pipeline of transforms and format them, unless a transform
+    /// did already.
+    fn transformed(
+        &self,
+        tokens: TokenStream,
+        dest: &Path,
+    ) -> Result<(Vec<u8>, Formatter), std::io::Error> {
+        let mut stage = Stage::Tokens(tokens);
+        let mut formatter = Formatter::Transform;
+        for step in &self.transforms {
+            stage = match (step, stage) {
+                (transform::Step::Transform(transform), stage) => transform.apply(stage)?,
+                (transform::Step::Format, Stage::Tokens(tokens)) => {
+                    let (bytes, built_in) = self.format(tokens, dest)?;
+                    formatter = built_in;
+                    String::from_utf8(bytes)
+                        .map(Stage::Source)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+                }
+                (transform::Step::Format, source) => source,
+            };
+        }
+        match stage {
+            Stage::Tokens(tokens) => self.format(tokens, dest),
+            Stage::Source(source) => Ok((source.into_bytes(), formatter)),
+        }
+    }
+
+    /// Format `tokens` with the built-in formatting, from the [`cache`](Self::cache) if enabled.
+    fn format(
+        &self,
+        tokens: TokenStream,
+        dest: &Path,
+    ) -> Result<(Vec<u8>, Formatter), std::io::Error> {
+        let started = std::time::Instant::now();
+        let formatted = if self.debug_stages {
+            self.format_staged(tokens, dest)?
+        } else {
+            match self.cache.then(|| cache::Cache::locate(&self.out_dir())) {
+                Some(Some(cache)) => {
+                    let token_str = tokens.to_string();
+                    cache.get_or_format(
+                        &token_str,
+                        &self.rustfmt(),
+                        self.format_chain.as_ref(),
+                        || self.format_uncached(tokens, dest),
+                    )?
+                }
+                _ => self.format_uncached(tokens, dest)?,
+            }
+        };
+        self.check_fmt_budget(started, dest, formatted.1);
+        Ok(formatted)
+    }
+
+    /// Format `tokens` with the [`format_chain`](Self::format_chain) if given, with the built-in
+    /// one otherwise.
+    fn format_uncached(
+        &self,
+        tokens: TokenStream,
+        dest: &Path,
+    ) -> Result<(Vec<u8>, Formatter), std::io::Error> {
+        match self.format_chain {
+            Some(ref chain) => format_chained(
+                tokens,
+                dest,
+                chain,
+                &self.rustfmt(),
+                self.verbose,
+                self.strict,
+            ),
+            None => format_tokens(tokens, dest, &self.rustfmt(), self.verbose, self.strict),
+        }
+    }
+
+    /// Warn if formatting `dest` with `formatter` since `started` exceeded the
+    /// [`fmt_budget`](Self::fmt_budget).
+    fn check_fmt_budget(&self, started: std::time::Instant, dest: &Path, formatter: Formatter) {
+        let elapsed = started.elapsed();
+        if let Some(budget) = self.fmt_budget.filter(|budget| elapsed > *budget) {
+            warning(format_args!(
+                "formatting {} with {} took {:?}, exceeding the budget of {:?}, consider \
+                 `fmt_full(.., allow_failure)` or not formatting this expansion",
+                dest.display(),
+                formatter,
+                elapsed,
+                budget
+            ));
+        }
+    }
+
+    /// Format `tokens` and dump the output of every stage, see [`debug_stages`](Self::debug_stages).
+    fn format_staged(
+        &self,
+        tokens: TokenStream,
+        dest: &Path,
+    ) -> Result<(Vec<u8>, Formatter), std::io::Error> {
+        for stage in STAGES {
+            match fs::remove_file(stage_path(dest, stage)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        let dump = |stage: &str, content: &[u8]| {
+            let path = stage_path(dest, stage);
+            if self.verbose {
+                debug(format_args!("dumping stage to {}", path.display()));
+            }
+            fs::write(path, content)
+        };
+        dump(STAGES[0], tokens.to_string().as_bytes())?;
+        let (bytes, formatter) = self.format_uncached(tokens, dest)?;
+        match formatter {
+            Formatter::PrettyPlease => dump(STAGES[1], &bytes)?,
+            Formatter::RustFmt => dump(STAGES[2], &bytes)?,
+            Formatter::Raw | Formatter::Transform => {}
+        }
+        Ok((bytes, formatter))
+    }
+
+    /// Write the already formatted `bytes` and do the bookkeeping.
+    ///
+    /// The filename is derived from `input` if given, from `bytes` otherwise.
+    fn write_formatted(
+        &self,
+        bytes: Vec<u8>,
+        dest_dir: &Path,
+        input_digest: Option<String>,
+        input: Option<String>,
+        formatter: Formatter,
+        #[cfg(any(feature = "spans", all(test, feature = "pretty")))] span_map: Option<
+            spans::SpanMap,
+        >,
+    ) -> Result<(std::path::PathBuf, TokenStream), std::io::Error> {
+        let bytes = self.convert_line_endings(bytes);
+        let dest = dest_dir.join(&self.filename_base);
+        let (dest, tokens) = if self.merge {
+            let header = self.report_size(self.render_header(input_digest.as_deref())?, &bytes);
+            expand_to_merged_file(
+                bytes,
+                dest_dir
+                    .join(self.filename_base.clone() + "-merged.rs")
+                    .as_path(),
+                &merged_module(&self.filename_base),
+                &dest,
+                header,
+                self.check_only,
+                self.verbose,
+            )?
+        } else {
+            let header = self.report_size(self.render_header(input_digest.as_deref())?, &bytes);
+            let dest = if self.dedup {
+                let store = store::store_dir(dest_dir);
+                if !self.check_only {
+                    fs::create_dir_all(&store)?;
+                }
+                store.join(store::BLOB)
+            } else {
+                dest
+            };
+            let dest =
+                self.write_or_check(bytes, &dest, header, input.as_ref().map(String::as_bytes))?;
+            let tokens = self.emit.reference(&dest, self.anchor(&dest))?;
+            (dest, tokens)
+        };
+        #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+        if let Some(span_map) = span_map {
+            span_map.write(&spans::span_map_file(&dest))?;
+        }
+        self.record(dest_dir, &dest, input_digest, formatter)?;
+        Ok((dest, self.gate(tokens)))
+    }
+
+    /// Prefix every item of the referencing `tokens` with the [`cfg`](Self::cfg) predicate.
+    fn gate(&self, tokens: TokenStream) -> TokenStream {
+        match self.cfg {
+            Some(ref predicate) => gate_items(tokens, &predicate.to_token_stream()),
+            None => tokens,
+        }
+    }
+
+    /// Both the `original` tokens gated by `#[cfg(expander_inline)]` and the `reference` to the
+    /// file gated by `#[cfg(not(expander_inline))]`, see [`inline_switch`](Self::inline_switch).
+    fn switch(&self, original: TokenStream, reference: TokenStream) -> TokenStream {
+        let inline = match self.wrap {
+            Some(_) => self.attribute(original),
+            // inner attributes only apply within a module
+            None => self.attribute_outer(original),
+        };
+        let inline = match self.emit {
+            Emit::Module(ref name) => {
+                let name = format_ident!("{}", name);
+                quote! { mod #name { #inline } }
+            }
+            _ => inline,
+        };
+        let cfg = format_ident!("{}", INLINE_CFG);
+        let mut switched = self.gate(gate_items(inline, &cfg.to_token_stream()));
+        switched.extend(gate_items(reference, &quote! { not(#cfg) }));
+        switched
+    }
+
+    #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+    /// Locate the items of `file` in the formatted `bytes`, which are preceded by the comment.
+    fn span_map_of(
+        &self,
+        file: &syn::File,
+        bytes: &[u8],
+        input_digest: Option<&str>,
+    ) -> Result<spans::SpanMap, std::io::Error> {
+        let mut offset = self
+            .render_header(input_digest)?
+            .map_or(0, |header| header.lines().count());
+        if self.stats == Some(Stats::Header) {
+            offset += 1;
+        }
+        Ok(spans::SpanMap::new(
+            file,
+            &String::from_utf8_lossy(bytes),
+            offset,
+        ))
+    }
+
+    #[cfg(feature = "pretty")]
+    /// Create a file with `filename` under `env!("OUT_DIR")` from `file`, see [`write_file_to`](Self::write_file_to).
+    pub fn write_file_to_out_dir(&self, file: &syn::File) -> Result<TokenStream, std::io::Error> {
+        let out = self.out_dir();
+        self.write_file_to(file, out.as_path())
+    }
+
+    #[cfg(feature = "pretty")]
+    /// Create a file with `self.filename` in `dest_dir` from an already parsed `file`.
+    ///
+    /// The file is unparsed with `prettyplease` right away, rather than serializing it to tokens
+    /// and parsing those again. No validation is required either.
+    pub fn write_file_to(
+        &self,
+        file: &syn::File,
+        dest_dir: &Path,
+    ) -> Result<TokenStream, std::io::Error> {
+        let _quiet = diagnostics::Quiet::enter(self.quiet);
+        if self.dry {
+            return Ok(file.to_token_stream());
+        }
+        if self.min_size > 0 {
+            let tokens = file.to_token_stream();
+            if self.inline(&tokens) {
+                return Ok(tokens);
+            }
+        }
+        self.check_inner_attrs()?;
+        let dest_dir = &self.dest_dir(dest_dir, true)?;
+        let attributed;
+        let file =
+            if self.inner_attrs.is_empty() && self.outer_attrs.is_empty() && self.wrap.is_none() {
+                file
+            } else {
+                attributed = self.attribute_file(file.clone());
+                &attributed
+            };
+        let input_digest = self.input_digest(|| file.to_token_stream().to_string());
+        let input = self.hash_input.then(|| file.to_token_stream().to_string());
+        if self.verbose {
+            debug("formatting with prettyplease");
+        }
+        let bytes = prettyplease::unparse(file).into_bytes();
+        #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+        let span_map = (self.span_map && !self.merge && !self.check_only)
+            .then(|| self.span_map_of(file, &bytes, input_digest.as_deref()))
+            .transpose()?;
+        self.write_formatted(
+            bytes,
+            dest_dir,
+            input_digest,
+            input,
+            Formatter::PrettyPlease,
+            #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+            span_map,
+        )
+        .map(|(_, tokens)| tokens)
+        .or_else(|e| {
+            if self.fallback_inline {
+                self.fall_back(e, file.to_token_stream())
+            } else {
+                Err(e)
+            }
+        })
+    }
+
+    /// Write the formatted `tokens` to `path` in the source tree of a crate, for committed codegen.
+    ///
+    /// Unlike [`write_to`](Self::write_to), the file is written exactly to `path`, prefixed with a
+    /// `@generated` "DO NOT EDIT" banner, and only if its content changed. Returns whether the file
+    /// was (or in [`check_only`](Self::check_only) mode would have to be) changed, in which mode an
+    /// out of date file is an error instead. [`dry`](Self::dry) mode only reports the change.
+    pub fn write_to_source(
+        &self,
+        tokens: impl ToTokens,
+        path: &Path,
+    ) -> Result<bool, std::io::Error> {
+        let _quiet = diagnostics::Quiet::enter(self.quiet);
+        let tokens = tokens.into_token_stream();
+        let input_digest = self.input_digest(|| tokens.to_string());
+        let (bytes, _) = self.format_uncached(tokens, path)?;
+        let bytes = self.convert_line_endings(bytes);
+        let mut content = format!(
+            "// @generated by `expander` for `{}`, DO NOT EDIT.\n",
+            self.filename_base
+        );
+        if let Some(header) = self.render_header(input_digest.as_deref())? {
+            content += &header;
+        }
+        let mut content = self.convert_line_endings(content.into_bytes());
+        content.extend_from_slice(&bytes);
+
+        let changed = match fs::read(path) {
+            Ok(existing) => existing != content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e),
+        };
+        if !changed {
+            if self.verbose {
+                info(format_args!("{} is up to date", path.display()));
+            }
+        } else if self.check_only {
+            return Err(out_of_date(path, "differs"));
+        } else if !self.dry {
+            if self.verbose {
+                info(format_args!("writing {}", path.display()));
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, content)?;
+        }
+        Ok(changed)
+    }
+
+    /// Create an artifact with `filename` under `env!("OUT_DIR")`, see [`write_artifact_to`](Self::write_artifact_to).
+    pub fn write_artifact_to_out_dir(
+        &self,
+        content: impl AsRef<[u8]>,
+    ) -> Result<std::path::PathBuf, std::io::Error> {
+        let out = self.out_dir();
+        self.write_artifact_to(content, out.as_path())
+    }
+
+    /// Create a file with `self.filename` in `dest_dir` containing `content` verbatim and return its path.
+    ///
+    /// Shares the digest based naming and locking with all other expansions. In [`dry`](Self::dry)
+    /// mode the path is determined, but nothing is written.
+    pub fn write_artifact_to(
+        &self,
+        content: impl AsRef<[u8]>,
+        dest_dir: &Path,
+    ) -> Result<std::path::PathBuf, std::io::Error> {
+        let _quiet = diagnostics::Quiet::enter(self.quiet);
+        let content = content.as_ref();
+        let dest_dir = &self.dest_dir(dest_dir, !self.dry)?;
+        let dest = dest_dir.join(&self.filename_base);
+        if self.dry {
+            return Ok(self.destination(content, &dest));
+        }
+        let dest = self.write_or_check(content.to_vec(), &dest, None, None)?;
+        let input_digest = self.input_digest(|| content);
+        self.record(dest_dir, &dest, input_digest, Formatter::Raw)?;
+        Ok(dest)
+    }
+
+    /// Digest of the unformatted `input`, only determined if required for the metadata sidecar
+    /// or the header.
+    fn input_digest<T: AsRef<[u8]>>(&self, input: impl FnOnce() -> T) -> Option<String> {
+        #[cfg(any(feature = "metadata", test))]
+        if self.metadata {
+            return Some(digest_hex(input().as_ref()));
+        }
+        self.header
+            .as_ref()
+            .filter(|header| header.wants_input_digest())
+            .map(|_| digest_hex(input().as_ref()))
+    }
+
+    /// Derive the path of the file for `bytes`, `{dest}.{extension}` in [`stable_path`](Self::stable_path) mode.
+    fn destination(&self, bytes: &[u8], dest: &Path) -> std::path::PathBuf {
+        self.destination_of(dest, |digester| digester.update(bytes))
+    }
+
+    /// The destination of the content fed to the digester by `feed`, see [`destination`](Self::destination).
+    fn destination_of(&self, dest: &Path, feed: impl FnOnce(&mut Digester)) -> std::path::PathBuf {
+        if self.stable_path || env::var_os(STABLE_PATHS).is_some() {
+            let mut path = dest.as_os_str().to_owned();
+            path.push(".");
+            path.push(&self.extension);
+            std::path::PathBuf::from(path)
+        } else {
+            let mut digester = self.hasher.digester(&self.salt);
+            feed(&mut digester);
+            let shortened_hex = digester.suffix(self.digest_len.min(self.hasher.max_len()));
+            if self.shard {
+                if let (Some(dir), Some(base)) = (dest.parent(), dest.file_name()) {
+                    let dest = dir.join(&shortened_hex[..2]).join(base);
+                    return with_suffix(&dest, &shortened_hex, &self.extension);
+                }
+            }
+            with_suffix(dest, &shortened_hex, &self.extension)
+        }
+    }
+
+    #[cfg(any(feature = "metadata", test))]
+    /// Seconds since the unix epoch, taken from `SOURCE_DATE_EPOCH` in [`reproducible`](Self::reproducible) mode.
+    fn timestamp(&self) -> u64 {
+        if self.reproducible {
+            return env::var("SOURCE_DATE_EPOCH")
+                .ok()
+                .and_then(|epoch| epoch.parse().ok())
+                .unwrap_or_default();
+        }
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default()
+    }
+
+    /// Write `bytes` to `{dest}-{digest}.{extension}`, or only verify it in [`check_only`](Self::check_only) mode.
+    ///
+    /// The digest is the one of `input` if given, of `bytes` otherwise.
+    fn write_or_check(
+        &self,
+        bytes: Vec<u8>,
+        dest: &Path,
+        comment: Option<String>,
+        input: Option<&[u8]>,
+    ) -> Result<std::path::PathBuf, std::io::Error> {
+        let dest =
+            self.prepare_destination(dest, |digester| digester.update(input.unwrap_or(&bytes)))?;
+        if self.check_only {
+            return check_file(bytes, dest, comment, self.verbose);
+        }
+        // a different digest than the one of the previous expansion
+        let changed = self.verbose && !dest.exists();
+        if self.file_mode.is_none() && !self.read_only {
+            let dest = write_to_file(
+                bytes,
+                dest,
+                comment,
+                self.share_mode,
+                self.sync,
+                self.verbose,
+            )?;
+            if changed {
+                self.summarize_changes(&dest)?;
+            }
+            return Ok(dest);
+        }
+        // a protected file can't be opened for writing
+        match fs::read(&dest) {
+            Ok(existing) if is_written(&existing, comment.as_deref(), &bytes) => return Ok(dest),
+            Ok(_) => remove_protected(&dest)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let dest = write_to_file(
+            bytes,
+            dest,
+            comment,
+            self.share_mode,
+            self.sync,
+            self.verbose,
+        )?;
+        self.protect(&dest)?;
+        if changed {
+            self.summarize_changes(&dest)?;
+        }
+        Ok(dest)
+    }
+
+    /// Print a summary of the differences between the newly written `dest` and the most recently
+    /// modified file previously written for the same `filename_base`, in verbose mode.
+    fn summarize_changes(&self, dest: &Path) -> Result<(), std::io::Error> {
+        let Some(mut dir) = dest.parent() else {
+            return Ok(());
+        };
+        if self.shard {
+            dir = dir.parent().unwrap_or(dir);
+        }
+        let mut previous = None;
+        for path in siblings(dir, dest, &self.filename_base, &self.extension)? {
+            // might have been collected by a parallel invocation already
+            let Ok(modified) = fs::metadata(&path).and_then(|metadata| metadata.modified()) else {
+                continue;
+            };
+            if previous
+                .as_ref()
+                .map_or(true, |(latest, _)| modified > *latest)
+            {
+                previous = Some((modified, path));
+            }
+        }
+        let Some((_, previous)) = previous else {
+            return Ok(());
+        };
+        // binary content is not worth a summary
+        let (Ok(old), Ok(new)) = (fs::read_to_string(&previous), fs::read_to_string(dest)) else {
+            return Ok(());
+        };
+        if let Some(summary) = diff::summary(&old, &new) {
+            info(format_args!(
+                "{} differs from {}, {}",
+                dest.display(),
+                previous.display(),
+                summary
+            ));
+        }
+        Ok(())
+    }
+
+    /// The [`destination_of`](Self::destination_of) the content fed by `feed`, fitted to the
+    /// platform and with its shard directory created.
+    fn prepare_destination(
+        &self,
+        dest: &Path,
+        feed: impl FnOnce(&mut Digester),
+    ) -> Result<std::path::PathBuf, std::io::Error> {
+        let mut dest = self.destination_of(dest, feed);
+        if cfg!(windows) {
+            dest = fit_path(dest, MAX_PATH)?;
+        }
+        if self.shard && !self.check_only {
+            if let Some(shard) = dest.parent() {
+                fs::create_dir_all(shard)?;
+            }
+        }
+        Ok(dest)
+    }
+
+    /// If the unformatted `Display` output of the tokens can be streamed into the file, as nothing
+    /// requires it as a whole: no formatter, no digest of the input and plain writing.
+    fn streams(&self) -> bool {
+        let validates = {
+            #[cfg(any(feature = "validate", test))]
+            {
+                self.validate || self.read_back
+            }
+            #[cfg(not(any(feature = "validate", test)))]
+            false
+        };
+        !cfg!(feature = "pretty")
+            && matches!(self.rustfmt, RustFmt::No)
+            && !validates
+            && !(self.merge
+                || self.check_only
+                || self.cache
+                || self.dedup
+                || self.hash_input
+                || self.reproducible
+                || self.debug_stages
+                || self.read_only)
+            && self.stats.is_none()
+            && self.format_chain.is_none()
+            && self.warn_above_bytes.is_none()
+            && self.line_ending.is_none()
+            && self.file_mode.is_none()
+            && self.transforms.is_empty()
+            && self.input_digest(String::new).is_none()
+    }
+
+    /// Write `tokens` unformatted, streaming their `Display` output into the file instead of
+    /// collecting it into a `String` first, see [`streams`](Self::streams).
+    ///
+    /// The digest is computed in the same pass, writing to a temporary file which is renamed to the
+    /// digest derived name once complete, so partial content is never observed.
+    fn write_streamed(
+        &self,
+        tokens: &TokenStream,
+        dest_dir: &Path,
+    ) -> Result<(std::path::PathBuf, TokenStream), std::io::Error> {
+        let header = self.render_header(None)?;
+        let partial = dest_dir.join(format!(
+            ".{}.{}.partial",
+            self.filename_base,
+            std::process::id()
+        ));
+        let mut digester = self.hasher.digester(&self.salt);
+        let written = (|| {
+            let mut writer = std::io::BufWriter::new(fs::File::create(&partial)?);
+            if let Some(header) = header {
+                writer.write_all(header.as_bytes())?;
+            }
+            let mut tee = Tee {
+                writer: &mut writer,
+                digester: &mut digester,
+            };
+            write!(tee, "{}", tokens)?;
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            if self.sync {
+                file.sync_all()?;
+            }
+            Ok::<_, std::io::Error>(())
+        })();
+        let renamed = written
+            .and_then(|()| {
+                self.prepare_destination(&dest_dir.join(&self.filename_base), |fresh| {
+                    *fresh = digester
+                })
+            })
+            .and_then(|dest| {
+                // a different digest than the one of the previous expansion
+                let changed = self.verbose && !dest.exists();
+                if self.verbose {
+                    info(format_args!("writing {}", dest.display()));
+                }
+                match fs::rename(&partial, &dest) {
+                    // identical content, opened by a different crate on windows
+                    Err(_) if dest.exists() => fs::remove_file(&partial)?,
+                    renamed => renamed?,
+                }
+                if self.sync {
+                    sync_dir(&dest)?;
+                }
+                if changed {
+                    self.summarize_changes(&dest)?;
+                }
+                Ok(dest)
+            });
+        let dest = renamed.map_err(|e| {
+            let _ = fs::remove_file(&partial);
+            e
+        })?;
+        let reference = self.emit.reference(&dest, self.anchor(&dest))?;
+        self.record(dest_dir, &dest, None, Formatter::Raw)?;
+        Ok((dest, self.gate(reference)))
+    }
+
+    /// Apply [`file_mode`](Self::file_mode) and [`read_only`](Self::read_only) to `dest`.
+    fn protect(&self, dest: &Path) -> Result<(), std::io::Error> {
+        #[cfg(unix)]
+        if let Some(mode) = self.file_mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dest, std::fs::Permissions::from_mode(mode))?;
+        }
+        if self.read_only {
+            let mut permissions = fs::metadata(dest)?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(dest, permissions)?;
+        }
+        Ok(())
+    }
+
+    /// Bookkeeping once the file `dest` in `dest_dir` was written.
+    fn record(
+        &self,
+        dest_dir: &Path,
+        dest: &Path,
+        input_digest: Option<String>,
+        formatter: Formatter,
+    ) -> Result<(), std::io::Error> {
+        track(dest);
+        if self.check_only {
+            return Ok(());
+        }
+        for command in &self.post_commands {
+            command.run(dest, self.verbose)?;
+        }
+        #[cfg(any(feature = "validate", test))]
+        if self.read_back && self.emit.is_rust() && !self.merge {
+            if self.verbose {
+                debug(format_args!("reading back {}", dest.display()));
+            }
+            validate::read_back(dest)?;
+        }
+        self.bookkeep(dest_dir, dest)?;
+        #[cfg(any(feature = "metadata", test))]
+        if let Some(input_digest) = input_digest.filter(|_| self.metadata) {
+            let metadata = metadata::Metadata {
+                macro_name: self.filename_base.clone(),
+                crate_name: crate_name(),
+                input_digest,
+                formatter: formatter.to_string(),
+                timestamp: self.timestamp(),
+            };
+            metadata.write(&metadata::metadata_file(dest))?;
+        }
+        #[cfg(not(any(feature = "metadata", test)))]
+        let _ = (input_digest, formatter);
+        for hook in &self.after_write.0 {
+            hook(dest)?;
+        }
+        Ok(())
+    }
+
+    /// Register the file at `dest` in the aggregate file, history, manifest and mirrors, and remove
+    /// the superseded files, for written and [`memoize`](Self::memoize)d expansions alike.
+    fn bookkeep(&self, dest_dir: &Path, dest: &Path) -> Result<(), std::io::Error> {
+        if self.aggregate && self.emit.is_rust() {
+            register_in_aggregate(dest_dir, dest, self.verbose)?;
+        }
+        let stored = self.dedup && !self.merge && dest.starts_with(store::store_dir(dest_dir));
+        if stored {
+            store::reference(
+                &store::store_dir(dest_dir),
+                &self.filename_base,
+                dest,
+                self.verbose,
+            )?;
+        } else if self.history > 0 && !self.merge {
+            update_history(
+                dest_dir,
+                dest,
+                &self.filename_base,
+                self.history,
+                self.diff_to_file,
+                self.verbose,
+            )?;
+        }
+        if !matches!(self.gc, Gc::Off) && !self.merge && !stored {
+            collect_garbage(
+                dest_dir,
+                dest,
+                &self.filename_base,
+                &self.extension,
+                self.gc,
+                self.verbose,
+            )?;
+        }
+        if self.latest && !self.merge {
+            link_latest(
+                dest_dir,
+                dest,
+                &self.filename_base,
+                &self.extension,
+                self.verbose,
+            )?;
+        }
+        #[cfg(any(feature = "metadata", test))]
+        if self.manifest {
+            let manifest = manifest::manifest_file(dest_dir);
+            if self.verbose {
+                debug(format_args!("recording in manifest {}", manifest.display()));
+            }
+            manifest::record(&manifest, dest, crate_name())?;
+        }
+        for dir in &self.mirrors {
+            mirror(dest, dir, self.verbose)?;
+        }
+        Ok(())
+    }
+
+    /// Create a file with `filename` under `env!("OUT_DIR")` containing `content` verbatim.
+    pub fn write_bytes_to_out_dir(
+        &self,
+        content: impl AsRef<[u8]>,
+    ) -> Result<TokenStream, std::io::Error> {
+        let out = self.out_dir();
+        self.write_bytes_to(content, out.as_path())
+    }
+
+    /// Create a file with `self.filename` in `dest_dir` containing `content` verbatim.
+    ///
+    /// No formatting is applied, the comment is only prepended if the file is referenced as Rust
+    /// source. In [`dry`](Self::dry) mode, the content is returned as literal respectively parsed
+    /// as tokens.
+    pub fn write_bytes_to(
+        &self,
+        content: impl AsRef<[u8]>,
+        dest_dir: &Path,
+    ) -> Result<TokenStream, std::io::Error> {
+        let _quiet = diagnostics::Quiet::enter(self.quiet);
+        let content = content.as_ref();
+        if self.dry {
+            return match &self.emit {
+                Emit::Str(binding) => {
+                    let binding = format_ident!("{}", binding);
+                    let content = std::str::from_utf8(content)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                    Ok(quote! {
+                        const #binding: &str = #content;
+                    })
+                }
+                Emit::Bytes(binding) => {
+                    let binding = format_ident!("{}", binding);
+                    let content = proc_macro2::Literal::byte_string(content);
+                    Ok(quote! {
+                        const #binding: &[u8] = #content;
+                    })
+                }
+                Emit::Artifact => Ok(TokenStream::new()),
+                Emit::Include | Emit::Module(_) => String::from_utf8_lossy(content)
+                    .parse::<TokenStream>()
+                    .map_err(|e| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+                    }),
+            };
+        }
+        let dest_dir = &self.dest_dir(dest_dir, true)?;
+        let input_digest = self.input_digest(|| content);
+        let comment = if self.emit.is_rust() {
+            self.report_size(self.render_header(input_digest.as_deref())?, content)
+        } else {
+            None
+        };
+        let dest = self.write_or_check(
+            content.to_vec(),
+            dest_dir.join(&self.filename_base).as_path(),
+            comment,
+            None,
+        )?;
+        self.record(dest_dir, &dest, input_digest, Formatter::Raw)?;
+        Ok(self.gate(self.emit.reference(&dest, self.anchor(&dest))?))
+    }
+}
+
+/// Path of the aggregate file listing all expansions written to `dest_dir` with
+/// [`Expander::aggregate`] enabled.
+///
+/// Mount it with `#[path = "…/expander_generated/mod.rs"] mod generated;` to inspect all
+/// generated code under one module.
+pub fn aggregate_file(dest_dir: &Path) -> std::path::PathBuf {
+    dest_dir.join("expander_generated").join("mod.rs")
+}
+
+/// Name of the profile directory, i.e. `debug` or `release`, of a `…/{profile}/build/{crate}-{hash}/out` dir.
+fn profile_of(out_dir: &Path) -> Option<&str> {
+    let mut ancestors = out_dir.ancestors().skip(2);
+    let build = ancestors.next()?;
+    if build.file_name()? != "build" {
+        return None;
+    }
+    ancestors.next()?.file_name()?.to_str()
+}
+
+/// Number of hex characters of the digest in filenames, unless configured with [`Expander::digest_len`].
+const DEFAULT_DIGEST_LEN: usize = 12;
+/// Bounds of [`Expander::digest_len`].
+const DIGEST_LENS: std::ops::RangeInclusive<usize> = 8..=64;
+
+/// Take the leading `len` nibbles and convert them to hex ascii characters.
+fn make_suffix(digest: &[u8], len: usize) -> String {
+    let mut shortened_hex = String::with_capacity(len);
+    const TABLE: &[u8] = b"0123456789abcdef";
+    for &byte in digest.iter() {
+        shortened_hex.push(TABLE[((byte >> 4) & 0x0F) as usize] as char);
+        shortened_hex.push(TABLE[(byte & 0x0F) as usize] as char);
+    }
+    shortened_hex.truncate(len);
+    shortened_hex
+}
+
+/// If `digest` could be the digest in the name of a generated file.
+fn is_digest(digest: &str) -> bool {
+    DIGEST_LENS.contains(&digest.len()) && digest.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Name of the crate being compiled, if invoked as part of a cargo build.
+fn crate_name() -> Option<String> {
+    env::var("CARGO_CRATE_NAME")
+        .or_else(|_| env::var("CARGO_PKG_NAME"))
+        .ok()
+}
+
+/// Full hex representation of the digest of `bytes`.
+fn digest_hex(bytes: &[u8]) -> String {
+    let hasher = Hasher::default();
+    hasher.suffix(&[], bytes, hasher.max_len())
+}
+
+/// Derive the path `{dest}-{digest}.{extension}` for `bytes`, with `digest_len` hex characters of the digest.
+fn destination(
+    bytes: &[u8],
+    dest: &Path,
+    extension: &str,
+    hasher: Hasher,
+    digest_len: usize,
+) -> std::path::PathBuf {
+    // we need to disambiguate for transitive dependencies, that might create different output to not override one another
+    let shortened_hex = hasher.suffix(&[], bytes, digest_len.min(hasher.max_len()));
+    with_suffix(dest, &shortened_hex, extension)
+}
+
+/// The path `{dest}-{shortened_hex}.{extension}`.
+fn with_suffix(dest: &Path, shortened_hex: &str, extension: &str) -> std::path::PathBuf {
+    std::path::PathBuf::from(dest.display().to_string() + "-" + shortened_hex + "." + extension)
+}
+
+/// Longest path supported on windows, unless long paths are enabled system wide.
+const MAX_PATH: usize = 260;
+
+/// Shorten the filename base of `dest` for the path to stay below `limit` characters, keeping the
+/// digest and leaving room for sidecar files.
+fn fit_path(dest: std::path::PathBuf, limit: usize) -> Result<std::path::PathBuf, std::io::Error> {
+    // terminating nul and the longest sidecar suffix
+    let limit = limit.saturating_sub(1 + ".spans.json".len());
+    let len = dest.as_os_str().to_string_lossy().chars().count();
+    if len <= limit || dest.as_os_str().to_string_lossy().starts_with(r"\\?\") {
+        return Ok(dest);
+    }
+    let too_long = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "expander: path of the generated file {} exceeds {} characters, use a shorter target directory or enable long paths",
+                dest.display(),
+                limit
+            ),
+        )
+    };
+    let (Some(dir), Some(name)) = (
+        dest.parent(),
+        dest.file_name().and_then(|name| name.to_str()),
+    ) else {
+        return Err(too_long());
+    };
+    let Some((base, suffix)) = name.rsplit_once('-') else {
+        return Err(too_long());
+    };
+    let excess = len - limit;
+    let base_len = base.chars().count();
+    if excess >= base_len {
+        return Err(too_long());
+    }
+    let base = base.chars().take(base_len - excess).collect::<String>();
+    let shortened = dir.join(format!("{}-{}", base, suffix));
+    warning(format_args!(
+        "path of the generated file {} exceeds {} characters, shortened to {}",
+        dest.display(),
+        limit,
+        shortened.display()
+    ));
+    Ok(shortened)
+}
+
+/// Formatting stages dumped by [`Expander::debug_stages`], in order.
+const STAGES: [&str; 3] = ["0.raw", "1.pretty", "2.rustfmt"];
+
+/// Path the `stage` of formatting the file `dest` is dumped to.
+fn stage_path(dest: &Path, stage: &str) -> std::path::PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.rs", stage));
+    dest.with_file_name(name)
+}
+
+/// Write content which failed to parse to `{dest}-{digest}.invalid.rs` for inspection and return its path.
+#[cfg(any(feature = "pretty", feature = "validate", test))]
+fn dump_invalid(content: &[u8], dest: &Path) -> Result<std::path::PathBuf, std::io::Error> {
+    let dump = destination(
+        content,
+        dest,
+        "invalid.rs",
+        Hasher::default(),
+        DEFAULT_DIGEST_LEN,
+    );
+    fs::write(&dump, content)?;
+    Ok(dump)
+}
+
+/// Write the formatted content to `dest` and return the path of the written file.
+fn write_to_file(
+    bytes: Vec<u8>,
+    dest: std::path::PathBuf,
+    comment: impl Into<Option<String>>,
+    share_mode: ShareMode,
+    sync: bool,
+    verbose: bool,
+) -> Result<std::path::PathBuf, std::io::Error> {
+    let comment = comment.into();
+    write_with(dest, share_mode, sync, verbose, |writer| {
+        if let Some(comment) = comment {
+            writer.write_all(comment.as_bytes())?;
+        }
+        writer.write_all(&bytes)
+    })
+}
+
+/// Write the content produced by `content` to `dest`, unless a different crate is already writing
+/// it, see [`write_to_file`].
+fn write_with(
+    dest: std::path::PathBuf,
+    share_mode: ShareMode,
+    sync: bool,
+    verbose: bool,
+    content: impl FnOnce(&mut dyn Write) -> Result<(), std::io::Error>,
+) -> Result<std::path::PathBuf, std::io::Error> {
+    let mut f = open_shared(&dest, share_mode, verbose)?;
+
+    let Ok(mut f) = try_lock(&mut f, 64) else {
+        // the digest of the file will not match if the content to be written differed, hence any existing lock
+        // means we are already writing the same content to the file
+        if verbose {
+            note(format_args!(
+                "already in progress of writing identical content to {} by a different crate",
+                dest.display()
+            ));
+        }
+        // now actually wait until the write is complete
+        let _lock = lock(&mut f, 64).expect("File Lock never fails us. qed");
+
+        if verbose {
+            debug("lock was released, referencing");
+        }
+
+        return Ok(dest);
+    };
+
+    if verbose {
+        info(format_args!("writing {}", dest.display()));
+    }
+
+    // Write the content while holding the guard
+    let mut writer = std::io::BufWriter::new(&mut *f);
+    content(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+
+    if sync {
+        f.sync_all()?;
+        sync_dir(&dest)?;
+    }
+
+    Ok(dest)
+}
+
+/// File locked by [`lock`], unlocked when dropped.
+#[cfg(any(feature = "locking", test))]
+type Locked<'a> = file_guard::FileGuard<&'a mut std::fs::File>;
+#[cfg(not(any(feature = "locking", test)))]
+type Locked<'a> = Unlocked<'a>;
+
+/// Stand-in for the guard of a locked file without feature `locking`.
+#[cfg(not(any(feature = "locking", test)))]
+struct Unlocked<'a>(&'a mut std::fs::File);
+
+#[cfg(not(any(feature = "locking", test)))]
+impl<'a> std::ops::Deref for Unlocked<'a> {
+    type Target = &'a mut std::fs::File;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(not(any(feature = "locking", test)))]
+impl std::ops::DerefMut for Unlocked<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Exclusively lock the leading `len` bytes of `file`, waiting for other processes to unlock it.
+///
+/// Without feature `locking` nothing is locked, concurrent writers of the same file may interleave.
+fn lock(file: &mut fs::File, len: usize) -> std::io::Result<Locked<'_>> {
+    #[cfg(any(feature = "locking", test))]
+    {
+        file_guard::lock(std_file(file), file_guard::Lock::Exclusive, 0, len)
+    }
+    #[cfg(not(any(feature = "locking", test)))]
+    {
+        let _ = len;
+        Ok(Unlocked(std_file(file)))
+    }
+}
+
+/// Exclusively lock the leading `len` bytes of `file`, failing if another process holds a lock.
+fn try_lock(file: &mut fs::File, len: usize) -> std::io::Result<Locked<'_>> {
+    #[cfg(any(feature = "locking", test))]
+    {
+        file_guard::try_lock(std_file(file), file_guard::Lock::Exclusive, 0, len)
+    }
+    #[cfg(not(any(feature = "locking", test)))]
+    {
+        let _ = len;
+        Ok(Unlocked(std_file(file)))
+    }
+}
+
+/// The `std` file underlying `file`.
+fn std_file(file: &mut fs::File) -> &mut std::fs::File {
+    #[cfg(any(feature = "fs-err", test))]
+    {
+        file.file_mut()
+    }
+    #[cfg(not(any(feature = "fs-err", test)))]
+    {
+        file
+    }
+}
+
+/// Writes to `writer`, feeding `digester` with everything written along the way.
+struct Tee<'a, W> {
+    writer: W,
+    digester: &'a mut Digester,
+}
+
+impl<W: Write> Write for Tee<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.digester.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Copy `dest` into `dir`, replacing an earlier copy at once.
+fn mirror(dest: &Path, dir: &Path, verbose: bool) -> std::io::Result<()> {
+    let name = dest
+        .file_name()
+        .expect("Written files have a file name. qed");
+    let copy = dir.join(name);
+    if verbose {
+        info(format_args!("copying to {}", copy.display()));
+    }
+    fs::create_dir_all(dir)?;
+    let partial = dir.join(format!(
+        ".{}.{}.partial",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::copy(dest, &partial)?;
+    fs::rename(&partial, &copy).map_err(|e| {
+        let _ = fs::remove_file(&partial);
+        e
+    })
+}
+
+/// Flush the directory entry of `dest` to disk, a no-op where directories can't be opened.
+fn sync_dir(dest: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if let Some(dir) = dest.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = dest;
+    Ok(())
+}
+
+/// Open `dest` for writing, sharing it according to `share_mode`.
+#[cfg(windows)]
+fn open_shared(dest: &Path, share_mode: ShareMode, verbose: bool) -> std::io::Result<fs::File> {
+    #[cfg(any(feature = "fs-err", test))]
+    use fs_err::os::windows::fs::OpenOptionsExt;
+    #[cfg(not(any(feature = "fs-err", test)))]
+    use std::os::windows::fs::OpenOptionsExt;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+
+    let mut notified = false;
+    loop {
+        let opened = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .share_mode(share_mode.flags())
+            .open(dest);
+        match opened {
+            // exclusively opened by a different crate writing the identical content
+            Err(e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => {
+                if verbose && !notified {
+                    note(format_args!(
+                        "waiting for a different crate to finish writing {}",
+                        dest.display()
+                    ));
+                    notified = true;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            opened => return opened,
+        }
+    }
+}
+
+/// Open `dest` for writing, sharing it according to `share_mode`.
+#[cfg(not(windows))]
+fn open_shared(dest: &Path, _share_mode: ShareMode, _verbose: bool) -> std::io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dest)
+}
+
+/// Point `{dir}/{filename_base}-latest.{extension}` at `dest`, as hard link or copy.
+fn link_latest(
+    dir: &Path,
+    dest: &Path,
+    filename_base: &str,
+    extension: &str,
+    verbose: bool,
+) -> Result<(), std::io::Error> {
+    let latest = dir.join(format!("{}-latest.{}", filename_base, extension));
+    if verbose {
+        debug(format_args!("linking {}", latest.display()));
+    }
+    match remove_protected(&latest) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    match fs::hard_link(dest, &latest) {
+        Ok(()) => Ok(()),
+        // updated by a parallel invocation
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        // i.e. a filesystem without hard links
+        Err(_) => fs::copy(dest, &latest).map(|_| ()),
+    }
+}
+
+/// Remove the outdated file `dest`, which might be read-only.
+fn remove_protected(dest: &Path) -> Result<(), std::io::Error> {
+    // windows refuses to remove read-only files, unix only cares about the directory
+    #[cfg(windows)]
+    {
+        let mut permissions = fs::metadata(dest)?.permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(dest, permissions)?;
+    }
+    fs::remove_file(dest)
+}
+
+/// Verify `dest` exists and contains exactly the comment followed by `bytes`.
+fn check_file(
+    bytes: Vec<u8>,
+    dest: std::path::PathBuf,
+    comment: Option<String>,
+    verbose: bool,
+) -> Result<std::path::PathBuf, std::io::Error> {
+    if verbose {
+        info(format_args!("checking {}", dest.display()));
+    }
+    match fs::read(&dest) {
+        Ok(existing) if is_written(&existing, comment.as_deref(), &bytes) => Ok(dest),
+        Ok(_) => Err(out_of_date(&dest, "differs")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(out_of_date(&dest, "is missing")),
+        Err(e) => Err(e),
+    }
+}
+
+/// If the `existing` content of a file is the `comment` followed by `bytes`, without joining them.
+fn is_written(existing: &[u8], comment: Option<&str>, bytes: &[u8]) -> bool {
+    existing.strip_prefix(comment.unwrap_or_default().as_bytes()) == Some(bytes)
+}
+
+/// Error for a generated file at `path` that is not up to date.
+fn out_of_date(path: &Path, reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "expander: generated file {} {}, regenerate it without `check_only`",
+            path.display(),
+            reason
+        ),
+    )
+}
+
+#[cfg(feature = "pretty")]
+/// The outer attributes of `item`, if it has any.
+fn item_attrs_mut(item: &mut syn::Item) -> Option<&mut Vec<syn::Attribute>> {
+    use syn::Item;
+
+    Some(match item {
+        Item::Const(item) => &mut item.attrs,
+        Item::Enum(item) => &mut item.attrs,
+        Item::ExternCrate(item) => &mut item.attrs,
+        Item::Fn(item) => &mut item.attrs,
+        Item::ForeignMod(item) => &mut item.attrs,
+        Item::Impl(item) => &mut item.attrs,
+        Item::Macro(item) => &mut item.attrs,
+        Item::Mod(item) => &mut item.attrs,
+        Item::Static(item) => &mut item.attrs,
+        Item::Struct(item) => &mut item.attrs,
+        Item::Trait(item) => &mut item.attrs,
+        Item::TraitAlias(item) => &mut item.attrs,
+        Item::Type(item) => &mut item.attrs,
+        Item::Union(item) => &mut item.attrs,
+        Item::Use(item) => &mut item.attrs,
+        _ => return None,
+    })
+}
+
+/// Tokens to include the file at `dest`.
+fn include_file(dest: &Path) -> std::io::Result<TokenStream> {
+    let dest = path_literal(dest)?;
+    Ok(quote! {
+        include!( #dest );
+    })
+}
+
+/// The value of the string `literal`, as serialized by [`path_literal`], `None` if it is none.
+fn string_value(literal: &str) -> Option<String> {
+    let mut chars = literal.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut value = String::with_capacity(literal.len());
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        value.push(match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            'u' => {
+                let code = chars.by_ref().skip(1).take_while(|&c| c != '}');
+                char::from_u32(u32::from_str_radix(&code.collect::<String>(), 16).ok()?)?
+            }
+            c => c,
+        });
+    }
+    Some(value)
+}
+
+/// `concat!(env!("{var}"), "/…")` resolving to `dest` for the first of `vars` that is set, the
+/// literal path of `dest` if there is no relative path from it, i.e. on a different drive.
+fn relocatable_path(dest: &Path, vars: &[&str]) -> std::io::Result<TokenStream> {
+    let regular = dest.to_str().and_then(strip_verbatim);
+    let relative = vars.iter().find_map(|&var| {
+        let base = env::var_os(var)?;
+        let base = Path::new(&base);
+        let relative = match regular {
+            Some(ref regular) => relative_path(base, Path::new(regular)),
+            None => relative_path(base, dest),
+        };
+        Some((var, relative))
+    });
+    Ok(match relative {
+        Some((var, Some(relative))) => {
+            let relative = format!("/{}", relative);
+            quote! { concat!(env!(#var), #relative) }
+        }
+        _ => path_literal(dest)?.into_token_stream(),
+    })
+}
+
+/// `path` as escaped string literal, with forward slashes on windows, which `rustc` accepts as well.
+///
+/// Fails with [`UnrepresentablePath`] rather than including a lossy conversion which can't resolve.
+fn path_literal(path: &Path) -> std::io::Result<proc_macro2::Literal> {
+    let Some(utf8) = path.to_str() else {
+        return Err(UnrepresentablePath {
+            path: path.to_owned(),
+        }
+        .into());
+    };
+    Ok(if cfg!(windows) {
+        proc_macro2::Literal::string(&forward_slashes(utf8))
+    } else {
+        proc_macro2::Literal::string(utf8)
+    })
+}
+
+/// Replace the backslashes of a windows `path`.
+///
+/// Extended-length `\\?\C:\…` and `\\?\UNC\server\…` paths, i.e. of a canonicalized `OUT_DIR`,
+/// are converted to their regular form first. If they exceed [`MAX_PATH`] they are preserved,
+/// they must not contain forward slashes.
+fn forward_slashes(path: &str) -> String {
+    let Some(regular) = strip_verbatim(path) else {
+        return path.replace('\\', "/");
+    };
+    if regular.chars().count() >= MAX_PATH {
+        return path.to_owned();
     }
+    regular.replace('\\', "/")
+}
 
-    #[cfg(any(feature = "syndicate", test))]
-    /// Create a file with `filename` under `env!("OUT_DIR")` if it's not an `Err(_)`.
-    pub fn maybe_write_to_out_dir(
-        self,
-        tokens: impl Into<Result<TokenStream, syn::Error>>,
-    ) -> Result<syn::Result<TokenStream>, std::io::Error> {
-        self.maybe_write_to(tokens, std::path::PathBuf::from(env!("OUT_DIR")).as_path())
+/// The regular form of an extended-length windows `path` for a drive or UNC share, if it is one.
+fn strip_verbatim(path: &str) -> Option<String> {
+    let rest = path.strip_prefix(r"\\?\")?;
+    if let Some(share) = rest.strip_prefix(r"UNC\") {
+        return Some(format!(r"\\{}", share));
     }
+    let mut chars = rest.chars();
+    let drive = matches!(
+        (chars.next(), chars.next(), chars.next()),
+        (Some(letter), Some(':'), Some('\\') | None) if letter.is_ascii_alphabetic()
+    );
+    drive.then(|| rest.to_owned())
+}
 
-    /// Create a file with `filename` under `env!("OUT_DIR")`.
-    pub fn write_to_out_dir(self, tokens: TokenStream) -> Result<TokenStream, std::io::Error> {
-        let out = std::path::PathBuf::from(env!("OUT_DIR"));
-        self.write_to(tokens, out.as_path())
+/// Path from `base` to `dest` joined with forward slashes, if both are absolute and share a root.
+fn relative_path(base: &Path, dest: &Path) -> Option<String> {
+    use std::path::Component;
+
+    if !base.is_absolute() || !dest.is_absolute() {
+        return None;
+    }
+    let mut base = base.components().peekable();
+    let mut dest = dest.components().peekable();
+    if base.peek() != dest.peek() {
+        return None;
     }
+    while base.peek().is_some() && base.peek() == dest.peek() {
+        base.next();
+        dest.next();
+    }
+    let mut relative = Vec::new();
+    for component in base {
+        match component {
+            Component::Normal(_) => relative.push("..".to_owned()),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    for component in dest {
+        relative.push(component.as_os_str().to_str()?.to_owned());
+    }
+    Some(relative.join("/"))
+}
 
-    #[cfg(any(feature = "syndicate", test))]
-    /// Create a file with `filename` at `dest` if it's not an `Err(_)`.
-    pub fn maybe_write_to(
-        self,
-        maybe_tokens: impl Into<Result<TokenStream, syn::Error>>,
-        dest_dir: &Path,
-    ) -> Result<syn::Result<TokenStream>, std::io::Error> {
-        match maybe_tokens.into() {
-            Ok(tokens) => Ok(Ok(self.write_to(tokens, dest_dir)?)),
-            err => Ok(err),
+/// Prefix every item of `tokens` with `#[cfg(predicate)]`.
+///
+/// Items end with `;` or a `{ … }` block, apart from `const`, `static`, `type` and `use` items,
+/// which always end with `;`, as their value might contain blocks.
+fn gate_items(tokens: TokenStream, predicate: &TokenStream) -> TokenStream {
+    let mut gated = TokenStream::new();
+    let mut item = TokenStream::new();
+    // decided by the first keyword after attributes and visibility
+    let mut semicolon_only = None;
+    let mut after_const = false;
+    for tt in tokens {
+        let end = match tt {
+            TokenTree::Punct(ref punct) => punct.as_char() == ';',
+            TokenTree::Group(ref group) => {
+                group.delimiter() == Delimiter::Brace && semicolon_only != Some(true)
+            }
+            TokenTree::Ident(ref ident) if semicolon_only.is_none() => {
+                let ident = ident.to_string();
+                match ident.as_str() {
+                    "pub" => {}
+                    // `const fn`
+                    "fn" | "unsafe" | "async" | "extern" if after_const => {
+                        semicolon_only = Some(false)
+                    }
+                    _ if after_const => semicolon_only = Some(true),
+                    "const" => after_const = true,
+                    "static" | "type" | "use" => semicolon_only = Some(true),
+                    _ => semicolon_only = Some(false),
+                }
+                false
+            }
+            _ => false,
+        };
+        item.extend(Some(tt));
+        if end {
+            gated.extend(quote! { #[cfg(#predicate)] #item });
+            item = TokenStream::new();
+            semicolon_only = None;
+            after_const = false;
         }
     }
+    if !item.is_empty() {
+        gated.extend(quote! { #[cfg(#predicate)] #item });
+    }
+    gated
+}
 
-    /// Create a file with `self.filename` in  `dest_dir`.
-    pub fn write_to(
-        self,
-        tokens: TokenStream,
-        dest_dir: &Path,
-    ) -> Result<TokenStream, std::io::Error> {
-        if self.dry {
-            Ok(tokens)
-        } else {
-            expand_to_file(
-                tokens,
-                dest_dir.join(self.filename_base).as_path(),
-                dest_dir,
-                self.rustfmt,
-                self.comment,
-                self.verbose,
-            )
+/// Replace all `\n` in `bytes` with `\r\n`, which must not contain any yet.
+fn to_crlf(bytes: &[u8]) -> Vec<u8> {
+    let mut crlf = Vec::with_capacity(bytes.len() + bytes.len() / 32);
+    for &byte in bytes {
+        if byte == b'\n' {
+            crlf.push(b'\r');
         }
+        crlf.push(byte);
     }
+    crlf
 }
 
-/// Take the leading 6 bytes and convert them to 12 hex ascii characters.
-fn make_suffix(digest: &[u8; 32]) -> String {
-    let mut shortened_hex = String::with_capacity(12);
-    const TABLE: &[u8] = b"0123456789abcdef";
-    for &byte in digest.iter().take(6) {
-        shortened_hex.push(TABLE[((byte >> 4) & 0x0F) as usize] as char);
-        shortened_hex.push(TABLE[((byte >> 0) & 0x0F) as usize] as char);
+/// Replace all `\r\n` in `bytes` with `\n`, in place.
+fn normalize_line_endings(mut bytes: Vec<u8>) -> Vec<u8> {
+    let Some(first) = bytes.iter().position(|&byte| byte == b'\r') else {
+        return bytes;
+    };
+    let mut len = first;
+    for read in first..bytes.len() {
+        if bytes[read] == b'\r' && bytes.get(read + 1) == Some(&b'\n') {
+            continue;
+        }
+        bytes[len] = bytes[read];
+        len += 1;
     }
-    shortened_hex
+    bytes.truncate(len);
+    bytes
 }
 
-/// Expand a proc-macro to file.
+/// Serialize the `tokens` and format them according to the enabled features and `rustfmt` setting.
 ///
-/// The current working directory `cwd` is only used for the `rustfmt` invocation
-/// and hence influences where the config files would be pulled in from.
-fn expand_to_file(
+/// `dest` is only used for diagnostics.
+fn format_tokens(
     tokens: TokenStream,
     dest: &Path,
-    _cwd: &Path,
-    rustfmt: RustFmt,
-    comment: impl Into<Option<String>>,
+    rustfmt: &RustFmt,
     verbose: bool,
-) -> Result<TokenStream, std::io::Error> {
-    let token_str = tokens.to_string();
+    strict: bool,
+) -> Result<(Vec<u8>, Formatter), std::io::Error> {
+    match format_in_process(tokens, dest, verbose, strict)? {
+        Ok(formatted) => Ok((formatted, Formatter::PrettyPlease)),
+        Err((token_str, message)) => {
+            maybe_run_rustfmt_on_content(rustfmt, verbose, message, token_str)
+        }
+    }
+}
 
-    // Determine the content to write
-    let bytes = {
-        #[cfg(feature = "pretty")]
-        {
-            // Try prettyplease first if the feature is enabled
-            match syn::parse_file(&token_str) {
-                Ok(sf) => {
-                    if verbose {
-                        eprintln!("expander: formatting with prettyplease");
-                    }
-                    prettyplease::unparse(&sf).into_bytes()
+/// Format `tokens` with the first formatter of the `chain` to succeed.
+///
+/// `dest` is only used for diagnostics.
+fn format_chained(
+    tokens: TokenStream,
+    dest: &Path,
+    chain: &FormatChain,
+    rustfmt: &RustFmt,
+    verbose: bool,
+    strict: bool,
+) -> Result<(Vec<u8>, Formatter), std::io::Error> {
+    #[cfg(not(feature = "pretty"))]
+    let _ = strict;
+    let mut token_str = None;
+    let mut formatters = chain
+        .formatters
+        .iter()
+        .filter(|formatting| cfg!(feature = "pretty") || **formatting != Formatting::PrettyPlease)
+        .peekable();
+    while let Some(formatting) = formatters.next() {
+        let formatted = match formatting {
+            #[cfg(feature = "pretty")]
+            Formatting::PrettyPlease => match unparse_items(tokens.clone(), verbose) {
+                Ok(formatted) => Ok((formatted.into_bytes(), Formatter::PrettyPlease)),
+                Err(e) if strict => {
+                    let dump = dump_invalid(tokens.to_string().as_bytes(), dest)?;
+                    return Err(unparsable(dest, &dump, &e));
                 }
-                Err(e) => {
-                    eprintln!(
-                        "expander: prettyplease failed for {}: {:?}",
-                        dest.display(),
-                        e
-                    );
-                    // Fall back to rustfmt if available, regardless of rustfmt setting
-                    maybe_run_rustfmt_on_content(
-                        &rustfmt,
-                        verbose,
-                        "expander: falling back to rustfmt",
-                        token_str,
-                    )?
+                Err(e) => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    e.to_string(),
+                )),
+            },
+            #[cfg(not(feature = "pretty"))]
+            Formatting::PrettyPlease => unreachable!("Filtered without feature `pretty`. qed"),
+            Formatting::RustFmt => {
+                let token_str = token_str.get_or_insert_with(|| tokens.to_string());
+                let command = match *rustfmt {
+                    RustFmt::Yes {
+                        channel,
+                        edition,
+                        ref unstable,
+                        ..
+                    } => rustfmt_command(channel, edition, unstable),
+                    RustFmt::No => rustfmt_command(Channel::Default, Edition::Unspecified, &[]),
+                };
+                run_rustfmt_on_content(token_str.as_bytes(), command, false).map(|formatted| {
+                    let formatted = formatted.expect("Failures are not allowed. qed");
+                    (formatted, Formatter::RustFmt)
+                })
+            }
+            Formatting::Raw => {
+                let token_str = token_str.take().unwrap_or_else(|| tokens.to_string());
+                Ok((token_str.into_bytes(), Formatter::Raw))
+            }
+        };
+        let e = match formatted {
+            Ok(formatted) => return Ok(formatted),
+            Err(e) => e,
+        };
+        let Some(next) = formatters.peek() else {
+            return Err(e);
+        };
+        let message = format_args!(
+            "{} failed for {}, falling back to {}: {}",
+            formatting,
+            dest.display(),
+            next,
+            e
+        );
+        if chain.report {
+            warning(message);
+        } else if verbose {
+            debug(message);
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "expander: no formatter in the format chain",
+    ))
+}
+
+/// Format `tokens` with prettyplease if the feature is enabled.
+///
+/// Otherwise, or if that fails, the serialized tokens are returned as error, alongside the message
+/// announcing to format them with `rustfmt`.
+fn format_in_process(
+    tokens: TokenStream,
+    dest: &Path,
+    verbose: bool,
+    strict: bool,
+) -> Result<Result<Vec<u8>, (String, &'static str)>, std::io::Error> {
+    #[cfg(feature = "pretty")]
+    {
+        // Try prettyplease first if the feature is enabled, cloning a `TokenStream` is cheap
+        match unparse_items(tokens.clone(), verbose) {
+            Ok(formatted) => Ok(Ok(formatted.into_bytes())),
+            Err(e) => {
+                let token_str = tokens.to_string();
+                drop(tokens);
+                let dump = dump_invalid(token_str.as_bytes(), dest)?;
+                if strict {
+                    return Err(unparsable(dest, &dump, &e));
                 }
+                warning(format_args!(
+                    "prettyplease failed for {}, content dumped to {}: {:?}",
+                    dest.display(),
+                    dump.display(),
+                    e
+                ));
+                // Fall back to rustfmt if available, regardless of rustfmt setting
+                Ok(Err((token_str, "falling back to rustfmt")))
             }
         }
+    }
 
-        #[cfg(not(feature = "pretty"))]
-        {
-            // Without pretty feature, use rustfmt if requested
-            maybe_run_rustfmt_on_content(
-                &rustfmt,
-                verbose,
-                "expander: formatting with rustfmt",
-                token_str,
-            )?
+    #[cfg(not(feature = "pretty"))]
+    {
+        let _ = (dest, verbose, strict);
+        // Without pretty feature, use rustfmt if requested
+        Ok(Err((tokens.to_string(), "formatting with rustfmt")))
+    }
+}
+
+/// The error of prettyplease failing to parse the generated code for `dest`, dumped to `dump`, see
+/// [`Expander::strict`].
+#[cfg(feature = "pretty")]
+fn unparsable(dest: &Path, dump: &Path, e: &syn::Error) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "expander: generated code for {} does not parse, dumped to {}: {}",
+            dest.display(),
+            dump.display(),
+            e
+        ),
+    )
+}
+
+/// Format `tokens` with prettyplease one item at a time.
+///
+/// The syntax tree takes up about eighty times the size of the source, so only the tree of a single
+/// item is kept alive rather than the one of the whole file. Items are printed independently of
+/// each other, hence the result is identical to unparsing the whole [`syn::File`].
+#[cfg(feature = "pretty")]
+fn unparse_items(tokens: TokenStream, verbose: bool) -> syn::Result<String> {
+    use syn::parse::{ParseStream, Parser};
+
+    let unparse = |input: ParseStream| {
+        if verbose {
+            debug("formatting with prettyplease");
+        }
+        let mut formatted = String::new();
+        let mut attrs = input.call(syn::Attribute::parse_inner)?;
+        loop {
+            let items = if input.is_empty() {
+                Vec::new()
+            } else {
+                vec![input.parse()?]
+            };
+            formatted.push_str(&prettyplease::unparse(&syn::File {
+                shebang: None,
+                attrs: std::mem::take(&mut attrs),
+                items,
+            }));
+            if input.is_empty() {
+                return Ok(formatted);
+            }
         }
     };
+    unparse.parse2(tokens)
+}
 
-    // we need to disambiguate for transitive dependencies, that might create different output to not override one another
-    let hash = <blake2::Blake2s256 as blake2::Digest>::digest(&bytes);
-    let shortened_hex = make_suffix(hash.as_ref());
+/// Write the formatted content of a proc-macro as region of the shared file `merged`.
+///
+/// The region is only appended, if no region with identical content exists yet. If the content can not
+/// be embedded into a region, it is written to a dedicated file derived from `dest` instead.
+///
+/// Returns the path of the written file and the tokens to reference the region within `module`,
+/// which includes `merged`, see [`Expander::include_merged`].
+fn expand_to_merged_file(
+    bytes: Vec<u8>,
+    merged: &Path,
+    module: &proc_macro2::Ident,
+    dest: &Path,
+    comment: Option<String>,
+    check_only: bool,
+    verbose: bool,
+) -> Result<(std::path::PathBuf, TokenStream), std::io::Error> {
+    if bytes.contains(&b'$') {
+        if verbose {
+            info(format_args!(
+                "content contains `$`, not merging into {}",
+                merged.display()
+            ));
+        }
+        let dest = destination(&bytes, dest, "rs", Hasher::default(), DEFAULT_DIGEST_LEN);
+        let dest = if check_only {
+            check_file(bytes, dest, comment, verbose)?
+        } else {
+            write_to_file(bytes, dest, comment, ShareMode::ReadWrite, false, verbose)?
+        };
+        let tokens = include_file(&dest)?;
+        return Ok((dest, tokens));
+    }
+
+    let shortened_hex = Hasher::default().suffix(&[], &bytes, DEFAULT_DIGEST_LEN);
+    let region_begin = format!("// expander-region-begin: {}\n", shortened_hex);
 
-    let dest =
-        std::path::PathBuf::from(dest.display().to_string() + "-" + shortened_hex.as_str() + ".rs");
+    if check_only {
+        check_region(merged, &region_begin, &shortened_hex)?;
+    } else {
+        append_region(
+            merged,
+            &region_begin,
+            &shortened_hex,
+            &bytes,
+            comment,
+            verbose,
+        )?;
+    }
+
+    let region = format_ident!("__expander_region_{}", shortened_hex);
+    Ok((
+        merged.to_owned(),
+        quote! {
+            crate::#module::#region !{}
+        },
+    ))
+}
+
+/// The module including the shared file of the merged expansions of `filename_base`.
+fn merged_module(filename_base: &str) -> proc_macro2::Ident {
+    let name = filename_base
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    format_ident!("__expander_merged_{}", name)
+}
+
+/// Verify the shared file `merged` contains the region starting with `region_begin`.
+fn check_region(merged: &Path, region_begin: &str, hex: &str) -> Result<(), std::io::Error> {
+    let existing = match fs::read_to_string(merged) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        existing => existing?,
+    };
+    if existing.contains(region_begin) {
+        Ok(())
+    } else {
+        Err(out_of_date(merged, &format!("lacks region {}", hex)))
+    }
+}
 
+/// Append the region identified by `hex` to the shared file `merged`, unless already present.
+fn append_region(
+    merged: &Path,
+    region_begin: &str,
+    shortened_hex: &str,
+    bytes: &[u8],
+    comment: Option<String>,
+    verbose: bool,
+) -> Result<(), std::io::Error> {
     let mut f = fs::OpenOptions::new()
+        .read(true)
         .write(true)
         .create(true)
-        .truncate(true)
-        .open(dest.as_path())?;
+        .truncate(false)
+        .open(merged)?;
 
-    let Ok(mut f) = file_guard::try_lock(f.file_mut(), file_guard::Lock::Exclusive, 0, 64) else {
-        // the digest of the file will not match if the content to be written differed, hence any existing lock
-        // means we are already writing the same content to the file
+    // held until the region is appended, other invocations might be appending to the same file
+    let mut f = lock(&mut f, 64)?;
+
+    let mut existing = String::new();
+    f.read_to_string(&mut existing)?;
+
+    if existing.contains(region_begin) {
         if verbose {
-            eprintln!("expander: already in progress of writing identical content to {} by a different crate", dest.display());
+            debug(format_args!(
+                "region {} already present in {}",
+                shortened_hex,
+                merged.display()
+            ));
         }
-        // now actually wait until the write is complete
-        let _lock = file_guard::lock(f.file_mut(), file_guard::Lock::Exclusive, 0, 64)
-            .expect("File Lock never fails us. qed");
+    } else {
+        if verbose {
+            info(format_args!(
+                "appending region {} to {}",
+                shortened_hex,
+                merged.display()
+            ));
+        }
+        // assemble the whole region upfront, so it's appended with a single write
+        let mut region = Vec::with_capacity(bytes.len() + 256);
+        region.extend_from_slice(region_begin.as_bytes());
+        if let Some(comment) = comment {
+            region.extend_from_slice(comment.as_bytes());
+        }
+        region.extend_from_slice(
+            format!(
+                "#[allow(unused_macros)]\nmacro_rules! __expander_region_{} {{\n    () => {{\n",
+                shortened_hex
+            )
+            .as_bytes(),
+        );
+        region.extend_from_slice(bytes);
+        region.extend_from_slice(
+            format!(
+                "\n    }};\n}}\n#[allow(unused_imports)]\npub(crate) use __expander_region_{};\n// expander-region-end: {}\n",
+                shortened_hex, shortened_hex
+            )
+            .as_bytes(),
+        );
+        f.seek(SeekFrom::End(0))?;
+        f.write_all(&region)?;
+    }
+    Ok(())
+}
 
+/// Files written for `filename_base` other than `dest`, in `dir` and its shards.
+fn siblings(
+    dir: &Path,
+    dest: &Path,
+    filename_base: &str,
+    extension: &str,
+) -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+    let prefix = format!("{}-", filename_base);
+    let suffix = format!(".{}", extension);
+    let mut dirs = vec![dir.to_owned()];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let shard = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| {
+                name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit())
+            });
+        if shard && path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    let entries = dirs
+        .iter()
+        .map(fs::read_dir)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut siblings = Vec::new();
+    for entry in entries.into_iter().flatten() {
+        let path = entry?.path();
+        if path == dest {
+            continue;
+        }
+        let digest = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|name| name.strip_suffix(&suffix));
+        if digest.map_or(false, is_digest) {
+            siblings.push(path);
+        }
+    }
+    Ok(siblings)
+}
+
+/// Remove all files next to `dest` named `{filename_base}-{digest}.{extension}`, except `dest` itself.
+fn collect_garbage(
+    dir: &Path,
+    dest: &Path,
+    filename_base: &str,
+    extension: &str,
+    gc: Gc,
+    verbose: bool,
+) -> Result<(), std::io::Error> {
+    for path in siblings(dir, dest, filename_base, extension)? {
+        if let Gc::OlderThan(ttl) = gc {
+            let age = fs::metadata(&path)?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default();
+            if age < ttl {
+                continue;
+            }
+        }
         if verbose {
-            eprintln!("expander: lock was release, referencing");
+            info(format_args!("removing stale {}", path.display()));
         }
+        match fs::remove_file(&path) {
+            // might have been collected by a parallel invocation already
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        #[cfg(any(feature = "metadata", test))]
+        let _ = fs::remove_file(metadata::metadata_file(&path));
+        #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+        let _ = fs::remove_file(spans::span_map_file(&path));
+    }
+    Ok(())
+}
 
-        let dest = dest.display().to_string();
-        return Ok(quote! {
-            include!( #dest );
-        });
+/// Retain a copy of `dest` in `{filename_base}.history/` and report differences to the previous version.
+///
+/// Copies are named `{sequence}-{file name}`, of which only the latest `versions` are kept.
+fn update_history(
+    dir: &Path,
+    dest: &Path,
+    filename_base: &str,
+    versions: usize,
+    diff_to_file: bool,
+    verbose: bool,
+) -> Result<(), std::io::Error> {
+    let Some(name) = dest.file_name().and_then(|name| name.to_str()) else {
+        return Ok(());
     };
+    let history = dir.join(format!("{}.history", filename_base));
+    fs::create_dir_all(&history)?;
+
+    let mut previous = Vec::new();
+    for entry in fs::read_dir(&history)? {
+        let path = entry?.path();
+        let sequence = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split_once('-'))
+            .and_then(|(sequence, _)| sequence.parse::<u64>().ok());
+        if let Some(sequence) = sequence {
+            previous.push((sequence, path));
+        }
+    }
+    previous.sort();
+
+    let latest = previous
+        .last()
+        .map(|(sequence, path)| (*sequence, path.clone()));
+    if let Some((_, ref path)) = latest {
+        let retained = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split_once('-'))
+            .map(|(_, retained)| retained);
+        if retained == Some(name) {
+            // unchanged since the last expansion
+            return Ok(());
+        }
+        let old = fs::read_to_string(path)?;
+        let new = fs::read_to_string(dest)?;
+        let diff = diff::unified(
+            &old,
+            &new,
+            &path.display().to_string(),
+            &dest.display().to_string(),
+        );
+        if diff_to_file {
+            let mut file = dest.as_os_str().to_owned();
+            file.push(".diff");
+            fs::write(std::path::PathBuf::from(file), diff)?;
+        } else {
+            info(format_args!("{} changed\n{}", filename_base, diff));
+        }
+    }
 
+    let sequence = latest.map_or(0, |(sequence, _)| sequence + 1);
+    let copy = history.join(format!("{:06}-{}", sequence, name));
     if verbose {
-        eprintln!("expander: writing {}", dest.display());
+        debug(format_args!("retaining {}", copy.display()));
     }
+    fs::copy(dest, &copy)?;
+    previous.push((sequence, copy));
+    let excess = previous.len().saturating_sub(versions);
+    for (_, path) in previous.into_iter().take(excess) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Add a module entry for the file at `dest` to the aggregate file of `dest_dir`.
+///
+/// Entries of files which do not exist anymore are dropped in the process.
+fn register_in_aggregate(
+    dest_dir: &Path,
+    dest: &Path,
+    verbose: bool,
+) -> Result<(), std::io::Error> {
+    let aggregate = aggregate_file(dest_dir);
+    fs::create_dir_all(
+        aggregate
+            .parent()
+            .expect("Aggregate file has a parent. qed"),
+    )?;
+
+    let mut f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&aggregate)?;
+    let mut f = lock(&mut f, 64)?;
 
-    if let Some(comment) = comment.into() {
-        f.write_all(&mut comment.as_bytes())?;
+    let mut existing = String::new();
+    f.read_to_string(&mut existing)?;
+
+    let entry = |path: &Path| -> Result<String, std::io::Error> {
+        let mut name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            name.insert(0, '_');
+        }
+        // sanitized stems can collide, e.g. for `foo-bar.rs` and `foo_bar.rs`, their paths cannot
+        let unique = Hasher::default().suffix(&[], path.to_string_lossy().as_bytes(), 8);
+        Ok(format!(
+            "#[path = {}]\npub mod {}_{};\n",
+            path_literal(path)?,
+            name,
+            unique
+        ))
+    };
+    let declaration = entry(dest)?;
+    if existing.contains(&declaration) {
+        return Ok(());
     }
 
-    // Write the already-formatted content while holding the guard
-    f.write_all(&bytes)?;
+    let mut content =
+        String::from("// Generated by expander, lists all expansions written to this directory.\n");
+    // entries span two lines, the path attribute and the module declaration
+    let mut lines = existing.lines();
+    while let Some(line) = lines.next() {
+        let Some(path) = line
+            .strip_prefix("#[path = ")
+            .and_then(|path| path.strip_suffix(']'))
+            .and_then(string_value)
+        else {
+            continue;
+        };
+        let module = lines.next().unwrap_or_default();
+        // `dest` is re-declared below, possibly under a name of an earlier version
+        if Path::new(&path) != dest && Path::new(&path).exists() {
+            content.push_str(line);
+            content.push('\n');
+            content.push_str(module);
+            content.push('\n');
+        }
+    }
+    content.push_str(&declaration);
 
-    let dest = dest.display().to_string();
-    Ok(quote! {
-        include!( #dest );
-    })
+    if verbose {
+        debug(format_args!("updating aggregate {}", aggregate.display()));
+    }
+    f.set_len(0)?;
+    f.seek(SeekFrom::Start(0))?;
+    f.write_all(content.as_bytes())?;
+    Ok(())
 }
 
 fn maybe_run_rustfmt_on_content(
@@ -328,70 +4549,251 @@ fn maybe_run_rustfmt_on_content(
     verbose: bool,
     message: &str,
     token_str: String,
-) -> Result<Vec<u8>, std::io::Error> {
-    Ok(
-        if let RustFmt::Yes {
-            channel,
-            edition,
-            allow_failure,
-        } = *rustfmt
+) -> Result<(Vec<u8>, Formatter), std::io::Error> {
+    if let RustFmt::Yes {
+        channel,
+        edition,
+        allow_failure,
+        ref unstable,
+    } = *rustfmt
+    {
+        if verbose {
+            debug(message);
+        }
+        let command = rustfmt_command(channel, edition, unstable);
+        if let Some(formatted) =
+            run_rustfmt_on_content(token_str.as_bytes(), command, allow_failure)?
         {
-            if verbose {
-                eprintln!("{message}");
-            }
-            run_rustfmt_on_content(token_str.as_bytes(), channel, edition, allow_failure)?
-        } else {
-            token_str.into_bytes()
-        },
-    )
+            return Ok((formatted, Formatter::RustFmt));
+        }
+    }
+    Ok((token_str.into_bytes(), Formatter::Raw))
 }
 
 fn run_rustfmt_on_content(
     content: &[u8],
+    mut command: std::process::Command,
+    allow_failure: bool,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let mut child = command.spawn()?;
+
+    // Write content to rustfmt's stdin while collecting its output, so neither side blocks on a
+    // full pipe for large content
+    let stdin = child.stdin.take();
+    let output = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || match stdin {
+            // Dropping stdin signals EOF to rustfmt
+            Some(mut stdin) => stdin.write_all(content),
+            None => Ok(()),
+        });
+        let output = child.wait_with_output()?;
+        match writer
+            .join()
+            .expect("Writing to a pipe does not panic. qed")
+        {
+            // rustfmt may exit early without reading everything, its status tells why
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e),
+            _ => Ok(output),
+        }
+    })?;
+    rustfmt_formatted(content, output, allow_failure)
+}
+
+/// `rustfmt` formatting its stdin to its stdout.
+fn rustfmt_command(
     channel: Channel,
     edition: Edition,
-    allow_failure: bool,
-) -> Result<Vec<u8>, std::io::Error> {
+    unstable: &[(String, String)],
+) -> std::process::Command {
     let mut process = std::process::Command::new("rustfmt");
     if Channel::Default != channel {
         process.arg(channel.to_string());
     }
-
-    let mut child = process
+    if Channel::Nightly == channel && !unstable.is_empty() {
+        let config = unstable
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join(",");
+        process
+            .arg("--unstable-features")
+            .arg("--config")
+            .arg(config);
+    }
+    process
         .arg(format!("--edition={}", edition))
         .arg("--emit=stdout")
         .arg("--") // Signal to read from stdin
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-
-    // Write content to rustfmt's stdin
-    if let Some(ref mut stdin) = child.stdin {
-        stdin.write_all(content)?;
-        // Dropping stdin here signals EOF to rustfmt
-    }
+        .stderr(Stdio::piped());
+    process
+}
 
-    let output = child.wait_with_output()?;
+/// The formatted content in the `output` of `rustfmt` run on `content`, `None` if it failed and
+/// failures are allowed.
+///
+/// Warnings printed by a successful `rustfmt`, i.e. about unknown configuration keys, are passed on.
+fn rustfmt_formatted(
+    content: &[u8],
+    output: std::process::Output,
+    allow_failure: bool,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
     if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let context = rustfmt_error_context(&String::from_utf8_lossy(content), &stderr)
+            .map(|context| format!("\n{}", context))
+            .unwrap_or_default();
         let error = std::io::Error::new(
             std::io::ErrorKind::Other,
             format!(
-                "rustfmt failed with exit code {}\nstderr: {}",
+                "rustfmt failed with exit code {}\nstderr: {}{}",
                 output.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&output.stderr)
+                stderr,
+                context
             ),
         );
         if allow_failure {
-            eprintln!("expander: {}", error);
-            Ok(content.to_vec())
+            warning(&error);
+            Ok(None)
         } else {
             Err(error)
         }
     } else {
-        Ok(output.stdout)
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !stderr.trim().is_empty() {
+            warning(format_args!("rustfmt reported\n{}", stderr.trim_end()));
+        }
+        Ok(Some(output.stdout))
+    }
+}
+
+/// Locate the first `<stdin>:{line}:{column}` reported by `rustfmt` in `content` and describe the
+/// surrounding generated code.
+///
+/// The serialized tokens are all on one line, so the position itself does not tell anything.
+fn rustfmt_error_context(content: &str, stderr: &str) -> Option<String> {
+    const SURROUNDING: usize = 60;
+    let location = stderr.split("<stdin>:").nth(1)?;
+    let mut location = location.split(|c: char| !c.is_ascii_digit());
+    let line = location.next()?.parse::<usize>().ok()?;
+    let column = location.next()?.parse::<usize>().ok()?;
+
+    let offset = content
+        .split_inclusive('\n')
+        .take(line.checked_sub(1)?)
+        .map(str::len)
+        .sum::<usize>()
+        + content
+            .lines()
+            .nth(line - 1)?
+            .char_indices()
+            .nth(column.saturating_sub(1))
+            .map_or(0, |(index, _)| index);
+
+    let floor = |mut index: usize| {
+        while !content.is_char_boundary(index) {
+            index -= 1;
+        }
+        index
+    };
+    let start = floor(offset.saturating_sub(SURROUNDING));
+    let end = floor((offset + SURROUNDING).min(content.len()));
+    let before = content[start..offset].replace('\n', " ");
+    let after = content[offset..end].replace('\n', " ");
+    let token = after.split_whitespace().next().unwrap_or_default();
+    Some(format!(
+        "at offset {} of the generated code, near `{}`:\n  {}{}\n  {}^",
+        offset,
+        token,
+        before,
+        after,
+        " ".repeat(before.chars().count())
+    ))
+}
+
+/// Register `dest` as dependency of the macro invocation with the incremental compilation of `rustc`,
+/// with feature `tracked-path`.
+fn track(dest: &Path) {
+    #[cfg(feature = "tracked-path")]
+    if proc_macro::is_available() {
+        proc_macro::tracked::path(dest);
+    }
+    #[cfg(not(feature = "tracked-path"))]
+    let _ = dest;
+}
+
+/// Report a warning, attached to the macro invocation as diagnostic with feature `nightly`.
+fn warning(message: impl std::fmt::Display) {
+    if diagnostics::quiet() {
+        return;
+    }
+    #[cfg(feature = "nightly")]
+    if proc_macro::is_available() {
+        let message = format!("expander: {}", message);
+        proc_macro::Diagnostic::spanned(
+            proc_macro::Span::call_site(),
+            proc_macro::Level::Warning,
+            message,
+        )
+        .emit();
+        return;
+    }
+    diagnostics::emit(diagnostics::Level::Warn, message);
+}
+
+/// Report a note, attached to the macro invocation as diagnostic with feature `nightly`.
+fn note(message: impl std::fmt::Display) {
+    if diagnostics::quiet() {
+        return;
     }
+    #[cfg(feature = "nightly")]
+    if proc_macro::is_available() {
+        let message = format!("expander: {}", message);
+        proc_macro::Diagnostic::spanned(
+            proc_macro::Span::call_site(),
+            proc_macro::Level::Note,
+            message,
+        )
+        .emit();
+        return;
+    }
+    diagnostics::emit(diagnostics::Level::Info, message);
 }
 
+#[cfg(any(feature = "tokio", test))]
+mod async_io;
+mod cache;
+mod diagnostics;
+use diagnostics::{debug, info};
+mod diff;
+mod environment;
+mod escape;
+pub use environment::{check_environment, Environment};
+mod header;
+pub use header::Header;
+mod macro_error;
+pub use macro_error::MacroError;
+mod memo;
+mod session;
+mod stats;
+mod store;
+mod toolchain;
+mod transform;
+pub use session::ExpansionSession;
+pub use stats::Stats;
+pub use transform::{Stage, Transform};
+#[cfg(any(feature = "metadata", test))]
+pub mod manifest;
+#[cfg(any(feature = "metadata", test))]
+pub mod metadata;
+#[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+pub mod spans;
+#[cfg(any(feature = "testing", test))]
+pub mod testing;
+#[cfg(any(feature = "validate", test))]
+pub mod validate;
+
 #[cfg(test)]
+#[allow(clippy::unnecessary_literal_unwrap)] // the original tests unwrap `Ok` literals
 mod tests;
@@ -0,0 +1,95 @@
+//! Errors of proc-macros which are passed through [`Expander::maybe_write_any_to`](crate::Expander::maybe_write_any_to).
+
+use proc_macro2::TokenStream;
+
+/// Error of a proc-macro, reported as `compile_error!` in place of the expansion.
+///
+/// Implemented for the error types of the commonly used proc-macro libraries, each behind a feature
+/// named like the library, and for [`TokenStream`] containing the reporting tokens already, i.e.
+/// by mapping an error implementing `ToTokens` with `.map_err(ToTokens::into_token_stream)`.
+/// Implement it for custom error types to pass them through as is.
+pub trait MacroError: Sized {
+    /// Tokens reporting the error, to be returned by the proc-macro in place of the expansion.
+    fn into_compile_error(self) -> TokenStream;
+
+    #[cfg(any(feature = "validate", test))]
+    /// Convert the error of [`validate`](crate::Expander::validate), or return it if not representable.
+    ///
+    /// [`maybe_write_any_to`](crate::Expander::maybe_write_any_to) reports it as `compile_error!` in that case.
+    fn from_invalid(error: syn::Error) -> Result<Self, syn::Error> {
+        Err(error)
+    }
+}
+
+impl MacroError for TokenStream {
+    fn into_compile_error(self) -> TokenStream {
+        self
+    }
+}
+
+#[cfg(any(feature = "syndicate", test))]
+impl MacroError for syn::Error {
+    fn into_compile_error(self) -> TokenStream {
+        self.to_compile_error()
+    }
+
+    #[cfg(any(feature = "validate", test))]
+    fn from_invalid(error: syn::Error) -> Result<Self, syn::Error> {
+        Ok(error)
+    }
+}
+
+#[cfg(any(feature = "syndicate", test))]
+impl MacroError for Vec<syn::Error> {
+    /// Combines all errors, each is reported as separate `compile_error!` at its own span.
+    fn into_compile_error(self) -> TokenStream {
+        self.into_iter()
+            .reduce(|mut combined, error| {
+                combined.combine(error);
+                combined
+            })
+            .map(|combined| combined.to_compile_error())
+            .unwrap_or_default()
+    }
+
+    #[cfg(any(feature = "validate", test))]
+    fn from_invalid(error: syn::Error) -> Result<Self, syn::Error> {
+        Ok(vec![error])
+    }
+}
+
+#[cfg(feature = "proc-macro-error2")]
+impl MacroError for proc_macro_error2::Diagnostic {
+    fn into_compile_error(self) -> TokenStream {
+        quote::ToTokens::into_token_stream(self)
+    }
+
+    #[cfg(any(feature = "validate", test))]
+    fn from_invalid(error: syn::Error) -> Result<Self, syn::Error> {
+        Ok(error.into())
+    }
+}
+
+#[cfg(feature = "darling")]
+impl MacroError for darling::Error {
+    fn into_compile_error(self) -> TokenStream {
+        self.write_errors()
+    }
+
+    #[cfg(any(feature = "validate", test))]
+    fn from_invalid(error: syn::Error) -> Result<Self, syn::Error> {
+        Ok(error.into())
+    }
+}
+
+#[cfg(feature = "manyhow")]
+impl MacroError for manyhow::Error {
+    fn into_compile_error(self) -> TokenStream {
+        manyhow::ToTokensError::into_token_stream(self)
+    }
+
+    #[cfg(any(feature = "validate", test))]
+    fn from_invalid(error: syn::Error) -> Result<Self, syn::Error> {
+        Ok(error.into())
+    }
+}
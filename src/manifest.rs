@@ -0,0 +1,209 @@
+//! Build-wide record of all written expansions.
+//!
+//! Each expansion written with [`Expander::manifest`](crate::Expander::manifest) enabled adds an
+//! entry to `expander-manifest.json` in the target directory, which allows auditing how much code
+//! is generated by macros across a whole build.
+
+use crate::fs;
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A single written expansion.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    /// Path of the written file.
+    pub path: PathBuf,
+    /// The crate being compiled when the expansion was written, if known.
+    pub crate_name: Option<String>,
+    /// Hex encoded digest of the written file.
+    pub digest: String,
+    /// Size of the written file in bytes.
+    pub size: u64,
+    /// Id of the process which recorded the entry, distinguishing the runs of a build.
+    #[serde(default)]
+    pub process: Option<u32>,
+}
+
+impl Entry {
+    /// The crate, directory and filename base the entry was written for, `None` for a file
+    /// without digest in its name, which is replaced in place rather than superseded.
+    fn origin(&self) -> Option<(Option<&str>, &Path, &str)> {
+        let name = self.path.file_name()?.to_str()?;
+        let (stem, _) = name.split_once('.')?;
+        let (filename_base, digest) = stem.rsplit_once('-')?;
+        crate::is_digest(digest).then_some((
+            self.crate_name.as_deref(),
+            self.path.parent()?,
+            filename_base,
+        ))
+    }
+}
+
+/// All expansions recorded in a manifest file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    entries: Vec<Entry>,
+}
+
+impl Manifest {
+    /// Read the manifest at `path`, a missing manifest is treated as empty.
+    pub fn read(path: &Path) -> Result<Self, std::io::Error> {
+        match fs::read(path) {
+            Ok(content) => Self::parse(&content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn parse(content: &[u8]) -> Result<Self, std::io::Error> {
+        if content.is_empty() {
+            return Ok(Self::default());
+        }
+        serde_json::from_slice(content).map_err(std::io::Error::from)
+    }
+
+    /// Write the manifest to `path`, replacing its previous content.
+    ///
+    /// Not synchronized with concurrently running builds.
+    pub fn write(&self, path: &Path) -> Result<(), std::io::Error> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Drop all entries whose file does not exist anymore.
+    pub fn retain_existing(&mut self) {
+        self.entries.retain(|entry| entry.path.is_file());
+    }
+
+    /// All recorded entries, in order of recording.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Entries written while compiling crate `crate_name`.
+    pub fn by_crate<'a>(&'a self, crate_name: &'a str) -> impl Iterator<Item = &'a Entry> + 'a {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.crate_name.as_deref() == Some(crate_name))
+    }
+
+    /// Entries written for `filename_base`, named `{filename_base}-{digest}.{extension}`, or
+    /// `{filename_base}.{extension}` with a [stable path](crate::Expander::stable_path).
+    pub fn by_filename_base<'a>(
+        &'a self,
+        filename_base: &'a str,
+    ) -> impl Iterator<Item = &'a Entry> + 'a {
+        self.entries.iter().filter(move |entry| {
+            let suffix = entry
+                .path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(filename_base));
+            match suffix.map(|suffix| (suffix, suffix.strip_prefix('-'))) {
+                Some((_, Some(suffixed))) => suffixed
+                    .split_once('.')
+                    .map_or(false, |(digest, _)| crate::is_digest(digest)),
+                Some((suffix, None)) => suffix.starts_with('.'),
+                None => false,
+            }
+        })
+    }
+
+    /// Entries superseded by a later run, i.e. a later compilation of their crate, which wrote
+    /// the same filename base into the same directory but not their file.
+    ///
+    /// Every run of a build records the files it writes anew, including unchanged ones, so the
+    /// remaining entries of an earlier run are stale. Another target of the same crate, i.e. its
+    /// unit tests, counts as later run too, deleting its files only causes it to be rebuilt.
+    pub fn superseded(&self) -> impl Iterator<Item = &Entry> + '_ {
+        let latest = self
+            .entries
+            .iter()
+            .filter_map(|entry| Some((entry.origin()?, entry.process)))
+            .collect::<HashMap<_, _>>();
+        self.entries.iter().filter(move |entry| {
+            entry
+                .origin()
+                .map_or(false, |origin| latest[&origin] != entry.process)
+        })
+    }
+
+    /// Sum of the sizes of all entries in bytes.
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+
+    /// The `n` largest entries, largest first.
+    pub fn largest(&self, n: usize) -> Vec<&Entry> {
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.size));
+        entries.truncate(n);
+        entries
+    }
+}
+
+/// Path of the manifest for expansions written to `dest_dir`.
+///
+/// Located in the cargo target directory, identified by its `CACHEDIR.TAG`, if `dest_dir` is
+/// within one, and `dest_dir` itself otherwise.
+pub fn manifest_file(dest_dir: &Path) -> PathBuf {
+    dest_dir
+        .ancestors()
+        .find(|dir| dir.join("CACHEDIR.TAG").is_file())
+        .unwrap_or(dest_dir)
+        .join("expander-manifest.json")
+}
+
+/// Add or update the entry for the written file `dest` to the manifest at `manifest`, dropping
+/// the entries of deleted files.
+///
+/// A corrupt manifest is rebuilt from scratch.
+pub(crate) fn record(
+    manifest: &Path,
+    dest: &Path,
+    crate_name: Option<String>,
+) -> Result<(), std::io::Error> {
+    let content = fs::read(dest)?;
+    let entry = Entry {
+        path: dest.to_owned(),
+        crate_name,
+        digest: crate::digest_hex(&content),
+        size: content.len() as u64,
+        process: Some(std::process::id()),
+    };
+
+    let mut f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(manifest)?;
+    // the manifest is shared by all crates of the build, which might be compiled in parallel
+    let mut f = crate::lock(&mut f, 64)?;
+
+    let mut existing = Vec::new();
+    f.read_to_end(&mut existing)?;
+    let mut manifest = Manifest::parse(&existing).unwrap_or_else(|e| {
+        crate::warning(format_args!(
+            "manifest {} is corrupt, rebuilding it: {}",
+            manifest.display(),
+            e
+        ));
+        Manifest::default()
+    });
+    let recorded = manifest.entries.len();
+    manifest.retain_existing();
+    if manifest.entries.contains(&entry) && manifest.entries.len() == recorded {
+        return Ok(());
+    }
+    manifest
+        .entries
+        .retain(|existing| existing.path != entry.path);
+    manifest.entries.push(entry);
+
+    let content = serde_json::to_vec_pretty(&manifest)?;
+    f.set_len(0)?;
+    f.seek(SeekFrom::Start(0))?;
+    f.write_all(&content)?;
+    Ok(())
+}
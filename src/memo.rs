@@ -0,0 +1,158 @@
+//! Index of previous expansions keyed by the digest of their input tokens, see
+//! [`Expander::memoize`](crate::Expander::memoize).
+
+use crate::fs;
+use crate::{
+    cache, CommentStyle, Emit, Expander, FormatChain, Hasher, Header, License, LineEnding,
+    PostCommand, RustFmt, Stored, Wrap,
+};
+use proc_macro2::TokenStream;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Directory of the index within the destination directory.
+const MEMO: &str = "memo";
+
+/// The configuration of an [`Expander`] affecting the written file, its content, location and
+/// sidecars, but not i.e. the verbosity or the tokens referencing it.
+#[derive(Debug)]
+#[allow(dead_code)] // only read through `Debug`
+struct Output<'a> {
+    filename_base: &'a str,
+    comment: &'a Option<String>,
+    comment_style: &'a CommentStyle,
+    header: &'a Option<Header>,
+    license: &'a Option<License>,
+    rustfmt: &'a RustFmt,
+    fmt_unstable: &'a [(String, String)],
+    emit: &'a Emit,
+    extension: &'a str,
+    stable_path: bool,
+    strict: bool,
+    format_chain: &'a Option<FormatChain>,
+    reproducible: bool,
+    line_ending: &'a Option<LineEnding>,
+    inner_attrs: &'a [Stored],
+    #[cfg(feature = "pretty")]
+    outer_attrs: &'a [Stored],
+    wrap: &'a Option<Wrap>,
+    digest_len: usize,
+    hasher: &'a Hasher,
+    hash_input: bool,
+    salt: &'a [u8],
+    per_crate_dir: bool,
+    per_macro_dir: bool,
+    shard: bool,
+    file_mode: Option<u32>,
+    read_only: bool,
+    dedup: bool,
+    path_prefixes: &'a [(String, String)],
+    sandbox_fallback: bool,
+    post_commands: &'a [PostCommand],
+    #[cfg(any(feature = "call-site", test))]
+    call_site: &'a Option<String>,
+    #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+    span_map: bool,
+    #[cfg(any(feature = "metadata", test))]
+    metadata: bool,
+}
+
+impl<'a> From<&'a Expander> for Output<'a> {
+    fn from(expander: &'a Expander) -> Self {
+        Self {
+            filename_base: &expander.filename_base,
+            comment: &expander.comment,
+            comment_style: &expander.comment_style,
+            header: &expander.header,
+            license: &expander.license,
+            rustfmt: &expander.rustfmt,
+            fmt_unstable: &expander.fmt_unstable,
+            emit: &expander.emit,
+            extension: &expander.extension,
+            stable_path: expander.stable_path,
+            strict: expander.strict,
+            format_chain: &expander.format_chain,
+            reproducible: expander.reproducible,
+            line_ending: &expander.line_ending,
+            inner_attrs: &expander.inner_attrs,
+            #[cfg(feature = "pretty")]
+            outer_attrs: &expander.outer_attrs,
+            wrap: &expander.wrap,
+            digest_len: expander.digest_len,
+            hasher: &expander.hasher,
+            hash_input: expander.hash_input,
+            salt: &expander.salt,
+            per_crate_dir: expander.per_crate_dir,
+            per_macro_dir: expander.per_macro_dir,
+            shard: expander.shard,
+            file_mode: expander.file_mode,
+            read_only: expander.read_only,
+            dedup: expander.dedup,
+            path_prefixes: &expander.path_prefixes,
+            sandbox_fallback: expander.sandbox_fallback,
+            post_commands: &expander.post_commands,
+            #[cfg(any(feature = "call-site", test))]
+            call_site: &expander.call_site,
+            #[cfg(any(feature = "spans", all(test, feature = "pretty")))]
+            span_map: expander.span_map,
+            #[cfg(any(feature = "metadata", test))]
+            metadata: expander.metadata,
+        }
+    }
+}
+
+/// Digest of `tokens` and everything else the written file depends on: the crate version and
+/// features, the configuration of the `expander` affecting the output, the license banner and the
+/// `rustfmt` toolchain.
+///
+/// The banner is rendered, so edits to a [`header_file`](crate::Expander::header_file) change
+/// the key, while its path alone is part of the configuration.
+pub(crate) fn key(expander: &Expander, tokens: &TokenStream) -> Result<String, std::io::Error> {
+    let banner = match expander.license {
+        Some(ref license) => license.render()?,
+        None => String::new(),
+    };
+    let mut digester = Hasher::default().digester(&[]);
+    for part in [
+        env!("CARGO_PKG_VERSION"),
+        if cfg!(feature = "pretty") {
+            "pretty"
+        } else {
+            ""
+        },
+        &format!("{:?}", Output::from(expander)),
+        &banner,
+        &cache::toolchain(&expander.rustfmt),
+    ] {
+        digester.update(part.as_bytes());
+        digester.update(&[0]);
+    }
+    write!(digester, "{}", tokens).expect("Digesting never fails. qed");
+    Ok(digester.suffix(64))
+}
+
+/// The file written for `key` within `dest_dir`, if it still exists.
+pub(crate) fn lookup(dest_dir: &Path, key: &str) -> Option<PathBuf> {
+    let relative = fs::read_to_string(dest_dir.join(MEMO).join(key)).ok()?;
+    let dest = dest_dir.join(relative);
+    dest.is_file().then_some(dest)
+}
+
+/// Record `dest` as the file written for `key` within `dest_dir`.
+///
+/// Destinations outside of `dest_dir` or not representable as UTF-8 are not recorded.
+pub(crate) fn insert(dest_dir: &Path, key: &str, dest: &Path) -> Result<(), std::io::Error> {
+    let Some(relative) = dest
+        .strip_prefix(dest_dir)
+        .ok()
+        .and_then(|relative| relative.to_str())
+    else {
+        return Ok(());
+    };
+    let memo = dest_dir.join(MEMO);
+    fs::create_dir_all(&memo)?;
+    // readers never observe a partially written entry
+    let partial = memo.join(format!(".{}.{}", key, std::process::id()));
+    fs::write(&partial, relative)?;
+    fs::rename(&partial, memo.join(key))
+}
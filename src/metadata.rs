@@ -0,0 +1,41 @@
+//! Machine readable information about written expansions.
+
+use crate::fs;
+use std::path::{Path, PathBuf};
+
+/// Content of the `{file}.meta.json` sidecar written next to each expansion when
+/// [`Expander::metadata`](crate::Expander::metadata) is enabled.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Metadata {
+    /// The filename base the expansion was written with, commonly the name of the macro.
+    pub macro_name: String,
+    /// The crate being compiled when the expansion was written, if known.
+    pub crate_name: Option<String>,
+    /// Hex encoded digest of the content before formatting.
+    pub input_digest: String,
+    /// The formatter which produced the written content, one of `prettyplease`, `rustfmt`, `raw`
+    /// or `transform`, if a [`Transform`](crate::Transform) returned the source.
+    pub formatter: String,
+    /// Seconds since the unix epoch at the time of writing.
+    pub timestamp: u64,
+}
+
+impl Metadata {
+    /// Read the sidecar at `path`.
+    pub fn read(path: &Path) -> Result<Self, std::io::Error> {
+        let content = fs::read(path)?;
+        serde_json::from_slice(&content).map_err(std::io::Error::from)
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<(), std::io::Error> {
+        let content = serde_json::to_vec_pretty(self)?;
+        fs::write(path, content)
+    }
+}
+
+/// Path of the metadata sidecar of the expansion written to `dest`.
+pub fn metadata_file(dest: &Path) -> PathBuf {
+    let mut file = dest.as_os_str().to_owned();
+    file.push(".meta.json");
+    PathBuf::from(file)
+}
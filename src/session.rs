@@ -0,0 +1,82 @@
+//! Several expansions of a single macro invocation.
+
+use crate::Expander;
+use proc_macro2::TokenStream;
+use quote::ToTokens;
+use std::path::{Path, PathBuf};
+
+/// Writes several outputs of one macro invocation with a shared configuration.
+///
+/// Every output is written with the filename base of the [`Expander`] suffixed by its number, i.e.
+/// `{filename_base}-0`, and the tokens referencing all of them are combined in order. With
+/// [`verbose`](Expander::verbose), a single summary is printed on [`finish`](Self::finish)
+/// instead of a report per output.
+#[derive(Debug)]
+pub struct ExpansionSession {
+    expander: Expander,
+    verbose: bool,
+    dest_dir: PathBuf,
+    tokens: TokenStream,
+    written: Vec<PathBuf>,
+    count: usize,
+}
+
+impl ExpansionSession {
+    /// Create a session writing to `env!("OUT_DIR")`.
+    pub fn new(expander: Expander) -> Self {
+        let dest_dir = expander.out_dir();
+        Self::with_dest_dir(expander, &dest_dir)
+    }
+
+    /// Create a session writing to `dest_dir`.
+    pub fn with_dest_dir(expander: Expander, dest_dir: &Path) -> Self {
+        let verbose = expander.verbose;
+        Self {
+            expander: expander.verbose(false),
+            verbose,
+            dest_dir: dest_dir.to_owned(),
+            tokens: TokenStream::new(),
+            written: Vec::new(),
+            count: 0,
+        }
+    }
+
+    /// Write the next output and append the tokens referencing it to the combined tokens.
+    ///
+    /// Written like [`write_to`](Expander::write_to), returns the tokens referencing this output
+    /// only.
+    pub fn add(&mut self, tokens: impl ToTokens) -> Result<TokenStream, std::io::Error> {
+        let mut expander = self.expander.clone();
+        expander.filename_base = format!("{}-{}", expander.filename_base, self.count);
+        self.count += 1;
+
+        let (dest, tokens) = expander.write_to_path(tokens.into_token_stream(), &self.dest_dir)?;
+        self.written.extend(dest);
+        self.tokens.extend(tokens.clone());
+        Ok(tokens)
+    }
+
+    /// Paths of the files written so far.
+    pub fn written(&self) -> &[PathBuf] {
+        &self.written
+    }
+
+    /// Finish the session and return the combined tokens of all outputs.
+    pub fn finish(self) -> TokenStream {
+        let _quiet = crate::diagnostics::Quiet::enter(self.expander.quiet);
+        if self.verbose {
+            let mut summary = format!(
+                "{} wrote {} of {} outputs to {}",
+                self.expander.filename_base,
+                self.written.len(),
+                self.count,
+                self.dest_dir.display()
+            );
+            for dest in &self.written {
+                summary += &format!("\n  {}", dest.display());
+            }
+            crate::info(summary);
+        }
+        self.tokens
+    }
+}
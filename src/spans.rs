@@ -0,0 +1,118 @@
+//! Map lines of a written file back to the spans of the tokens it was generated from.
+
+use crate::fs;
+use std::path::{Path, PathBuf};
+
+/// Location in the source of the tokens an item was generated from.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Origin {
+    /// The file the tokens originate from, for display purposes only.
+    pub file: String,
+    /// One based line of the first token.
+    pub start_line: usize,
+    /// Zero based column of the first token.
+    pub start_column: usize,
+    /// One based line of the last token.
+    pub end_line: usize,
+    /// Zero based column after the last token.
+    pub end_column: usize,
+}
+
+/// Top level item of a written file.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    /// One based first line of the item in the written file.
+    pub first_line: usize,
+    /// One based last line of the item in the written file, inclusive.
+    pub last_line: usize,
+    /// Where the tokens of the item originate from.
+    pub origin: Origin,
+}
+
+/// Content of the `{file}.spans.json` sidecar written next to each expansion when
+/// [`Expander::span_map`](crate::Expander::span_map) is enabled.
+///
+/// Note that the spans are only meaningful if the proc-macro runs on a toolchain which exposes
+/// span locations, and the line ranges are only determined for files formatted with `prettyplease`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SpanMap {
+    /// One entry per top level item found in the written file.
+    pub entries: Vec<Entry>,
+}
+
+impl SpanMap {
+    /// Read the sidecar at `path`.
+    pub fn read(path: &Path) -> Result<Self, std::io::Error> {
+        let content = fs::read(path)?;
+        serde_json::from_slice(&content)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<(), std::io::Error> {
+        let content = serde_json::to_vec_pretty(self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        fs::write(path, content)
+    }
+
+    /// Find the origin of the item covering `line` of the written file.
+    pub fn origin(&self, line: usize) -> Option<&Origin> {
+        self.entries
+            .iter()
+            .find(|entry| (entry.first_line..=entry.last_line).contains(&line))
+            .map(|entry| &entry.origin)
+    }
+
+    /// Locate every item of `file` in the `formatted` content, which starts after `offset` lines.
+    pub(crate) fn new(file: &syn::File, formatted: &str, offset: usize) -> Self {
+        let lines = formatted.lines().collect::<Vec<_>>();
+        let mut cursor = 0;
+        let mut entries = Vec::with_capacity(file.items.len());
+        for item in &file.items {
+            let Some(origin) = origin(item) else {
+                continue;
+            };
+            let unparsed = prettyplease::unparse(&syn::File {
+                shebang: None,
+                attrs: Vec::new(),
+                items: vec![item.clone()],
+            });
+            let Some(first) = unparsed.lines().next() else {
+                continue;
+            };
+            let Some(found) = lines[cursor..].iter().position(|line| *line == first) else {
+                continue;
+            };
+            let first_line = cursor + found;
+            let last_line = first_line + unparsed.lines().count() - 1;
+            cursor = last_line + 1;
+            entries.push(Entry {
+                first_line: offset + first_line + 1,
+                last_line: offset + last_line + 1,
+                origin,
+            });
+        }
+        Self { entries }
+    }
+}
+
+/// Span of the first to the last token of `item`.
+fn origin(item: &syn::Item) -> Option<Origin> {
+    let tokens = quote::ToTokens::to_token_stream(item);
+    let first = tokens.clone().into_iter().next()?.span();
+    let last = tokens.into_iter().last()?.span();
+    let (start, end) = (first.start(), last.end());
+    Some(Origin {
+        file: first.file(),
+        start_line: start.line,
+        start_column: start.column,
+        end_line: end.line,
+        end_column: end.column,
+    })
+}
+
+/// Path of the span map sidecar for the written file `dest`.
+pub fn span_map_file(dest: &Path) -> PathBuf {
+    let mut path = dest.as_os_str().to_owned();
+    path.push(".spans.json");
+    PathBuf::from(path)
+}
@@ -0,0 +1,97 @@
+//! Size statistics of an expansion, see [`Expander::stats`](crate::Expander::stats).
+
+/// Where the statistics of an expansion are reported, to monitor macro bloat over time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stats {
+    /// Print a line to `stderr` during the proc-macro invocation.
+    Stderr,
+    /// Append a comment to the header of the generated file.
+    Header,
+}
+
+/// Size of the formatted content of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Summary {
+    bytes: usize,
+    lines: usize,
+    /// Top-level items, functions and impl blocks, if the content could be parsed.
+    items: Option<Items>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Items {
+    top_level: usize,
+    functions: usize,
+    impls: usize,
+}
+
+impl Summary {
+    /// Collect the statistics of the formatted `content`.
+    pub(crate) fn of(content: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(content);
+        Self {
+            bytes: content.len(),
+            lines: text.lines().count(),
+            items: items(&text),
+        }
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes, {} lines", self.bytes, self.lines)?;
+        if let Some(items) = self.items {
+            write!(
+                f,
+                ", {} items, {} functions, {} impl blocks",
+                items.top_level, items.functions, items.impls
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Count the items of `text`, `None` without `syn` or if it is not valid Rust.
+#[cfg(any(feature = "pretty", feature = "validate", test))]
+fn items(text: &str) -> Option<Items> {
+    fn count(items: &[syn::Item], counted: &mut Items) {
+        for item in items {
+            match item {
+                syn::Item::Fn(_) => counted.functions += 1,
+                syn::Item::Impl(item) => {
+                    counted.impls += 1;
+                    counted.functions += item
+                        .items
+                        .iter()
+                        .filter(|item| matches!(item, syn::ImplItem::Fn(_)))
+                        .count();
+                }
+                syn::Item::Trait(item) => {
+                    counted.functions += item
+                        .items
+                        .iter()
+                        .filter(|item| matches!(item, syn::TraitItem::Fn(_)))
+                        .count();
+                }
+                syn::Item::Mod(syn::ItemMod {
+                    content: Some((_, items)),
+                    ..
+                }) => count(items, counted),
+                _ => {}
+            }
+        }
+    }
+
+    let file = syn::parse_file(text).ok()?;
+    let mut counted = Items {
+        top_level: file.items.len(),
+        ..Items::default()
+    };
+    count(&file.items, &mut counted);
+    Some(counted)
+}
+
+#[cfg(not(any(feature = "pretty", feature = "validate", test)))]
+fn items(_text: &str) -> Option<Items> {
+    None
+}
@@ -0,0 +1,75 @@
+//! Content addressed store shared by all filename bases, see [`Expander::dedup`](crate::Expander::dedup).
+
+use crate::fs;
+use std::path::{Path, PathBuf};
+
+/// Filename base of all stored files, `blob-{digest}.{extension}`.
+pub(crate) const BLOB: &str = "blob";
+
+/// Directory of the store within `dest_dir`.
+pub(crate) fn store_dir(dest_dir: &Path) -> PathBuf {
+    dest_dir.join("store")
+}
+
+/// Record that `filename_base` references the stored file `blob` and remove the previously
+/// referenced file, once no filename base references it anymore.
+///
+/// Every filename base references a single stored file via `{filename_base}.ref` within the store.
+pub(crate) fn reference(
+    store: &Path,
+    filename_base: &str,
+    blob: &Path,
+    verbose: bool,
+) -> Result<(), std::io::Error> {
+    let blob = blob
+        .strip_prefix(store)
+        .unwrap_or(blob)
+        .to_string_lossy()
+        .into_owned();
+    let mut lock = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(store.join(".lock"))?;
+    let _lock = crate::lock(&mut lock, 1)?;
+
+    let ref_file = store.join(format!("{}.ref", filename_base));
+    let previous = match fs::read_to_string(&ref_file) {
+        Ok(previous) => previous,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    if previous == blob {
+        return Ok(());
+    }
+    fs::write(&ref_file, &blob)?;
+    if previous.is_empty() || is_referenced(store, &previous)? {
+        return Ok(());
+    }
+    let unreferenced = store.join(&previous);
+    if verbose {
+        crate::info(format_args!(
+            "removing unreferenced {}",
+            unreferenced.display()
+        ));
+    }
+    match fs::remove_file(&unreferenced) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// If any filename base references the stored file `blob`.
+fn is_referenced(store: &Path, blob: &str) -> Result<bool, std::io::Error> {
+    for entry in fs::read_dir(store)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .map_or(false, |extension| extension == "ref")
+            && fs::read_to_string(&path)? == blob
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,235 @@
+//! Helpers to test proc-macros built on [`Expander`].
+//!
+//! Intended to be used from the tests of a proc-macro crate, where resolving the digest based
+//! filename and reading the generated file back is required to inspect the expansion.
+
+use crate::fs;
+use crate::{diff, Expander};
+use proc_macro2::TokenStream;
+use std::path::{Path, PathBuf};
+
+/// Environment variable which, if set, makes [`assert_snapshot`] overwrite differing snapshots.
+pub const UPDATE_SNAPSHOTS: &str = "EXPANDER_UPDATE_SNAPSHOTS";
+
+/// Expansion with the generated file read back.
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    /// Filename base of the [`Expander`], stable across changes of the content.
+    pub filename_base: String,
+    /// Tokens referencing the generated file, as returned by [`write_to`](Expander::write_to).
+    pub tokens: TokenStream,
+    /// Path of the generated file, `None` if the tokens are returned inline, i.e. in
+    /// [`dry`](Expander::dry) mode.
+    pub path: Option<PathBuf>,
+    /// Content of the generated file with normalized line endings.
+    pub content: String,
+}
+
+/// Expand `tokens` under `env!("OUT_DIR")` and read the generated file back.
+///
+/// If the tokens are returned inline rather than written, i.e. in [`dry`](Expander::dry) mode or
+/// below the [`min_size`](Expander::min_size), the content is the serialized `tokens`.
+pub fn expand(expander: &Expander, tokens: TokenStream) -> Result<Expansion, std::io::Error> {
+    let out = crate::out_dir();
+    expand_to(expander, tokens, out.as_path())
+}
+
+/// Expand `tokens` into `dest_dir` and read the generated file back, see [`expand`].
+pub fn expand_to(
+    expander: &Expander,
+    tokens: TokenStream,
+    dest_dir: &Path,
+) -> Result<Expansion, std::io::Error> {
+    let filename_base = expander.filename_base.clone();
+    let content = tokens.to_string();
+    // the same path as `write_to`, including `tee`, `inline_switch` and the fallbacks
+    let (path, tokens) = expander.write_to_path(tokens, dest_dir)?;
+    let content = match path {
+        Some(ref path) => normalize(&fs::read_to_string(path)?),
+        None => content,
+    };
+    Ok(Expansion {
+        filename_base,
+        tokens,
+        path,
+        content,
+    })
+}
+
+/// Expand `tokens` under `env!("OUT_DIR")` and return the referencing tokens alongside the content
+/// of the generated file, see [`expand`].
+pub fn expand_and_read(
+    expander: &Expander,
+    tokens: TokenStream,
+) -> Result<(TokenStream, String), std::io::Error> {
+    let expansion = expand(expander, tokens)?;
+    Ok((expansion.tokens, expansion.content))
+}
+
+/// Replace the digest in paths of generated files, i.e. `baz-874698265c6c.rs`, with `{hash}`.
+///
+/// Intended for `trybuild` and other `.stderr` golden files, which would change with every change of
+/// the generated content otherwise.
+pub fn redact_digests(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('-') {
+        let (head, tail) = rest.split_at(at + 1);
+        redacted.push_str(head);
+        let len = tail
+            .bytes()
+            .take_while(|&c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c))
+            .count();
+        let is_digest =
+            crate::DIGEST_LENS.contains(&len) && tail.as_bytes().get(len) == Some(&b'.');
+        if is_digest {
+            redacted.push_str("{hash}");
+            rest = &tail[len..];
+        } else {
+            rest = tail;
+        }
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// Normalize `\r\n` line endings to `\n`.
+pub fn normalize(content: &str) -> String {
+    content.replace("\r\n", "\n")
+}
+
+/// Compare `content` against the snapshot stored at `snapshot`, panicking with a diff on mismatch.
+///
+/// A missing snapshot is created, an existing one is only overwritten if [`UPDATE_SNAPSHOTS`] is set.
+#[track_caller]
+pub fn assert_snapshot(content: &str, snapshot: &Path) {
+    if let Err(mismatch) = compare_snapshot(content, snapshot) {
+        panic!("{}", mismatch);
+    }
+}
+
+/// Compare `content` against `snapshot` and describe the mismatch, see [`assert_snapshot`].
+fn compare_snapshot(content: &str, snapshot: &Path) -> Result<(), String> {
+    let content = normalize(content);
+    let update = std::env::var_os(UPDATE_SNAPSHOTS).is_some();
+    let expected = match fs::read_to_string(snapshot) {
+        Ok(expected) => Some(normalize(&expected)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(format!("expander: failed to read snapshot: {}", e)),
+    };
+    match expected {
+        Some(expected) if expected == content => Ok(()),
+        Some(expected) if !update => Err(format!(
+            "expander: expansion does not match snapshot, set `{}` to update it\n{}",
+            UPDATE_SNAPSHOTS,
+            diff::unified(
+                &expected,
+                &content,
+                &snapshot.display().to_string(),
+                "expansion"
+            )
+        )),
+        _ => {
+            let write = || {
+                if let Some(parent) = snapshot.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(snapshot, content)
+            };
+            write().map_err(|e| format!("expander: failed to write snapshot: {}", e))
+        }
+    }
+}
+
+/// Run `expand` over every `*.input.rs` fixture in `dir` and compare the formatted result against
+/// the `*.expanded.rs` file next to it, panicking with all mismatches.
+///
+/// The output is formatted like `expander` formats it when writing, according to the features
+/// of the crate and the [`fmt`](Expander::fmt) settings of `expander`. Missing expanded files are
+/// created, differing ones are only overwritten if [`UPDATE_SNAPSHOTS`] is set.
+#[track_caller]
+pub fn golden(dir: &Path, expander: &Expander, mut expand: impl FnMut(TokenStream) -> TokenStream) {
+    let mut fixtures = fs::read_dir(dir)
+        .expect("expander: failed to read fixture directory")
+        .map(|entry| entry.expect("expander: failed to read fixture").path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| name.ends_with(".input.rs"))
+        })
+        .collect::<Vec<_>>();
+    fixtures.sort();
+    assert!(
+        !fixtures.is_empty(),
+        "expander: no `*.input.rs` fixtures in {}",
+        dir.display()
+    );
+
+    let mut mismatches = Vec::new();
+    for input in fixtures {
+        let name = input
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".input.rs"))
+            .expect("Filtered by suffix. qed");
+        let expanded = input.with_file_name(format!("{}.expanded.rs", name));
+        let result = fs::read_to_string(&input)
+            .map_err(|e| e.to_string())
+            .and_then(|source| source.parse::<TokenStream>().map_err(|e| e.to_string()))
+            .and_then(|tokens| {
+                expander
+                    .format_uncached(expand(tokens), &input)
+                    .map_err(|e| e.to_string())
+            })
+            .and_then(|(bytes, _)| compare_snapshot(&String::from_utf8_lossy(&bytes), &expanded));
+        if let Err(mismatch) = result {
+            mismatches.push(format!("{}: {}", input.display(), mismatch));
+        }
+    }
+    assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+}
+
+/// Expand `tokens` with `expander` and compare the generated file against the snapshot at `path`,
+/// relative to the manifest directory of the calling crate.
+///
+/// ```ignore
+/// assert_expansion_snapshot!(Expander::new("baz"), tokens, "tests/snapshots/baz.rs");
+/// ```
+#[macro_export]
+macro_rules! assert_expansion_snapshot {
+    ($expander:expr, $tokens:expr, $path:literal $(,)?) => {{
+        let (_, content) = $crate::testing::expand_and_read(&$expander, $tokens)
+            .expect("expander: failed to expand");
+        $crate::testing::assert_snapshot(
+            &content,
+            ::std::path::Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/", $path)),
+        );
+    }};
+}
+
+/// Feed the content of an [`Expansion`] into `insta`, named after its filename base.
+///
+/// Snapshots are stored and reviewed with `cargo insta` like any other snapshot of the calling crate.
+///
+/// ```ignore
+/// let expansion = expander::testing::expand(&Expander::new("baz"), tokens)?;
+/// expander::testing::assert_file_snapshot!(expansion);
+/// ```
+#[cfg(feature = "insta")]
+#[macro_export]
+macro_rules! assert_file_snapshot {
+    ($expansion:expr $(,)?) => {{
+        let expansion: &$crate::testing::Expansion = &$expansion;
+        $crate::testing::insta::assert_snapshot!(
+            expansion.filename_base.as_str(),
+            expansion.content.as_str()
+        );
+    }};
+}
+
+pub use crate::assert_expansion_snapshot;
+#[cfg(feature = "insta")]
+pub use crate::assert_file_snapshot;
+#[cfg(feature = "insta")]
+#[doc(hidden)]
+pub use insta;
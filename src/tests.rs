@@ -78,3 +78,2211 @@ fn syn_error_is_not_written_to_external_file() -> Result<(), std::io::Error> {
 
     Ok(())
 }
+
+#[cfg(feature = "pretty")]
+#[test]
+fn merge_appends_each_region_once() -> Result<(), std::io::Error> {
+    let a = quote! {
+        pub struct MergedA;
+    };
+    let b = quote! {
+        pub struct MergedB;
+    };
+    let expander = || Expander::new("merge_test").merge(true);
+    let modified = expander().write_to_out_dir(a.clone())?;
+    expander().write_to_out_dir(b)?;
+    assert_eq!(
+        modified.to_string(),
+        expander().write_to_out_dir(a)?.to_string()
+    );
+
+    let s = modified.to_string();
+    assert!(s.starts_with("crate :: __expander_merged_merge_test :: __expander_region_"));
+    assert!(!s.contains("include !"));
+
+    let merged = std::path::PathBuf::from(env!("OUT_DIR")).join("merge_test-merged.rs");
+    let include = expander().include_merged_out_dir()?.to_string();
+    assert!(include.contains("mod __expander_merged_merge_test"));
+    assert_eq!(include.matches("include !").count(), 1);
+    assert!(include.contains(&format!("{:?}", merged.display().to_string())));
+    let content = fs::read_to_string(merged)?;
+    assert!(content.contains("pub struct MergedA;"));
+    assert!(content.contains("pub struct MergedB;"));
+    assert_eq!(content.matches("pub struct MergedA;").count(), 1);
+    Ok(())
+}
+
+#[test]
+fn aggregate_lists_every_expansion_once() -> Result<(), std::io::Error> {
+    let dest_dir = std::path::PathBuf::from(env!("OUT_DIR")).join("aggregate_test");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+
+    for _ in 0..2 {
+        Expander::new("first")
+            .aggregate(true)
+            .write_to(quote! { pub struct First; }, &dest_dir)?;
+        Expander::new("second")
+            .aggregate(true)
+            .write_to(quote! { pub struct Second; }, &dest_dir)?;
+    }
+
+    let content = fs::read_to_string(aggregate_file(&dest_dir))?;
+    assert_eq!(content.matches("pub mod first_").count(), 1);
+    assert_eq!(content.matches("pub mod second_").count(), 1);
+    assert_eq!(content.matches("#[path = ").count(), 2);
+    Ok(())
+}
+
+#[test]
+fn aggregate_names_colliding_stems_uniquely() -> Result<(), std::io::Error> {
+    let dest_dir = std::path::PathBuf::from(env!("OUT_DIR")).join("aggregate \"quoted\" ß");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+
+    for name in ["foo-bar", "foo_bar"] {
+        Expander::new(name)
+            .stable_path(true)
+            .aggregate(true)
+            .write_to(quote! { pub struct Same; }, &dest_dir)?;
+    }
+
+    let content = fs::read_to_string(aggregate_file(&dest_dir))?;
+    let modules = content
+        .lines()
+        .filter_map(|line| line.strip_prefix("pub mod "))
+        .collect::<Vec<_>>();
+    assert_eq!(modules.len(), 2);
+    assert_ne!(modules[0], modules[1]);
+    assert!(modules.iter().all(|module| module.starts_with("foo_bar_")));
+    // the first entry survived re-parsing of its escaped path
+    assert!(content.contains(&path_literal(&dest_dir.join("foo-bar.rs"))?.to_string()));
+    assert!(content.contains(&path_literal(&dest_dir.join("foo_bar.rs"))?.to_string()));
+    Ok(())
+}
+
+#[test]
+fn include_as_module_declares_path_module() -> Result<(), std::io::Error> {
+    let modified = Expander::new("module")
+        .include_as_module("generated")
+        .write_to_out_dir(quote! { pub struct X; })?;
+
+    let s = modified.to_string();
+    assert!(s.starts_with("# [path = "));
+    assert!(s.ends_with("mod generated ;"));
+    assert!(!s.contains("include !"));
+    Ok(())
+}
+
+#[test]
+fn include_str_and_bytes_reference_verbatim_content() -> Result<(), std::io::Error> {
+    const CONTENT: &str = "SELECT * FROM expansions;";
+    let modified = Expander::new("asset")
+        .add_comment("Not a Rust comment!".to_owned())
+        .extension("sql")
+        .include_str("QUERY")
+        .write_bytes_to_out_dir(CONTENT)?;
+    let s = modified.to_string();
+    assert!(s.starts_with("const QUERY : & str = include_str ! ("));
+    assert!(s.contains(".sql\""));
+
+    let modified = Expander::new("asset")
+        .extension("sql")
+        .include_bytes("QUERY")
+        .write_bytes_to_out_dir(CONTENT)?;
+    let s = modified.to_string();
+    assert!(s.starts_with("const QUERY : & [u8] = include_bytes ! ("));
+
+    let dest = s.split('"').nth(1).expect("Contains a path literal. qed");
+    assert_eq!(fs::read_to_string(dest)?, CONTENT);
+    Ok(())
+}
+
+#[test]
+fn raw_artifact_returns_path_only() -> Result<(), std::io::Error> {
+    const CONTENT: &str = r#"{ "expanded": true }"#;
+    let expected = Expander::new("artifact")
+        .raw_artifact("json")
+        .dry(true)
+        .write_artifact_to_out_dir(CONTENT)?;
+    assert!(!expected.exists() || fs::read_to_string(&expected)? == CONTENT);
+
+    let dest = Expander::new("artifact")
+        .add_comment("Not part of the artifact!".to_owned())
+        .raw_artifact("json")
+        .write_artifact_to_out_dir(CONTENT)?;
+    assert_eq!(dest, expected);
+    assert_eq!(dest.extension(), Some("json".as_ref()));
+    assert_eq!(fs::read_to_string(dest)?, CONTENT);
+    Ok(())
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn metadata_sidecar_describes_expansion() -> Result<(), std::io::Error> {
+    let modified = Expander::new("meta")
+        .metadata(true)
+        .write_to_out_dir(quote! { pub struct Meta; })?;
+
+    let s = modified.to_string();
+    let dest = s.split('"').nth(1).expect("Contains a path literal. qed");
+    let metadata = metadata::Metadata::read(&metadata::metadata_file(Path::new(dest)))?;
+    assert_eq!(metadata.macro_name, "meta");
+    assert_eq!(metadata.input_digest.len(), 64);
+    assert_eq!(metadata.formatter, "prettyplease");
+    assert!(metadata.timestamp > 0);
+    Ok(())
+}
+
+#[test]
+fn manifest_records_and_queries_expansions() -> Result<(), std::io::Error> {
+    let dest_dir = std::path::PathBuf::from(env!("OUT_DIR"));
+    for ts in [quote! { pub struct A; }, quote! { pub struct B; }] {
+        Expander::new("manifest_test")
+            .manifest(true)
+            .write_to(ts, &dest_dir)?;
+    }
+
+    let path = manifest::manifest_file(&dest_dir);
+    assert!(path.starts_with(env!("CARGO_MANIFEST_DIR")));
+    let manifest = manifest::Manifest::read(&path)?;
+    // the manifest is shared with builds of other feature sets, which use a different `OUT_DIR`
+    let entries = manifest
+        .by_filename_base("manifest_test")
+        .filter(|entry| entry.path.starts_with(&dest_dir))
+        .collect::<Vec<_>>();
+    assert_eq!(entries.len(), 2);
+    assert!(entries.iter().all(|entry| entry.size > 0));
+    assert!(manifest.total_size() >= entries.iter().map(|entry| entry.size).sum());
+    Ok(())
+}
+
+#[test]
+fn manifest_rebuilt_and_pruned() -> Result<(), std::io::Error> {
+    let dir = Path::new(env!("OUT_DIR")).join("manifest_pruned");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("expander-manifest.json");
+    fs::write(&path, "{ corrupt")?;
+    let files = [
+        "pruned-0123456789ab.rs",
+        "pruned.rs",
+        "prunedness-0123456789ab.rs",
+    ]
+    .map(|name| dir.join(name));
+    for file in &files {
+        fs::write(file, "pub struct A;")?;
+        manifest::record(&path, file, None)?;
+    }
+    let manifest = manifest::Manifest::read(&path)?;
+    assert_eq!(manifest.entries().len(), 3);
+    assert_eq!(manifest.by_filename_base("pruned").count(), 2);
+
+    fs::remove_file(&files[0])?;
+    manifest::record(&path, &files[1], None)?;
+    let manifest = manifest::Manifest::read(&path)?;
+    assert_eq!(manifest.entries().len(), 2);
+    assert_eq!(manifest.by_filename_base("pruned").count(), 1);
+    Ok(())
+}
+
+#[test]
+fn manifest_superseded_by_later_run() -> Result<(), std::io::Error> {
+    let dir = Path::new(env!("OUT_DIR")).join("manifest_superseded");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("expander-manifest.json");
+    let entry = |name: &str, crate_name: &str, process: u32| {
+        serde_json::json!({
+            "path": dir.join(name),
+            "crate_name": crate_name,
+            "digest": "",
+            "size": 1,
+            "process": process,
+        })
+    };
+    let entries = [
+        entry("gen-0123456789ab.rs", "a", 1),
+        entry("gen-0123456789ac.rs", "b", 1),
+        entry("other-0123456789ab.rs", "a", 1),
+        entry("stable.rs", "a", 1),
+        entry("gen-0123456789ad.rs", "a", 2),
+        entry("gen-0123456789ae.rs", "a", 2),
+    ];
+    fs::write(&path, serde_json::json!({ "entries": entries }).to_string())?;
+    let manifest = manifest::Manifest::read(&path)?;
+    let superseded = manifest
+        .superseded()
+        .map(|entry| entry.path.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(superseded, [dir.join("gen-0123456789ab.rs")]);
+    Ok(())
+}
+
+#[test]
+fn gc_removes_stale_files_of_same_base() -> Result<(), std::io::Error> {
+    let dest_dir = std::path::PathBuf::from(env!("OUT_DIR")).join("gc_test");
+    fs::create_dir_all(&dest_dir)?;
+    let unrelated = Expander::new("gc_other").write_artifact_to("unrelated", &dest_dir)?;
+    let stale = Expander::new("gc")
+        .raw_artifact("rs")
+        .write_artifact_to("stale", &dest_dir)?;
+
+    let kept = Expander::new("gc")
+        .raw_artifact("rs")
+        .gc_older_than(std::time::Duration::from_secs(3600))
+        .write_artifact_to("kept", &dest_dir)?;
+    assert!(stale.exists());
+
+    let current = Expander::new("gc")
+        .raw_artifact("rs")
+        .gc(true)
+        .write_artifact_to("current", &dest_dir)?;
+    assert!(!stale.exists());
+    assert!(!kept.exists());
+    assert!(current.exists());
+    assert!(unrelated.exists());
+    Ok(())
+}
+
+#[test]
+fn history_retains_versions_and_writes_diff() -> Result<(), std::io::Error> {
+    let dest_dir = std::path::PathBuf::from(env!("OUT_DIR")).join("history_test");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+
+    let write = |content: &str| {
+        Expander::new("history")
+            .raw_artifact("rs")
+            .history(2)
+            .diff_to_file(true)
+            .write_artifact_to(content, &dest_dir)
+    };
+    write("fn a() {}\nfn b() {}\nfn c() {}\n")?;
+    write("fn a() {}\nfn b() {}\nfn c() {}\n")?;
+    let changed = write("fn a() {}\nfn B() {}\nfn c() {}\n")?;
+    write("fn a() {}\n")?;
+
+    let mut diff = changed.as_os_str().to_owned();
+    diff.push(".diff");
+    let diff = fs::read_to_string(std::path::PathBuf::from(diff))?;
+    assert!(diff.contains("@@ -1,3 +1,3 @@\n fn a() {}\n-fn b() {}\n+fn B() {}\n fn c() {}\n"));
+    assert_eq!(fs::read_dir(dest_dir.join("history.history"))?.count(), 2);
+    Ok(())
+}
+
+#[test]
+fn unified_diff_groups_hunks() {
+    let old = (0..20)
+        .map(|i| format!("line {:02}\n", i))
+        .collect::<String>();
+    let new = old
+        .replace("line 02\n", "line two\n")
+        .replace("line 17\n", "");
+    let diff = diff::unified(&old, &new, "old", "new");
+    assert!(diff.starts_with("--- old\n+++ new\n@@ -1,6 +1,6 @@\n"));
+    assert!(diff.contains(
+        "@@ -15,6 +15,5 @@\n line 14\n line 15\n line 16\n-line 17\n line 18\n line 19\n"
+    ));
+    assert!(diff::unified(&old, &old, "old", "new").is_empty());
+}
+
+#[test]
+fn check_only_verifies_without_writing() -> Result<(), std::io::Error> {
+    let dest_dir = std::path::PathBuf::from(env!("OUT_DIR")).join("check_only_test");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+
+    let expander = || Expander::new("checked").add_comment("This is generated code!".to_owned());
+    let tokens = quote! { struct Checked; };
+
+    let err = expander()
+        .check_only(true)
+        .write_to(tokens.clone(), &dest_dir)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("is missing"));
+    assert_eq!(fs::read_dir(&dest_dir)?.count(), 0);
+
+    let written = expander().write_to(tokens.clone(), &dest_dir)?;
+    let checked = expander()
+        .check_only(true)
+        .write_to(tokens.clone(), &dest_dir)?;
+    assert_eq!(written.to_string(), checked.to_string());
+
+    let merged = Expander::new("checked").merge(true);
+    assert!(merged
+        .check_only(true)
+        .write_to(tokens.clone(), &dest_dir)
+        .is_err());
+    Expander::new("checked")
+        .merge(true)
+        .write_to(tokens.clone(), &dest_dir)?;
+    Expander::new("checked")
+        .merge(true)
+        .check_only(true)
+        .write_to(tokens, &dest_dir)?;
+    Ok(())
+}
+
+#[test]
+fn write_to_source_only_rewrites_on_change() -> Result<(), std::io::Error> {
+    let path = std::path::PathBuf::from(env!("OUT_DIR"))
+        .join("source_test")
+        .join("generated.rs");
+    let _ = fs::remove_file(&path);
+
+    let tokens = quote! { pub struct Committed; };
+    let check = || Expander::new("committed").check_only(true);
+
+    assert!(check().write_to_source(tokens.clone(), &path).is_err());
+    assert!(Expander::new("committed").write_to_source(tokens.clone(), &path)?);
+    assert!(!Expander::new("committed").write_to_source(tokens.clone(), &path)?);
+    assert!(!check().write_to_source(tokens, &path)?);
+
+    let content = fs::read_to_string(&path)?;
+    assert!(content.starts_with("// @generated by `expander` for `committed`, DO NOT EDIT.\n"));
+    assert!(check()
+        .write_to_source(quote! { pub struct Changed; }, &path)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn testing_reads_back_and_compares_snapshot() -> Result<(), std::io::Error> {
+    let tokens = quote! { pub struct Snapshot { x: [u8; 32] } };
+    let (modified, content) = testing::expand_and_read(
+        &Expander::new("snapshot").add_comment("This is generated code!".to_owned()),
+        tokens.clone(),
+    )?;
+    assert!(modified.to_string().contains("include ! ("));
+    assert!(content.starts_with("/* This is generated code! */\n"));
+    assert!(content.contains("Snapshot"));
+
+    #[cfg(feature = "pretty")]
+    crate::assert_expansion_snapshot!(
+        Expander::new("snapshot"),
+        tokens,
+        "tests/snapshots/snapshot.rs"
+    );
+    Ok(())
+}
+
+#[test]
+fn testing_expands_like_write_to() -> Result<(), std::io::Error> {
+    let tokens = quote! { pub struct Like; };
+    let tee = Expander::new("testing_tee").tee(true);
+    let expansion = testing::expand(&tee, tokens.clone())?;
+    assert_eq!(
+        expansion.tokens.to_string(),
+        tee.write_to_out_dir(tokens.clone())?.to_string()
+    );
+    assert_eq!(expansion.tokens.to_string(), tokens.to_string());
+    assert!(expansion.content.contains("Like"));
+
+    let small = Expander::new("testing_min_size").min_size(1 << 20);
+    let expansion = testing::expand(&small, tokens.clone())?;
+    assert!(expansion.path.is_none());
+    assert_eq!(expansion.tokens.to_string(), tokens.to_string());
+    assert_eq!(expansion.content, tokens.to_string());
+    Ok(())
+}
+
+#[cfg(feature = "insta")]
+#[test]
+fn insta_snapshot_named_after_filename_base() -> Result<(), std::io::Error> {
+    let expansion = testing::expand(
+        &Expander::new("insta_snapshot"),
+        quote! { pub struct Snapshot; },
+    )?;
+    testing::assert_file_snapshot!(expansion);
+    Ok(())
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn golden_compares_expanded_fixtures() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    testing::golden(&dir, &Expander::new("golden"), |input| {
+        quote! {
+            #[derive(Debug, Clone, Copy)]
+            #input
+        }
+    });
+}
+
+#[test]
+fn stable_path_omits_digest_and_redaction() -> Result<(), std::io::Error> {
+    let dest = Expander::new("stable")
+        .raw_artifact("rs")
+        .stable_path(true)
+        .write_artifact_to_out_dir("struct Stable;")?;
+    assert_eq!(
+        dest.file_name().and_then(|name| name.to_str()),
+        Some("stable.rs")
+    );
+
+    let stderr =
+        "--> /out/baz-874698265c6c.rs:2:42\n--> /out/baz-merged.rs\n--> /out/a-b-0123456789ab.rs";
+    assert_eq!(
+        testing::redact_digests(stderr),
+        "--> /out/baz-{hash}.rs:2:42\n--> /out/baz-merged.rs\n--> /out/a-b-{hash}.rs"
+    );
+    Ok(())
+}
+
+#[test]
+fn read_back_rejects_unparseable_file() -> Result<(), std::io::Error> {
+    let expander = || Expander::new("read_back").read_back(true);
+    expander().write_bytes_to_out_dir("pub struct Valid;\n")?;
+
+    let err = expander()
+        .write_bytes_to_out_dir("pub struct Truncated {\n    x: u8,\n")
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let invalid = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<validate::InvalidCode>())
+        .expect("Is invalid code. qed");
+    assert!(invalid.path.is_some());
+    assert!(err.to_string().contains("read_back-"));
+    Ok(())
+}
+
+#[test]
+fn validate_returns_syn_error_before_writing() -> Result<(), std::io::Error> {
+    let broken = quote! { pub struct Broken; ; };
+    let result = Expander::new("validate")
+        .validate(true)
+        .maybe_write_to_out_dir(Ok(broken.clone()))?;
+
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("generated code dumped to "));
+
+    let err = Expander::new("validate")
+        .validate(true)
+        .write_to_out_dir(broken.clone())
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let dump = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<validate::InvalidCode>())
+        .and_then(|invalid| invalid.path.clone())
+        .expect("Invalid code is dumped. qed");
+    assert!(dump.to_string_lossy().ends_with(".invalid.rs"));
+    assert_eq!(fs::read_to_string(dump)?, broken.to_string());
+
+    let modified = Expander::new("validate")
+        .validate(true)
+        .maybe_write_to_out_dir(Ok(quote! { pub struct Valid; }))?
+        .expect("Is valid. qed");
+    assert!(modified.to_string().contains("include ! ("));
+    Ok(())
+}
+
+#[test]
+fn validate_locates_error_within_dump() -> Result<(), std::io::Error> {
+    // the stray `;` is on line 4 of the input, but on the single line of the dump
+    let broken = "pub struct A;\n\npub struct B;\n   ;\n"
+        .parse::<TokenStream>()
+        .expect("Valid tokens. qed");
+    let err = Expander::new("validate_location")
+        .validate(true)
+        .write_to_out_dir(broken)
+        .unwrap_err();
+    let invalid = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<validate::InvalidCode>())
+        .expect("Is invalid code. qed");
+    let dump = fs::read_to_string(invalid.path.as_ref().expect("Is dumped. qed"))?;
+    assert_eq!(dump.lines().count(), 1);
+    assert_eq!(invalid.line, 1);
+    assert_eq!(invalid.column, dump.rfind(';').expect("Contains `;`. qed"));
+    assert!(err
+        .to_string()
+        .contains(&format!(".invalid.rs:1:{}:", invalid.column + 1)));
+    Ok(())
+}
+
+#[test]
+fn rustfmt_error_is_mapped_to_generated_code() {
+    let content = "pub struct A ; ; pub struct B ;";
+    let stderr = "error: expected item, found `;`\n --> <stdin>:1:16\n  |\n";
+    let context = rustfmt_error_context(content, stderr).expect("Location is parsed. qed");
+    assert_eq!(
+        context,
+        "at offset 15 of the generated code, near `;`:\n  pub struct A ; ; pub struct B ;\n                 ^"
+    );
+    assert!(rustfmt_error_context(content, "error: unknown").is_none());
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn span_map_links_lines_to_input_spans() -> Result<(), std::io::Error> {
+    let tokens = "struct A;\n\nfn b() {\n    let x = 1;\n}\n"
+        .parse::<TokenStream>()
+        .expect("Valid tokens. qed");
+    let (dest, _) = Expander::new("spans")
+        .add_comment("This is generated code!".to_owned())
+        .span_map(true)
+        .expand(tokens, std::path::PathBuf::from(env!("OUT_DIR")).as_path())?;
+
+    let map = spans::SpanMap::read(&spans::span_map_file(&dest))?;
+    assert_eq!(map.entries.len(), 2);
+    assert_eq!(
+        (map.entries[0].first_line, map.entries[0].last_line),
+        (2, 2)
+    );
+    let origin = map.origin(4).expect("Line of `fn b`. qed");
+    assert_eq!((origin.start_line, origin.end_line), (3, 5));
+    Ok(())
+}
+
+#[cfg(feature = "proc-macro-error2")]
+#[test]
+fn proc_macro_error2_diagnostic_is_passed_through() -> Result<(), std::io::Error> {
+    use proc_macro_error2::{Diagnostic, Level};
+
+    let diagnostic = Diagnostic::new(Level::Error, "yikes!".to_owned());
+    let result = Expander::new("diagnostic").maybe_write_any_to_out_dir(Err(diagnostic))?;
+    let s = result.unwrap_err().into_compile_error().to_string();
+    assert!(s.contains("compile_error !"));
+    assert!(s.contains("yikes!"));
+
+    let result = Expander::new("diagnostic")
+        .validate(true)
+        .maybe_write_any_to_out_dir(Result::<_, Diagnostic>::Ok(quote! { struct ; }))?;
+    assert!(result.is_err());
+    Ok(())
+}
+
+#[cfg(feature = "darling")]
+#[test]
+fn darling_error_is_written_as_errors() -> Result<(), std::io::Error> {
+    let error = darling::Error::multiple(vec![
+        darling::Error::custom("first"),
+        darling::Error::unknown_field("second"),
+    ]);
+    let result = Expander::new("darling").maybe_write_any_to_out_dir(Err(error))?;
+    let s = result.unwrap_err().into_compile_error().to_string();
+    assert_eq!(s.matches("compile_error !").count(), 2);
+    Ok(())
+}
+
+#[cfg(feature = "manyhow")]
+#[test]
+fn manyhow_errors_are_accumulated() -> Result<(), std::io::Error> {
+    let mut error = manyhow::Error::from(manyhow::error_message!("first"));
+    error.push(manyhow::error_message!("second"));
+    let result = Expander::new("manyhow").maybe_write_any_to_out_dir(Err(error))?;
+    let s = result.unwrap_err().into_compile_error().to_string();
+    assert_eq!(s.matches("compile_error !").count(), 2);
+
+    let fine: manyhow::Result = Ok(quote! { struct Fine; });
+    let written = Expander::new("manyhow")
+        .maybe_write_any_to_out_dir(fine)?
+        .expect("Is not an error. qed");
+    assert!(written.to_string().contains("include ! ("));
+    Ok(())
+}
+
+#[test]
+fn vec_of_syn_errors_is_combined() -> Result<(), std::io::Error> {
+    let errors = vec![
+        syn::Error::new(Span::call_site(), "first"),
+        syn::Error::new(Span::call_site(), "second"),
+    ];
+    let result = Expander::new("errors").maybe_write_any_to_out_dir(Err(errors))?;
+    let s = result.unwrap_err().into_compile_error().to_string();
+    assert_eq!(s.matches("compile_error !").count(), 2);
+    assert!(s.contains("first") && s.contains("second"));
+    assert!(Vec::<syn::Error>::new().into_compile_error().is_empty());
+    Ok(())
+}
+
+#[test]
+fn custom_errors_are_passed_through() -> Result<(), std::io::Error> {
+    #[derive(Debug)]
+    enum Diag {
+        Unsupported(&'static str),
+    }
+
+    impl MacroError for Diag {
+        fn into_compile_error(self) -> TokenStream {
+            let Diag::Unsupported(what) = self;
+            let message = format!("{} is not supported", what);
+            quote! { compile_error!(#message); }
+        }
+    }
+
+    let result =
+        Expander::new("custom").maybe_write_any_to_out_dir(Err(Diag::Unsupported("union")))?;
+    let s = result.unwrap_err().into_compile_error().to_string();
+    assert!(s.contains("union is not supported"));
+
+    let tokens: Result<TokenStream, TokenStream> = Err(quote! { compile_error!("yikes!"); });
+    let result = Expander::new("custom").maybe_write_any_to_out_dir(tokens)?;
+    assert!(result.unwrap_err().to_string().contains("yikes!"));
+    Ok(())
+}
+
+#[test]
+fn write_to_accepts_to_tokens() -> Result<(), std::io::Error> {
+    let item: syn::ItemStruct = syn::parse_quote! {
+        pub struct FromItem {
+            x: u8,
+        }
+    };
+    let (_, content) =
+        testing::expand_and_read(&Expander::new("to_tokens"), item.to_token_stream())?;
+    let modified = Expander::new("to_tokens").write_to_out_dir(&item)?;
+    assert!(modified.to_string().contains("include ! ("));
+    assert!(content.contains("FromItem"));
+    Ok(())
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn write_file_skips_string_round_trip() -> Result<(), std::io::Error> {
+    let file: syn::File = syn::parse_quote! {
+        pub struct FromFile;
+    };
+    let modified = Expander::new("syn_file").write_file_to_out_dir(&file)?;
+    let dest = modified
+        .to_string()
+        .split('"')
+        .nth(1)
+        .map(str::to_owned)
+        .expect("Contains a path literal. qed");
+    assert_eq!(fs::read_to_string(dest)?, "pub struct FromFile;\n");
+    Ok(())
+}
+
+#[test]
+fn configured_expander_is_reusable() -> Result<(), std::io::Error> {
+    let expander = Expander::new("reused").add_comment("This is generated code!".to_owned());
+    let first = expander.write_to_out_dir(quote! { pub struct First; })?;
+    let second = expander.write_to_out_dir(quote! { pub struct Second; })?;
+    assert_ne!(first.to_string(), second.to_string());
+
+    let dry = expander.clone().dry(true);
+    let tokens = quote! { pub struct Third; };
+    assert_eq!(
+        dry.write_to_out_dir(tokens.clone())?.to_string(),
+        tokens.to_string()
+    );
+    assert!(!expander
+        .write_to_out_dir(tokens.clone())?
+        .to_string()
+        .contains("Third"));
+    Ok(())
+}
+
+#[test]
+fn session_numbers_and_combines_outputs() -> Result<(), std::io::Error> {
+    let dest_dir = std::path::PathBuf::from(env!("OUT_DIR")).join("session_test");
+    fs::create_dir_all(&dest_dir)?;
+
+    let mut session = ExpansionSession::with_dest_dir(Expander::new("session"), &dest_dir);
+    let first = session.add(quote! { pub struct First; })?;
+    session.add(quote! { pub struct Second; })?;
+    let names = session
+        .written()
+        .iter()
+        .filter_map(|path| path.file_name()?.to_str().map(str::to_owned))
+        .collect::<Vec<_>>();
+    assert!(names[0].starts_with("session-0-"));
+    assert!(names[1].starts_with("session-1-"));
+
+    let combined = session.finish().to_string();
+    assert!(combined.starts_with(&first.to_string()));
+    assert_eq!(combined.matches("include !").count(), 2);
+
+    // outputs are written like `write_to`
+    let tokens = quote! { pub struct Teed; };
+    let mut session =
+        ExpansionSession::with_dest_dir(Expander::new("session_tee").tee(true), &dest_dir);
+    assert_eq!(session.add(tokens.clone())?.to_string(), tokens.to_string());
+    assert_eq!(session.written().len(), 1);
+    let mut session =
+        ExpansionSession::with_dest_dir(Expander::new("session_small").min_size(1024), &dest_dir);
+    assert_eq!(session.add(tokens.clone())?.to_string(), tokens.to_string());
+    assert!(session.written().is_empty());
+    Ok(())
+}
+
+#[test]
+fn write_failure_becomes_compile_error() {
+    let missing = std::path::PathBuf::from(env!("OUT_DIR")).join("does/not/exist");
+    let s = Expander::new("unwritable")
+        .write_to_or_compile_error(quote! { pub struct X; }, &missing)
+        .to_string();
+    assert!(s.starts_with(":: core :: compile_error ! (\"expander: failed to write `unwritable`: "));
+
+    let s = Expander::new("unwritable")
+        .maybe_write_to_out_dir_or_compile_error(Err(syn::Error::new(Span::call_site(), "yikes!")))
+        .to_string();
+    assert!(s.contains("compile_error") && s.contains("yikes!"));
+}
+
+#[test]
+fn fallback_inline_returns_tokens_on_write_failure() -> Result<(), std::io::Error> {
+    let missing = std::path::PathBuf::from(env!("OUT_DIR")).join("does/not/exist");
+    let tokens = quote! { pub struct Inline; };
+    assert!(Expander::new("fallback")
+        .write_to(tokens.clone(), &missing)
+        .is_err());
+    let inline = Expander::new("fallback")
+        .fallback_inline(true)
+        .write_to(tokens.clone(), &missing)?;
+    assert_eq!(inline.to_string(), tokens.to_string());
+
+    assert!(Expander::new("fallback")
+        .fallback_inline(true)
+        .check_only(true)
+        .write_to(tokens, &missing)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn min_size_returns_small_expansions_inline() -> Result<(), std::io::Error> {
+    let dest = std::path::PathBuf::from(env!("OUT_DIR")).join("min_size");
+    let _ = std::fs::remove_dir_all(&dest);
+    std::fs::create_dir_all(&dest)?;
+
+    let small = quote! { struct S; };
+    let inline = Expander::new("min_size")
+        .min_size(64)
+        .write_to(small.clone(), &dest)?;
+    assert_eq!(inline.to_string(), small.to_string());
+    assert_eq!(std::fs::read_dir(&dest)?.count(), 0);
+
+    let large = quote! { pub struct Large { pub first: u64, pub second: u64, pub third: u64 } };
+    let included = Expander::new("min_size")
+        .min_size(64)
+        .write_to(large, &dest)?;
+    assert!(included.to_string().contains("include"));
+    assert_eq!(std::fs::read_dir(&dest)?.count(), 1);
+    Ok(())
+}
+
+#[test]
+fn dry_driven_by_environment() -> Result<(), std::io::Error> {
+    const VAR: &str = "EXPANDER_TEST_DRY_ENV";
+    let tokens = quote! { struct Env; };
+    std::env::remove_var(VAR);
+    let unset_unless = Expander::new("dry_env")
+        .dry_unless_env(VAR)
+        .write_to_out_dir(tokens.clone())?;
+    assert_eq!(unset_unless.to_string(), tokens.to_string());
+    let unset_if = Expander::new("dry_env")
+        .dry_if_env(VAR)
+        .write_to_out_dir(tokens.clone())?;
+    assert!(unset_if.to_string().contains("include"));
+
+    std::env::set_var(VAR, "1");
+    let set_unless = Expander::new("dry_env")
+        .dry_unless_env(VAR)
+        .write_to_out_dir(tokens.clone())?;
+    std::env::remove_var(VAR);
+    assert!(set_unless.to_string().contains("include"));
+    Ok(())
+}
+
+#[test]
+fn dry_unless_debug_profile() {
+    assert_eq!(
+        profile_of(Path::new("/p/target/release/build/baz-0123/out")),
+        Some("release")
+    );
+    assert_eq!(
+        profile_of(Path::new(
+            "/p/target/x86_64-unknown-linux-gnu/debug/build/baz-0123/out"
+        )),
+        Some("debug")
+    );
+    assert_eq!(profile_of(Path::new("/tmp/out")), None);
+
+    let tokens = quote! { struct Profile; };
+    let expanded = Expander::new("profile")
+        .dry_unless_debug()
+        .write_to_out_dir(tokens.clone())
+        .unwrap();
+    assert_ne!(expanded.to_string(), tokens.to_string());
+}
+
+#[test]
+fn call_site_in_header() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct CallSite; };
+    let expander = Expander::new("call_site")
+        .add_comment("Hello".to_owned())
+        .call_site(true);
+    let (_, content) = testing::expand_and_read(&expander, tokens)?;
+    let mut lines = content.lines();
+    let origin = lines.next().unwrap();
+    assert!(origin.starts_with("/* generated from "), "{}", origin);
+    assert!(origin.ends_with(" by `call_site` */"), "{}", origin);
+    assert_eq!(lines.next(), Some("/* Hello */"));
+    Ok(())
+}
+
+#[test]
+fn structured_header() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct Structured; };
+    let expander = Expander::new("structured")
+        .header(
+            Header::new()
+                .macro_name(true)
+                .input_digest(true)
+                .expander_version(true)
+                .message("Hello"),
+        )
+        .add_comment("World".to_owned());
+    let (_, content) = testing::expand_and_read(&expander, tokens.clone())?;
+    let expected = format!(
+        "// expander-macro: structured\n// expander-input-digest: {}\n// expander-version: {}\n/* Hello */\n/* World */\n",
+        digest_hex(tokens.to_string().as_bytes()),
+        env!("CARGO_PKG_VERSION"),
+    );
+    assert!(content.starts_with(&expected), "{}", content);
+    Ok(())
+}
+
+#[test]
+fn reproducible_output() -> Result<(), std::io::Error> {
+    assert_eq!(
+        normalize_line_endings(b"a\r\nb\rc\n".to_vec()),
+        b"a\nb\rc\n".to_vec()
+    );
+    assert_eq!(
+        relative_path(
+            Path::new("/ws/crates/foo"),
+            Path::new("/ws/target/debug/build/x/out/baz-0.rs")
+        ),
+        Some("../../target/debug/build/x/out/baz-0.rs".to_owned())
+    );
+    assert_eq!(relative_path(Path::new("/ws"), Path::new("rel")), None);
+
+    let tokens = quote! { struct Reproducible; };
+    let expansion = testing::expand(&Expander::new("reproducible"), tokens.clone())?;
+    let expanded = Expander::new("reproducible")
+        .reproducible(true)
+        .write_to_out_dir(tokens)?;
+    let relative = relative_path(
+        Path::new(env!("CARGO_MANIFEST_DIR")),
+        expansion.path.as_deref().unwrap(),
+    )
+    .unwrap();
+    let relative = format!("/{}", relative);
+    assert_eq!(
+        expanded.to_string(),
+        quote! { include!(concat!(env!("CARGO_MANIFEST_DIR"), #relative)); }.to_string()
+    );
+    Ok(())
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn line_endings() -> Result<(), std::io::Error> {
+    assert_eq!(LineEnding::CrLf.apply(b"a\r\nb\n".to_vec()), b"a\r\nb\r\n");
+    assert_eq!(LineEnding::Lf.apply(b"a\r\nb\n".to_vec()), b"a\nb\n");
+
+    let tokens = quote! { struct Crlf; };
+    let lf = testing::expand(&Expander::new("line_ending"), tokens.clone())?;
+    let crlf = testing::expand(
+        &Expander::new("line_ending")
+            .add_comment("CrLf".to_owned())
+            .line_ending(LineEnding::CrLf),
+        tokens,
+    )?;
+    assert_ne!(lf.path, crlf.path);
+    let content = std::fs::read_to_string(crlf.path.unwrap())?;
+    assert!(content.starts_with("/* CrLf */\r\n"));
+    assert_eq!(
+        content.matches('\n').count(),
+        content.matches("\r\n").count()
+    );
+    Ok(())
+}
+
+#[test]
+fn license_banner() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct Licensed; };
+    let (_, spdx) = testing::expand_and_read(
+        &Expander::new("license")
+            .spdx("MIT OR Apache-2.0")
+            .add_comment("Hello".to_owned()),
+        tokens.clone(),
+    )?;
+    assert!(spdx.starts_with("// SPDX-License-Identifier: MIT OR Apache-2.0\n/* Hello */\n"));
+
+    let (_, banner) = testing::expand_and_read(
+        &Expander::new("license").header_file("tests/license-header.txt"),
+        tokens.clone(),
+    )?;
+    assert!(banner.starts_with(&std::fs::read_to_string("tests/license-header.txt")?));
+
+    assert!(Expander::new("license")
+        .header_file("does-not-exist")
+        .write_to_out_dir(tokens)
+        .is_err());
+    Ok(())
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn comment_sanitization_and_style() -> Result<(), std::io::Error> {
+    assert_eq!(
+        CommentStyle::Block.render("closes */ early /* opens"),
+        "/* closes * / early / * opens */\n"
+    );
+    assert_eq!(
+        CommentStyle::Line.render("first\n\nthird */"),
+        "// first\n//\n// third */\n"
+    );
+
+    let tokens = quote! { struct Commented; };
+    let (_, content) = testing::expand_and_read(
+        &Expander::new("comment_style")
+            .add_comment("Line\ncomment".to_owned())
+            .comment_style(CommentStyle::Line),
+        tokens,
+    )?;
+    assert!(content.starts_with("// Line\n// comment\nstruct Commented;"));
+    Ok(())
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn inner_attrs_prepended() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct Unused; };
+    let expander = Expander::new("inner_attrs")
+        .inner_attrs([quote!(allow(dead_code)), quote!(allow(clippy::all))]);
+    assert_eq!(
+        expander
+            .write_to_out_dir(tokens.clone())
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+
+    let (_, content) = testing::expand_and_read(&expander.include_as_module("generated"), tokens)?;
+    assert!(content.starts_with("#![allow(dead_code)]\n#![allow(clippy::all)]\n"));
+    Ok(())
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn outer_attrs_on_every_item() -> Result<(), std::io::Error> {
+    let tokens = quote! {
+        #[derive(Debug)]
+        struct First;
+        impl First {}
+    };
+    let (_, content) = testing::expand_and_read(
+        &Expander::new("outer_attrs")
+            .outer_attrs([quote!(automatically_derived), quote!(doc(hidden))]),
+        tokens,
+    )?;
+    assert_eq!(
+        content,
+        "#[automatically_derived]\n#[doc(hidden)]\n#[derive(Debug)]\nstruct First;\n#[automatically_derived]\n#[doc(hidden)]\nimpl First {}\n"
+    );
+    Ok(())
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn wrapped_in_module() -> Result<(), std::io::Error> {
+    let tokens = quote! { pub struct Wrapped; };
+    let (_, content) = testing::expand_and_read(
+        &Expander::new("wrap")
+            .inner_attrs([quote!(allow(dead_code))])
+            .wrap_in_module("__wrap_generated"),
+        tokens.clone(),
+    )?;
+    assert_eq!(
+        content,
+        "pub(crate) mod __wrap_generated {\n    #![allow(dead_code)]\n    pub struct Wrapped;\n}\n"
+    );
+
+    let (_, content) = testing::expand_and_read(
+        &Expander::new("wrap").wrap_in_module_with("generated", quote!(pub), [quote!(doc(hidden))]),
+        tokens,
+    )?;
+    assert!(content.starts_with("#[doc(hidden)]\npub mod generated {\n"));
+    Ok(())
+}
+
+#[test]
+fn cfg_gated_include() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct Gated; };
+    let expansion = testing::expand(&Expander::new("cfg").cfg(quote!(test)), tokens.clone())?;
+    let path = expansion.path.unwrap().display().to_string();
+    assert_eq!(
+        expansion.tokens.to_string(),
+        quote! { #[cfg(test)] include!(#path); }.to_string()
+    );
+
+    let merged = Expander::new("cfg_merged")
+        .merge(true)
+        .cfg(quote!(feature = "codegen"))
+        .write_to_out_dir(tokens)?
+        .to_string();
+    assert_eq!(merged.matches("# [cfg (feature = \"codegen\")]").count(), 1);
+    Ok(())
+}
+
+#[test]
+fn configurable_digest_len() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct Digest; };
+    for (len, expected) in [(16, 16), (64, 64), (2, 8), (100, 64)] {
+        let expansion =
+            testing::expand(&Expander::new("digest_len").digest_len(len), tokens.clone())?;
+        let path = expansion.path.unwrap();
+        let stem = path.file_stem().unwrap().to_str().unwrap();
+        let digest = stem.strip_prefix("digest_len-").unwrap();
+        assert_eq!(digest.len(), expected);
+        assert!(is_digest(digest));
+        assert_eq!(
+            testing::redact_digests(&path.display().to_string()),
+            format!("{}/digest_len-{{hash}}.rs", env!("OUT_DIR"))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn xxh3_digest() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct Fast; };
+    let blake2s = testing::expand(&Expander::new("hasher"), tokens.clone())?;
+    let xxh3 = testing::expand(
+        &Expander::new("hasher").hasher(Hasher::Xxh3).digest_len(64),
+        tokens,
+    )?;
+    assert_ne!(blake2s.path, xxh3.path);
+    let path = xxh3.path.unwrap();
+    let stem = path.file_stem().unwrap().to_str().unwrap();
+    assert_eq!(stem.strip_prefix("hasher-").unwrap().len(), 32);
+    Ok(())
+}
+
+#[test]
+fn sip_digest() {
+    let mut digester = Hasher::Sip.digester(b"salt");
+    digester.update(b"struct ");
+    digester.update(b"Minimal;");
+    let suffix = Hasher::Sip.suffix(b"salt", b"struct Minimal;", 64);
+    assert_eq!(digester.suffix(64), suffix);
+    assert_eq!(suffix.len(), Hasher::Sip.max_len());
+    assert_ne!(suffix, Hasher::Sip.suffix(b"", b"struct Minimal;", 64));
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn digest_of_input_tokens() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct Input; };
+    let formatted = testing::expand(&Expander::new("hash_input"), tokens.clone())?;
+    let input = testing::expand(
+        &Expander::new("hash_input").hash_input(true),
+        tokens.clone(),
+    )?;
+    assert_ne!(formatted.path, input.path);
+    assert_eq!(
+        input.path.unwrap(),
+        destination(
+            tokens.to_string().as_bytes(),
+            &Path::new(env!("OUT_DIR")).join("hash_input"),
+            "rs",
+            Hasher::Blake2s,
+            DEFAULT_DIGEST_LEN,
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn salted_digest() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct Salted; };
+    let plain = testing::expand(&Expander::new("salt"), tokens.clone())?;
+    let salted = testing::expand(&Expander::new("salt").salt("feature-a"), tokens.clone())?;
+    let other = testing::expand(&Expander::new("salt").salt("feature-b"), tokens.clone())?;
+    let env = testing::expand(
+        &Expander::new("salt").salt_env(["CARGO_PKG_NAME", "CARGO_PKG_VERSION"]),
+        tokens,
+    )?;
+    assert_ne!(plain.path, salted.path);
+    assert_ne!(salted.path, other.path);
+    assert_ne!(plain.path, env.path);
+    assert_eq!(plain.content, salted.content);
+    Ok(())
+}
+
+#[test]
+fn per_crate_subdirectory() -> Result<(), std::io::Error> {
+    let expansion = testing::expand(
+        &Expander::new("per_crate").per_crate_dir(true),
+        quote! { struct PerCrate; },
+    )?;
+    let dir = expansion.path.unwrap().parent().unwrap().to_owned();
+    assert_eq!(dir.parent(), Some(Path::new(env!("OUT_DIR"))));
+    let name = dir.file_name().unwrap().to_str().unwrap();
+    let digest = name.strip_prefix("expander-").unwrap();
+    assert!(is_digest(digest));
+    Ok(())
+}
+
+#[test]
+fn per_macro_subdirectory() -> Result<(), std::io::Error> {
+    let expansion = testing::expand(
+        &Expander::new("per_macro").per_macro_dir(true),
+        quote! { struct PerMacro; },
+    )?;
+    assert_eq!(
+        expansion.path.unwrap().parent(),
+        Some(Path::new(env!("OUT_DIR")).join("per_macro").as_path())
+    );
+    Ok(())
+}
+
+#[test]
+fn sharded_by_digest() -> Result<(), std::io::Error> {
+    let dest = std::path::PathBuf::from(env!("OUT_DIR")).join("sharded");
+    let _ = std::fs::remove_dir_all(&dest);
+    std::fs::create_dir_all(&dest)?;
+
+    let expander = Expander::new("shard").shard(true).gc(true);
+    let first = testing::expand_to(&expander, quote! { struct First; }, &dest)?;
+    let first = first.path.unwrap();
+    let shard = first.parent().unwrap();
+    assert_eq!(shard.parent(), Some(dest.as_path()));
+    let digest = first.file_stem().unwrap().to_str().unwrap();
+    assert_eq!(
+        shard.file_name().unwrap().to_str(),
+        digest.strip_prefix("shard-").map(|digest| &digest[..2])
+    );
+
+    let second = testing::expand_to(&expander, quote! { struct Second; }, &dest)?;
+    assert!(second.path.unwrap().exists());
+    assert!(!first.exists());
+    Ok(())
+}
+
+#[test]
+fn paths_fit_max_path() {
+    let dir = std::path::PathBuf::from("/target/out");
+    let dest = dir.join(format!("{}-0123456789ab.rs", "long_base".repeat(4)));
+    assert_eq!(fit_path(dest.clone(), 200).unwrap(), dest);
+
+    let shortened = fit_path(dest.clone(), 60).unwrap();
+    assert_eq!(shortened, dir.join("long_baselong_baselo-0123456789ab.rs"));
+    assert_eq!(shortened.as_os_str().len(), 60 - 12);
+
+    let error = fit_path(dest, 30).unwrap_err();
+    assert!(error.to_string().contains("exceeds"));
+}
+
+#[test]
+fn include_paths_as_literals() {
+    assert_eq!(
+        forward_slashes(r"C:\target\debug\build\out\baz-0123.rs"),
+        "C:/target/debug/build/out/baz-0123.rs"
+    );
+    assert_eq!(
+        forward_slashes(r"\\?\C:\target\baz-0123.rs"),
+        "C:/target/baz-0123.rs"
+    );
+    assert_eq!(
+        forward_slashes(r"\\?\UNC\server\share\baz-0123.rs"),
+        "//server/share/baz-0123.rs"
+    );
+    let long = format!(r"\\?\C:\{}\baz-0123.rs", "nested".repeat(50));
+    assert_eq!(forward_slashes(&long), long);
+    assert_eq!(strip_verbatim(r"\\?\Volume{0}\baz.rs"), None);
+    assert_eq!(
+        path_literal(Path::new("/out/\"quoted\"/baz.rs"))
+            .unwrap()
+            .to_string(),
+        r#""/out/\"quoted\"/baz.rs""#
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_paths_are_rejected() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dest = Path::new(std::ffi::OsStr::from_bytes(b"/out/\xff/baz.rs"));
+    let error = path_literal(dest).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    let unrepresentable = error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<UnrepresentablePath>())
+        .unwrap();
+    assert_eq!(unrepresentable.path, dest);
+    assert!(error.to_string().contains(OUT_DIR));
+    assert!(Emit::Include.reference(dest, Anchor::Absolute).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn read_only_output() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dest_dir = std::path::PathBuf::from(env!("OUT_DIR")).join("read_only");
+    fs::create_dir_all(&dest_dir)?;
+    let expander = Expander::new("read_only")
+        .fmt(Edition::_2021)
+        .read_only(true);
+    let (path, _) = expander.expand(quote! { struct A; }, &dest_dir)?;
+    assert!(fs::metadata(&path)?.permissions().readonly());
+    let content = fs::read_to_string(&path)?;
+    assert_eq!(expander.expand(quote! { struct A; }, &dest_dir)?.0, path);
+
+    // stale content is replaced despite the permissions
+    fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+    fs::write(&path, "stale")?;
+    fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444))?;
+    expander.expand(quote! { struct A; }, &dest_dir)?;
+    assert_eq!(fs::read_to_string(&path)?, content);
+    assert!(fs::metadata(&path)?.permissions().readonly());
+
+    let expander = Expander::new("file_mode")
+        .fmt(Edition::_2021)
+        .file_mode(0o440);
+    let (path, _) = expander.expand(quote! { struct B; }, &dest_dir)?;
+    assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o440);
+    Ok(())
+}
+
+#[test]
+fn share_mode_flags() -> Result<(), std::io::Error> {
+    assert_eq!(ShareMode::default().flags(), 0x7);
+    assert_eq!(ShareMode::Read.flags(), 0x1);
+    assert_eq!(ShareMode::Exclusive.flags(), 0);
+
+    let (path, _) = Expander::new("share_mode")
+        .share_mode(ShareMode::Exclusive)
+        .expand(quote! { struct A; }, Path::new(env!("OUT_DIR")))?;
+    assert!(path.exists());
+    Ok(())
+}
+
+#[test]
+fn synced_output() -> Result<(), std::io::Error> {
+    let (path, _) = Expander::new("sync")
+        .sync(true)
+        .expand(quote! { struct A; }, Path::new(env!("OUT_DIR")))?;
+    assert!(fs::read_to_string(path)?.starts_with("struct A"));
+    Ok(())
+}
+
+#[test]
+fn latest_alias() -> Result<(), std::io::Error> {
+    let dest_dir = std::path::PathBuf::from(env!("OUT_DIR")).join("latest");
+    fs::create_dir_all(&dest_dir)?;
+    let expander = Expander::new("latest").latest(true);
+    let latest = dest_dir.join("latest-latest.rs");
+
+    let (first, _) = expander.expand(quote! { struct A; }, &dest_dir)?;
+    assert_eq!(fs::read(&latest)?, fs::read(&first)?);
+    let (second, _) = expander.expand(quote! { struct B; }, &dest_dir)?;
+    assert_ne!(first, second);
+    assert_eq!(fs::read(&latest)?, fs::read(&second)?);
+    Ok(())
+}
+
+#[test]
+fn default_dirs_fall_back() -> Result<(), std::io::Error> {
+    let candidates = DefaultDir::candidates();
+    assert!(matches!(candidates.last(), Some(DefaultDir::TempDir(_))));
+
+    let (dir, tokens) =
+        Expander::new("default_dirs").write_to_default_dirs(quote! { struct A; })?;
+    assert!(tokens
+        .to_string()
+        .contains(&dir.path().join("default_dirs-").display().to_string()));
+    Ok(())
+}
+
+#[test]
+fn written_to_manifest_dir() -> Result<(), std::io::Error> {
+    let expander = Expander::new("manifest_dir");
+    let error = expander
+        .write_to_manifest_dir(quote! { struct A; }, Path::new("/absolute"))
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+    let out_dir = relative_path(
+        Path::new(env!("CARGO_MANIFEST_DIR")),
+        Path::new(env!("OUT_DIR")),
+    )
+    .unwrap();
+    let relative = Path::new(&out_dir).join("manifest_dir_test");
+    let tokens = expander
+        .write_to_manifest_dir(quote! { struct A; }, &relative)?
+        .to_string();
+    assert!(tokens.starts_with(r#"include ! (concat ! (env ! ("CARGO_MANIFEST_DIR") , "/"#));
+    assert!(tokens.contains("/manifest_dir_test/manifest_dir-"));
+
+    let missing: std::io::Error = MissingEnv {
+        var: "CARGO_MANIFEST_DIR".to_owned(),
+    }
+    .into();
+    assert_eq!(missing.kind(), std::io::ErrorKind::NotFound);
+    assert!(missing
+        .to_string()
+        .contains("`CARGO_MANIFEST_DIR` is not set"));
+    Ok(())
+}
+
+#[test]
+fn build_script_includes_relative_to_out_dir() -> Result<(), std::io::Error> {
+    // as set by cargo for build scripts, identical to the value at compile time
+    std::env::set_var("OUT_DIR", env!("OUT_DIR"));
+    let expander = Expander::new("build_script").for_build_script();
+    assert_eq!(expander.out_dir(), Path::new(env!("OUT_DIR")));
+
+    let tokens = expander.write_to_out_dir(quote! { struct A; })?.to_string();
+    assert!(tokens.starts_with(r#"include ! (concat ! (env ! ("OUT_DIR") , "/build_script-"#));
+    Ok(())
+}
+
+#[test]
+fn relocatable_includes() -> Result<(), std::io::Error> {
+    let dest = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/out/baz-0123.rs");
+    let tokens = relocatable_path(&dest, &["EXPANDER_UNSET_VAR", "CARGO_MANIFEST_DIR"])?;
+    assert_eq!(
+        tokens.to_string(),
+        r#"concat ! (env ! ("CARGO_MANIFEST_DIR") , "/target/out/baz-0123.rs")"#
+    );
+    let tokens = relocatable_path(&dest, &["EXPANDER_UNSET_VAR"])?;
+    assert_eq!(
+        tokens.to_string(),
+        format!("{:?}", dest.display().to_string())
+    );
+
+    let tokens = Expander::new("relocatable")
+        .relocatable(true)
+        .write_to(quote! { struct A; }, Path::new(env!("OUT_DIR")))?;
+    assert!(tokens.to_string().contains("concat ! (env ! ("));
+    Ok(())
+}
+
+#[test]
+fn remapped_path_prefixes() -> Result<(), std::io::Error> {
+    let flags = [
+        "-Cdebuginfo=2",
+        "--remap-path-prefix",
+        "/home/me=~",
+        "--remap-path-prefix=/a=b=/c",
+    ]
+    .map(String::from);
+    assert_eq!(
+        remap_flags(&flags),
+        vec![
+            ("/home/me".to_owned(), "~".to_owned()),
+            ("/a=b".to_owned(), "/c".to_owned()),
+        ]
+    );
+
+    let expander = Expander::new("remapped")
+        .add_comment(format!("from {}/src/lib.rs", env!("CARGO_MANIFEST_DIR")))
+        .remap_path_prefix(env!("CARGO_MANIFEST_DIR"), "/remapped");
+    let (path, tokens) = expander.expand(quote! { struct A; }, Path::new(env!("OUT_DIR")))?;
+    assert!(fs::read_to_string(path)?.starts_with("/* from /remapped/src/lib.rs */"));
+    assert!(!tokens.to_string().contains(env!("CARGO_MANIFEST_DIR")));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn sandboxed_destinations() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    assert!(sandbox(Path::new("/nix/store/abc-foo/out")).is_some());
+    assert!(sandbox(Path::new("/tmp/sandbox/1/execroot/_main/bazel-out")).is_some());
+    assert_eq!(sandbox(Path::new(env!("OUT_DIR"))), None);
+
+    let read_only = std::path::PathBuf::from(env!("OUT_DIR")).join("sandboxed");
+    fs::create_dir_all(&read_only)?;
+    fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o555))?;
+    assert_eq!(sandbox(&read_only), Some("a read-only directory"));
+    let tokens = Expander::new("sandboxed").write_to(quote! { struct A; }, &read_only)?;
+    let fallback = std::env::temp_dir().join("expander");
+    assert!(tokens.to_string().contains(&fallback.display().to_string()));
+
+    let error = Expander::new("sandboxed")
+        .sandbox_fallback(false)
+        .write_to(quote! { struct A; }, Path::new("/nix/store/does/not/exist"))
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[test]
+fn rerun_directives_for_build_scripts() {
+    let expander = Expander::new("rerun")
+        .reproducible(true)
+        .dry_unless_env("EXPANDER_TEST_RERUN");
+    assert_eq!(
+        expander.rerun_directives(["/out/rerun-0123.rs"]),
+        vec![
+            "cargo:rerun-if-changed=/out/rerun-0123.rs",
+            "cargo:rerun-if-env-changed=EXPANDER_STABLE_PATHS",
+            "cargo:rerun-if-env-changed=EXPANDER_OUT_DIR",
+            "cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH",
+            "cargo:rerun-if-env-changed=EXPANDER_TEST_RERUN",
+        ]
+    );
+}
+
+#[test]
+fn deduplicated_store() -> Result<(), std::io::Error> {
+    let dest_dir = std::path::PathBuf::from(env!("OUT_DIR")).join("dedup");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+    let store = dest_dir.join("store");
+
+    let (first, _) = Expander::new("first")
+        .dedup(true)
+        .expand(quote! { struct A; }, &dest_dir)?;
+    let (second, _) = Expander::new("second")
+        .dedup(true)
+        .expand(quote! { struct A; }, &dest_dir)?;
+    assert_eq!(first, second);
+    assert_eq!(first.parent(), Some(store.as_path()));
+
+    // still referenced by `second`
+    Expander::new("first")
+        .dedup(true)
+        .expand(quote! { struct B; }, &dest_dir)?;
+    assert!(first.exists());
+    Expander::new("second")
+        .dedup(true)
+        .expand(quote! { struct B; }, &dest_dir)?;
+    assert!(!first.exists());
+    Ok(())
+}
+
+#[test]
+fn workspace_cache() -> Result<(), std::io::Error> {
+    let cache = cache::Cache::locate(Path::new(env!("OUT_DIR"))).unwrap();
+    let token_str = format!("struct Cached{:?};", std::time::SystemTime::now());
+    let mut formatted = 0;
+    for _ in 0..2 {
+        let (bytes, formatter) = cache.get_or_format(&token_str, &RustFmt::No, None, || {
+            formatted += 1;
+            Ok((b"struct Cached;\n".to_vec(), Formatter::PrettyPlease))
+        })?;
+        assert_eq!(bytes, b"struct Cached;\n");
+        assert_eq!(formatter, Formatter::PrettyPlease);
+    }
+    assert_eq!(formatted, 1);
+
+    let (path, _) = Expander::new("cached")
+        .cache(true)
+        .expand(quote! { struct A; }, Path::new(env!("OUT_DIR")))?;
+    assert!(fs::read_to_string(path)?.starts_with("struct A"));
+    Ok(())
+}
+
+#[test]
+fn streamed_tokens() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct A; struct B; };
+    let expander = Expander::new("streamed").add_comment("streamed".to_owned());
+    assert_eq!(expander.streams(), !cfg!(feature = "pretty"));
+    let (path, _) = expander.write_streamed(&tokens, Path::new(env!("OUT_DIR")))?;
+    let content = fs::read_to_string(&path)?;
+    assert_eq!(content, format!("/* streamed */\n{}", tokens));
+    // same destination as if collected first
+    let dest = Path::new(env!("OUT_DIR")).join("streamed");
+    assert_eq!(
+        path,
+        expander.destination(tokens.to_string().as_bytes(), &dest)
+    );
+    let partial = fs::read_dir(env!("OUT_DIR"))?
+        .filter_map(Result::ok)
+        .any(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(".streamed.")
+        });
+    assert!(!partial);
+    Ok(())
+}
+
+#[test]
+fn exceeded_fmt_budget_warns() -> Result<(), std::io::Error> {
+    let dest_dir = Path::new(env!("OUT_DIR")).join("fmt_budget");
+    fs::create_dir_all(&dest_dir)?;
+    let expander = Expander::new("fmt_budget")
+        .fmt(Edition::_2021)
+        .fmt_budget(std::time::Duration::ZERO);
+    let (written, messages) =
+        diagnostics::capture(|| expander.expand(quote! { struct Budget; }, &dest_dir));
+    let dest = written?.0;
+    assert!(fs::read_to_string(&dest)?.contains("struct Budget;"));
+    let formatted = dest_dir.join("fmt_budget");
+    assert!(messages.iter().any(|message| {
+        message.starts_with("expander warn: ")
+            && message.contains(&format!("formatting {} with", formatted.display()))
+            && message.contains("exceeding the budget of 0ns")
+    }));
+    Ok(())
+}
+
+#[test]
+fn streamed_changes_are_summarized() -> Result<(), std::io::Error> {
+    let dest_dir = Path::new(env!("OUT_DIR")).join("streamed_changes");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+    let expander = Expander::new("streamed").verbose(true);
+    expander.write_streamed(&quote! { struct A; }, &dest_dir)?;
+    let (written, messages) =
+        diagnostics::capture(|| expander.write_streamed(&quote! { struct B; }, &dest_dir));
+    let (dest, _) = written?;
+    assert!(messages
+        .iter()
+        .any(|message| message.contains(&format!("{} differs from", dest.display()))));
+    Ok(())
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn itemwise_formatting() {
+    let tokens = quote! {
+        #![allow(dead_code)]
+        /// Documented.
+        #[derive(Debug)]
+        pub struct A { a: u32 }
+        impl A { fn get(&self) -> u32 { self.a } }
+        mod inner { use super::A; pub const X: [u8; 2] = [0; 2]; }
+        macro_rules! m { ($x:expr) => { $x }; }
+        fn f() -> u32 { m!(1) }
+    };
+    let whole = prettyplease::unparse(&syn::parse2(tokens.clone()).unwrap());
+    assert_eq!(unparse_items(tokens, false).unwrap(), whole);
+    assert_eq!(
+        unparse_items(quote! { #![allow(dead_code)] }, false).unwrap(),
+        prettyplease::unparse(&syn::parse_quote! { #![allow(dead_code)] })
+    );
+    assert!(unparse_items(quote! { struct A; fn }, false).is_err());
+}
+
+#[test]
+fn memoized_expansion() -> Result<(), std::io::Error> {
+    let dest_dir = Path::new(env!("OUT_DIR")).join("memoized");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+    let tokens = quote! { struct Memoized; };
+    let expander = Expander::new("memoized").memoize(true);
+    let (path, first) = expander.expand(tokens.clone(), &dest_dir)?;
+    // neither formatted nor written again
+    fs::write(&path, "// untouched")?;
+    let (again, second) = expander.expand(tokens.clone(), &dest_dir)?;
+    assert_eq!(again, path);
+    assert_eq!(first.to_string(), second.to_string());
+    assert_eq!(fs::read_to_string(&path)?, "// untouched");
+    // as is output of no consequence to the file
+    let (quiet, _) = expander
+        .clone()
+        .quiet(true)
+        .expand(tokens.clone(), &dest_dir)?;
+    assert_eq!(quiet, path);
+    assert_eq!(fs::read_to_string(&path)?, "// untouched");
+    // a different configuration is a different input
+    let (commented, _) = expander
+        .clone()
+        .add_comment("memoized".to_owned())
+        .expand(tokens.clone(), &dest_dir)?;
+    assert_eq!(commented, path);
+    assert!(fs::read_to_string(&path)?.starts_with("/* memoized */"));
+    // so is an edited banner behind an unchanged path
+    let banner = dest_dir.join("banner.txt");
+    let bannered = expander.clone().header_file(&banner);
+    fs::write(&banner, "// first\n")?;
+    bannered.expand(tokens.clone(), &dest_dir)?;
+    fs::write(&banner, "// second\n")?;
+    bannered.expand(tokens.clone(), &dest_dir)?;
+    assert!(fs::read_to_string(&path)?.starts_with("// second\n"));
+    // removed files are written again
+    fs::remove_file(&path)?;
+    let (again, _) = expander.expand(tokens, &dest_dir)?;
+    assert_eq!(again, path);
+    assert!(fs::read_to_string(&path)?.contains("Memoized"));
+    Ok(())
+}
+
+#[test]
+fn memoized_expansion_is_registered() -> Result<(), std::io::Error> {
+    let dest_dir = Path::new(env!("OUT_DIR")).join("memoized_registered");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+    let tokens = quote! { struct Registered; };
+    let expander = Expander::new("registered").memoize(true).aggregate(true);
+    let (path, _) = expander.expand(tokens.clone(), &dest_dir)?;
+    fs::remove_file(aggregate_file(&dest_dir))?;
+    fs::write(&path, "// untouched")?;
+    let (again, _) = expander.expand(tokens, &dest_dir)?;
+    assert_eq!(again, path);
+    assert_eq!(fs::read_to_string(&path)?, "// untouched");
+    let aggregate = fs::read_to_string(aggregate_file(&dest_dir))?;
+    assert!(aggregate.contains(&*path.file_name().unwrap().to_string_lossy()));
+    Ok(())
+}
+
+#[test]
+fn written_asynchronously() -> Result<(), std::io::Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let dest_dir = Path::new(env!("OUT_DIR"));
+    let tokens = quote! { struct Async { a: u8 } };
+    for expander in [
+        Expander::new("async").add_comment("async".to_owned()),
+        Expander::new("async").fmt(Edition::_2021).hash_input(true),
+    ] {
+        let expanded = runtime.block_on(expander.write_to_async(tokens.clone(), dest_dir))?;
+        let blocking = expander.write_to(tokens.clone(), dest_dir)?;
+        assert_eq!(expanded.to_string(), blocking.to_string());
+    }
+    let partial = fs::read_dir(dest_dir)?
+        .filter_map(Result::ok)
+        .any(|entry| entry.file_name().to_string_lossy().starts_with(".async"));
+    assert!(!partial);
+    Ok(())
+}
+
+#[test]
+fn after_write_hook() -> Result<(), std::io::Error> {
+    let written = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let record = written.clone();
+    let expander = Expander::new("hooked")
+        .after_write(move |path| {
+            record
+                .lock()
+                .unwrap()
+                .push((path.to_owned(), fs::read_to_string(path)?));
+            Ok(())
+        })
+        .after_write(|_| Err(std::io::Error::new(std::io::ErrorKind::Other, "rejected")));
+    let err = expander
+        .expand(quote! { struct Hooked; }, Path::new(env!("OUT_DIR")))
+        .unwrap_err();
+    assert_eq!(err.to_string(), "rejected");
+    let written = written.lock().unwrap();
+    assert_eq!(written.len(), 1);
+    assert!(written[0].0.exists());
+    assert!(written[0].1.contains("struct Hooked"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn post_commands() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct Post; };
+    let (path, _) = Expander::new("post")
+        .post_command("sh", ["-c", "echo '// linted' >> \"$0\"", "{file}"])
+        .expand(tokens.clone(), Path::new(env!("OUT_DIR")))?;
+    assert!(fs::read_to_string(&path)?.ends_with("// linted\n"));
+
+    let failing = Expander::new("post").post_command("sh", ["-c", "echo no >&2; exit 3"]);
+    let err = failing
+        .expand(tokens.clone(), Path::new(env!("OUT_DIR")))
+        .unwrap_err();
+    assert!(err.to_string().contains("exit code 3\nstderr: no"));
+    let missing =
+        Expander::new("post").post_command_full("expander-missing-linter", ["{file}"], true);
+    missing.expand(tokens, Path::new(env!("OUT_DIR")))?;
+    Ok(())
+}
+
+#[test]
+fn transform_pipeline() -> Result<(), std::io::Error> {
+    let tokens = quote! { struct Transformed; };
+    let rename = |stage| match stage {
+        Stage::Tokens(_) => Ok(Stage::Tokens(quote! { struct Renamed; })),
+        source => Ok(source),
+    };
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(String::new()));
+    let validated = seen.clone();
+    let expander = Expander::new("transformed")
+        .transform(rename)
+        .transform_format()
+        .transform(move |stage| {
+            if let Stage::Source(ref source) = stage {
+                validated.lock().unwrap().push_str(source);
+            }
+            Ok(stage)
+        });
+    let expansion = testing::expand(&expander, tokens.clone())?;
+    assert!(expansion.content.contains("struct Renamed"));
+    assert_eq!(*seen.lock().unwrap(), expansion.content);
+
+    // a transform producing source replaces the built-in formatting
+    let custom =
+        Expander::new("transformed").transform(|_| Ok(Stage::Source("// custom\n".to_owned())));
+    assert_eq!(testing::expand(&custom, tokens)?.content, "// custom\n");
+    Ok(())
+}
+
+#[test]
+fn mapped_tokens() -> Result<(), std::io::Error> {
+    let expander = Expander::new("mapped").map_tokens(|tokens| {
+        quote! {
+            #tokens
+            #[cfg(test)]
+            mod tests {}
+        }
+    });
+    let expansion = testing::expand(&expander, quote! { struct Mapped; })?;
+    assert!(expansion.content.contains("struct Mapped"));
+    assert!(expansion.content.contains("mod tests"));
+    Ok(())
+}
+
+#[test]
+fn mirrored_output() -> Result<(), std::io::Error> {
+    let review = Path::new(env!("OUT_DIR")).join("review");
+    let _ = fs::remove_dir_all(&review);
+    let expander = Expander::new("mirrored").also_write_to([&review, &review.join("nested")]);
+    let expansion = testing::expand(&expander, quote! { struct Mirrored; })?;
+    let path = expansion.path.unwrap();
+    let name = path.file_name().unwrap();
+    for dir in [review.clone(), review.join("nested")] {
+        assert_eq!(fs::read(dir.join(name))?, fs::read(&path)?);
+    }
+    // replaced on the next write
+    testing::expand(&expander, quote! { struct Mirrored; })?;
+    Ok(())
+}
+
+#[test]
+fn teed_tokens() -> Result<(), std::io::Error> {
+    let dest_dir = Path::new(env!("OUT_DIR")).join("teed");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+    let tokens = quote! { struct Teed; };
+    let returned = Expander::new("teed")
+        .tee(true)
+        .write_to(tokens.clone(), &dest_dir)?;
+    assert_eq!(returned.to_string(), tokens.to_string());
+    let written = fs::read_dir(&dest_dir)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("teed-"))
+        .count();
+    assert_eq!(written, 1);
+    Ok(())
+}
+
+#[test]
+fn inline_switch() -> Result<(), std::io::Error> {
+    let dest_dir = Path::new(env!("OUT_DIR")).join("switched");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+    let tokens = quote! {
+        struct S { a: u8 }
+        const C: S = S { a: 1 };
+        pub(crate) const fn f() -> u8 { C.a }
+    };
+    let switched = Expander::new("switched")
+        .inline_switch(true)
+        .write_to(tokens, &dest_dir)?
+        .to_string();
+    assert_eq!(switched.matches("# [cfg (expander_inline)]").count(), 3);
+    assert_eq!(
+        switched.matches("# [cfg (not (expander_inline))]").count(),
+        1
+    );
+    assert!(switched.contains("# [cfg (expander_inline)] const C : S = S { a : 1 } ;"));
+    assert!(switched.contains("include !"));
+    Ok(())
+}
+
+#[test]
+fn debug_stages_dumped() -> Result<(), std::io::Error> {
+    let dest_dir = Path::new(env!("OUT_DIR")).join("staged");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+    Expander::new("staged")
+        .debug_stages(true)
+        .write_to(quote! { struct Staged; }, &dest_dir)?;
+    let raw = fs::read_to_string(dest_dir.join("staged.0.raw.rs"))?;
+    assert_eq!(raw, "struct Staged ;");
+    let pretty = dest_dir.join("staged.1.pretty.rs");
+    if cfg!(feature = "pretty") {
+        assert_eq!(fs::read_to_string(pretty)?, "struct Staged;\n");
+    } else {
+        assert!(!pretty.exists());
+    }
+    assert!(!dest_dir.join("staged.2.rustfmt.rs").exists());
+    Ok(())
+}
+
+#[test]
+fn diff_summary() {
+    let old = "fn a() {}\nfn b() {}\nfn c() {}\n";
+    let new = "fn a() {}\nfn B() {}\nfn c() {}\nfn d() {}\n";
+    assert_eq!(
+        diff::summary(old, new).as_deref(),
+        Some("+2 -1 lines, first difference at line 2: -fn b() {}")
+    );
+    assert_eq!(diff::summary(old, old), None);
+}
+
+#[test]
+fn stats_in_header() -> Result<(), std::io::Error> {
+    let dest_dir = Path::new(env!("OUT_DIR")).join("stats");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+    let tokens = quote! {
+        struct S;
+        impl S { fn a() {} fn b() {} }
+        mod inner { fn c() {} }
+    };
+    let expander = Expander::new("stats")
+        .stats(Stats::Header)
+        .fmt(Edition::_2021);
+    let dest = expander.expand(tokens, &dest_dir)?.0;
+    let content = fs::read_to_string(dest)?;
+    let (header, code) = content.split_once('\n').unwrap();
+    let summary = format!("{} bytes, {} lines", code.len(), code.lines().count());
+    assert_eq!(
+        header,
+        format!(
+            "/* expander-stats: {}, 3 items, 3 functions, 1 impl blocks */",
+            summary
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn environment_checked() {
+    let environment = check_environment();
+    assert!(environment.out_dir.is_dir());
+    assert_eq!(environment.writable, Ok(()));
+    assert_eq!(environment.locking, Ok(()));
+    assert!(environment.to_string().contains("writable: ok"));
+}
+
+#[test]
+fn leveled_diagnostics() {
+    use diagnostics::{render, Level};
+
+    assert_eq!(render(Level::Warn, "slow", false), "expander warn: slow");
+    assert_eq!(
+        render(Level::Debug, "linking", true),
+        "\x1b[2mexpander debug:\x1b[0m linking"
+    );
+}
+
+#[test]
+fn quiet_while_entered() {
+    use diagnostics::{quiet, Quiet};
+
+    assert!(!quiet());
+    {
+        let _quiet = Quiet::enter(true);
+        let _nested = Quiet::enter(false);
+        assert!(quiet());
+    }
+    assert!(!quiet());
+}
+
+#[test]
+fn out_dir_looked_up_at_runtime() -> Result<(), std::io::Error> {
+    // as set by cargo for crates with a build script, identical to the value at compile time
+    std::env::set_var("OUT_DIR", env!("OUT_DIR"));
+    let tokens = Expander::new("runtime_out_dir")
+        .try_write_to_out_dir(quote! { struct A; })?
+        .to_string();
+    assert!(tokens.contains(&format!("{}/runtime_out_dir-", env!("OUT_DIR"))));
+
+    let missing = MissingEnv {
+        var: "OUT_DIR".to_owned(),
+    };
+    assert!(missing.to_string().contains("add an empty `build.rs`"));
+    Ok(())
+}
+
+#[test]
+fn toolchain_detected() -> Result<(), std::io::Error> {
+    let workspace = Path::new(env!("OUT_DIR")).join("toolchain_workspace");
+    let member = workspace.join("member");
+    let _ = fs::remove_dir_all(&workspace);
+    fs::create_dir_all(&member)?;
+    fs::write(
+        workspace.join("rust-toolchain.toml"),
+        "[toolchain]\nchannel = \"nightly-2024-01-01\"\ncomponents = [\"rustfmt\"]\n",
+    )?;
+    fs::write(
+        workspace.join("Cargo.toml"),
+        "[workspace]\nmembers = [\"member\"]\n\n[workspace.package]\nedition = \"2018\"\n",
+    )?;
+    fs::write(
+        member.join("Cargo.toml"),
+        "[package]\nname = \"member\"\nedition.workspace = true\n",
+    )?;
+    let (channel, edition) = toolchain::detect(&member);
+    assert_eq!(channel, Channel::Nightly);
+    assert_eq!(edition.to_string(), "2018");
+
+    fs::write(
+        workspace.join("rust-toolchain.toml"),
+        "[toolchain]\nchannel = \"1.70\"\n",
+    )?;
+    fs::remove_file(member.join("Cargo.toml"))?;
+    let (channel, edition) = toolchain::detect(&member);
+    assert_eq!(channel, Channel::Default);
+    assert_eq!(edition.to_string(), "2021");
+    Ok(())
+}
+
+#[test]
+fn rustfmt_warnings_are_passed_on() -> Result<(), std::io::Error> {
+    if std::process::Command::new("rustfmt")
+        .arg("--version")
+        .output()
+        .is_err()
+    {
+        // rustfmt is not installed
+        return Ok(());
+    }
+    // picked up by rustfmt from its working directory, reading from stdin
+    let dir = Path::new(env!("OUT_DIR")).join("rustfmt_warnings");
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("rustfmt.toml"), "unknown_key = 1\n")?;
+    let mut command = rustfmt_command(Channel::Default, Edition::_2021, &[]);
+    command.current_dir(&dir);
+    let (formatted, messages) =
+        diagnostics::capture(|| run_rustfmt_on_content(b"struct  S;", command, false));
+    assert_eq!(formatted?, Some(b"struct S;\n".to_vec()));
+    assert!(messages.iter().any(|message| {
+        message.starts_with("expander warn: rustfmt reported\n") && message.contains("unknown_key")
+    }));
+    Ok(())
+}
+
+#[test]
+fn unstable_rustfmt_config() {
+    let unstable = [
+        ("wrap_comments".to_owned(), "true".to_owned()),
+        ("format_strings".to_owned(), "true".to_owned()),
+    ];
+    let args = |channel| {
+        rustfmt_command(channel, Edition::_2021, &unstable)
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        args(Channel::Nightly)[..4],
+        [
+            "+nightly",
+            "--unstable-features",
+            "--config",
+            "wrap_comments=true,format_strings=true"
+        ]
+    );
+    assert!(!args(Channel::Stable).contains(&"--unstable-features".to_owned()));
+}
+
+#[test]
+fn unstable_rustfmt_config_in_any_order() {
+    let unstable = |expander: Expander| match expander.rustfmt() {
+        RustFmt::Yes { unstable, .. } => unstable,
+        RustFmt::No => panic!("rustfmt is enabled"),
+    };
+    let config = [("wrap_comments", "true")];
+    let expected = vec![("wrap_comments".to_owned(), "true".to_owned())];
+    let before = Expander::new("unstable").fmt_unstable(config).fmt_full(
+        Channel::Nightly,
+        Edition::_2021,
+        false,
+    );
+    assert_eq!(unstable(before), expected);
+    let after = Expander::new("unstable")
+        .fmt_full(Channel::Nightly, Edition::_2021, false)
+        .fmt_unstable(config);
+    assert_eq!(unstable(after.clone()), expected);
+    assert_eq!(unstable(after.fmt(Edition::_2021)), expected);
+}
+
+#[test]
+fn format_chain_falls_back() -> Result<(), std::io::Error> {
+    let dest_dir = Path::new(env!("OUT_DIR")).join("chained");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+    // not an item, prettyplease fails
+    let (dest, _) = Expander::new("chained")
+        .format_chain([Formatting::PrettyPlease, Formatting::Raw], false)
+        .expand(quote! { let x = 1; }, &dest_dir)?;
+    assert_eq!(fs::read_to_string(dest)?, "let x = 1 ;");
+
+    let (dest, _) = Expander::new("chained")
+        .format_chain([Formatting::Raw, Formatting::PrettyPlease], true)
+        .expand(quote! { struct A; }, &dest_dir)?;
+    assert_eq!(fs::read_to_string(dest)?, "struct A ;");
+
+    let empty = Expander::new("chained")
+        .format_chain([], true)
+        .expand(quote! { struct A; }, &dest_dir);
+    assert_eq!(empty.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn strict_rejects_unparsable() -> Result<(), std::io::Error> {
+    let dest_dir = Path::new(env!("OUT_DIR")).join("strict");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+    // not an item, prettyplease fails
+    let e = Expander::new("strict")
+        .strict(true)
+        .expand(quote! { let x = 1; }, &dest_dir)
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains("does not parse"), "{}", e);
+
+    let e = Expander::new("strict")
+        .strict(true)
+        .format_chain([Formatting::PrettyPlease, Formatting::Raw], false)
+        .expand(quote! { let x = 1; }, &dest_dir)
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[test]
+fn exotic_tokens_round_trip() -> Result<(), std::io::Error> {
+    use proc_macro2::{Ident, Literal};
+    use std::str::FromStr;
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+
+        fn text(&mut self) -> String {
+            const CHARS: &[char] = &[
+                'a', 'ß', '"', '\\', '#', '\r', '\n', '*', '/', '\u{202e}', '\u{2066}',
+            ];
+            (0..self.below(8))
+                .map(|_| CHARS[self.below(CHARS.len())])
+                .collect()
+        }
+    }
+
+    // leaves with literals decoded and `concat!` flattened
+    fn leaves(tokens: TokenStream, flat: &mut Vec<String>) {
+        let mut tokens = tokens.into_iter();
+        while let Some(token) = tokens.next() {
+            match token {
+                TokenTree::Ident(ident) if ident == "concat" => {
+                    let _bang = tokens.next();
+                    if let Some(TokenTree::Group(group)) = tokens.next() {
+                        leaves(group.stream(), flat);
+                    }
+                }
+                TokenTree::Group(group) => {
+                    flat.push(format!("{:?}", group.delimiter()));
+                    leaves(group.stream(), flat);
+                    // formatters add trailing commas
+                    if flat.last().map_or(false, |last| last == ",") {
+                        flat.pop();
+                    }
+                    flat.push("end".to_owned());
+                }
+                TokenTree::Literal(literal) => {
+                    let repr = literal.to_string();
+                    flat.push(
+                        match syn::parse2::<syn::Lit>(TokenTree::from(literal).into()) {
+                            Ok(syn::Lit::Str(lit)) => format!("{:?}", lit.value()),
+                            Ok(syn::Lit::ByteStr(lit)) => format!("{:?}", lit.value()),
+                            _ => repr,
+                        },
+                    );
+                }
+                token => flat.push(token.to_string()),
+            }
+        }
+    }
+
+    let dest_dir = Path::new(env!("OUT_DIR")).join("exotic");
+    let _ = fs::remove_dir_all(&dest_dir);
+    fs::create_dir_all(&dest_dir)?;
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for i in 0..64 {
+        let doc = Literal::string(&rng.text());
+        let string = Literal::string(&rng.text());
+        // a lone carriage return can't be lexed within a raw string
+        let raw = rng.text().replace('\r', "\r\n");
+        let fence = "#".repeat(raw.matches('#').count() + 1);
+        let raw = Literal::from_str(&format!("r{0}\"{1}\"{0}", fence, raw)).unwrap();
+        let bytes = Literal::byte_string(b"\r\n\"#");
+        let ident = Ident::new(&format!("Grüße{}", i), Span::call_site());
+        let tokens = quote! {
+            #[doc = #doc]
+            pub const #ident: (&str, &str, &[u8]) = (#string, #raw, #bytes);
+            pub fn r#match(r#async: u8) -> u8 { r#async }
+            pub struct 函数;
+        };
+        let mut expected = Vec::new();
+        leaves(tokens.clone(), &mut expected);
+        for chain in [None, Some(Formatting::Raw)] {
+            let mut expander = Expander::new("exotic");
+            if let Some(formatting) = chain {
+                expander = expander.format_chain([formatting], false);
+            }
+            let (dest, _) = expander.expand(tokens.clone(), &dest_dir)?;
+            let content = fs::read_to_string(dest)?;
+            assert!(
+                !content.contains(|c| c == '\r' || ('\u{202a}'..='\u{2069}').contains(&c)),
+                "{:?}",
+                content
+            );
+            let mut written = Vec::new();
+            leaves(TokenStream::from_str(&content).unwrap(), &mut written);
+            assert_eq!(written, expected, "{:?}", content);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn expander_is_send_and_sync() {
+    fn shared<T: Send + Sync>() {}
+    shared::<Expander>();
+}
+
+#[test]
+fn unrepresentable_literal() {
+    // byte strings permit neither the codepoint nor its `\u{…}` escape
+    let repr = "b\"\u{202e}\"".to_owned();
+    let e = escape::escape_repr(repr.clone()).unwrap_err();
+    assert_eq!(e.literal, repr);
+    assert_eq!(
+        std::io::Error::from(e).kind(),
+        std::io::ErrorKind::InvalidData
+    );
+    assert_eq!(
+        escape::escape_repr("\"\u{202e}\"".to_owned())
+            .unwrap()
+            .to_string(),
+        "\"\\u{202e}\""
+    );
+}
@@ -0,0 +1,112 @@
+//! Detection of the `rustfmt` channel and edition of the crate being compiled, see
+//! [`Expander::fmt_detected`](crate::Expander::fmt_detected).
+
+use crate::fs;
+use crate::{Channel, Edition};
+use std::path::Path;
+
+/// The channel pinned by the `rust-toolchain.toml` closest to `manifest_dir` and the edition of
+/// the crate in `manifest_dir`.
+pub(crate) fn detect(manifest_dir: &Path) -> (Channel, Edition) {
+    let pinned = manifest_dir.ancestors().find_map(|dir| {
+        ["rust-toolchain.toml", "rust-toolchain"]
+            .iter()
+            .find_map(|name| fs::read_to_string(dir.join(name)).ok())
+            .map(|content| pinned_channel(&content))
+    });
+    let channel = match pinned.as_deref() {
+        Some(pinned) if pinned.starts_with("nightly") => Channel::Nightly,
+        Some(pinned) if pinned.starts_with("beta") => Channel::Beta,
+        Some(pinned) if pinned.starts_with("stable") => Channel::Stable,
+        // a version pin is resolved by rustup itself
+        _ => Channel::Default,
+    };
+    let edition = manifest_edition(manifest_dir)
+        .or_else(|| pinned.as_deref().and_then(newest_edition))
+        .unwrap_or_default();
+    (channel, edition)
+}
+
+/// The `channel` of the `[toolchain]` table, or the whole content of a legacy `rust-toolchain`.
+fn pinned_channel(content: &str) -> String {
+    let mut section = "";
+    for line in content.lines().map(str::trim) {
+        if line.starts_with('[') {
+            section = line;
+        } else if section == "[toolchain]" {
+            if let Some(channel) = value(line, "channel") {
+                return channel.to_owned();
+            }
+        }
+    }
+    if content.contains("[toolchain]") {
+        return String::new();
+    }
+    content.trim().to_owned()
+}
+
+/// The edition declared in the manifest in `manifest_dir`, or inherited from the workspace.
+///
+/// A manifest without an edition is of edition 2015.
+fn manifest_edition(manifest_dir: &Path) -> Option<Edition> {
+    let manifest = fs::read_to_string(manifest_dir.join("Cargo.toml")).ok()?;
+    let mut section = "";
+    let mut inherited = false;
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            section = line;
+        } else if section == "[package]" {
+            if let Some(edition) = value(line, "edition") {
+                return parse_edition(edition);
+            }
+            inherited |= line.replace(' ', "") == "edition.workspace=true";
+        }
+    }
+    if !inherited {
+        return Some(Edition::_2015);
+    }
+    manifest_dir.ancestors().skip(1).find_map(|dir| {
+        let manifest = fs::read_to_string(dir.join("Cargo.toml")).ok()?;
+        let mut section = "";
+        manifest.lines().map(str::trim).find_map(|line| {
+            if line.starts_with('[') {
+                section = line;
+                return None;
+            }
+            value(line, "edition")
+                .filter(|_| section == "[workspace.package]")
+                .and_then(parse_edition)
+        })
+    })
+}
+
+/// The newest edition supported by the toolchain of version `pinned`, i.e. `1.70.0`.
+fn newest_edition(pinned: &str) -> Option<Edition> {
+    let mut version = pinned.split('.');
+    let major = version.next()?.parse::<u32>().ok()?;
+    let minor = version.next()?.parse::<u32>().ok()?;
+    Some(match (major, minor) {
+        (1, 56..) => Edition::_2021,
+        (1, 31..) => Edition::_2018,
+        _ => Edition::_2015,
+    })
+}
+
+fn parse_edition(edition: &str) -> Option<Edition> {
+    match edition {
+        "2015" => Some(Edition::_2015),
+        "2018" => Some(Edition::_2018),
+        // newer editions are formatted like 2021
+        _ if edition.parse::<u32>().map_or(false, |year| year >= 2021) => Some(Edition::_2021),
+        _ => None,
+    }
+}
+
+/// The string value of `key` if `line` assigns one.
+fn value<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let (assigned, value) = line.split_once('=')?;
+    if assigned.trim() != key {
+        return None;
+    }
+    Some(value.trim().trim_matches(|c| c == '"' || c == '\''))
+}
@@ -0,0 +1,58 @@
+//! Customization of the expansion pipeline, see [`Expander::transform`](crate::Expander::transform).
+
+use proc_macro2::TokenStream;
+use std::sync::Arc;
+
+/// Content passed through the [`Transform`]s of an [`Expander`](crate::Expander).
+#[derive(Debug, Clone)]
+pub enum Stage {
+    /// Tokens, before formatting.
+    Tokens(TokenStream),
+    /// Formatted source code, written as is apart from the header and line endings.
+    Source(String),
+}
+
+/// Step of the expansion pipeline, registered with [`Expander::transform`](crate::Expander::transform).
+///
+/// Transforms are applied in order of registration to the tokens, after the built-in attribute
+/// and module passes. Returning [`Stage::Source`] replaces the built-in formatting, all later
+/// transforms receive the source.
+///
+/// ```
+/// use expander::{Expander, Stage};
+///
+/// let expander = Expander::new("baz").transform(|stage| match stage {
+///     Stage::Source(source) => Ok(Stage::Source(source.replace("TODO", "DONE"))),
+///     tokens => Ok(tokens),
+/// });
+/// ```
+pub trait Transform: Send + Sync {
+    /// Transform the `content`, passing stages it is not concerned with through unchanged.
+    fn apply(&self, content: Stage) -> Result<Stage, std::io::Error>;
+}
+
+impl<F> Transform for F
+where
+    F: Fn(Stage) -> Result<Stage, std::io::Error> + Send + Sync,
+{
+    fn apply(&self, content: Stage) -> Result<Stage, std::io::Error> {
+        self(content)
+    }
+}
+
+/// Step of the pipeline of an [`Expander`](crate::Expander).
+#[derive(Clone)]
+pub(crate) enum Step {
+    Transform(Arc<dyn Transform>),
+    /// The built-in formatting.
+    Format,
+}
+
+impl std::fmt::Debug for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transform(_) => write!(f, "Transform"),
+            Self::Format => write!(f, "Format"),
+        }
+    }
+}
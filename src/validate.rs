@@ -0,0 +1,82 @@
+//! Validation of generated code.
+
+use crate::fs;
+use std::path::{Path, PathBuf};
+
+/// Generated code which does not parse as a Rust file.
+///
+/// Returned wrapped in a [`std::io::Error`] of kind [`std::io::ErrorKind::InvalidData`], use
+/// [`get_ref`](std::io::Error::get_ref) and `downcast_ref` to access it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCode {
+    /// The file containing the invalid code, if it was written already.
+    pub path: Option<PathBuf>,
+    /// One based line of the error, `0` if unknown.
+    pub line: usize,
+    /// Zero based column of the error.
+    pub column: usize,
+    /// The message of the parser.
+    pub message: String,
+}
+
+impl InvalidCode {
+    pub(crate) fn new(path: Option<&Path>, error: &syn::Error) -> Self {
+        let start = error.span().start();
+        Self {
+            path: path.map(Path::to_owned),
+            line: start.line,
+            column: start.column,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expander: generated code is invalid")?;
+        if let Some(ref path) = self.path {
+            write!(f, " in {}", path.display())?;
+            if self.line > 0 {
+                write!(f, ":{}:{}", self.line, self.column + 1)?;
+            }
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for InvalidCode {}
+
+impl From<InvalidCode> for std::io::Error {
+    fn from(invalid: InvalidCode) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, invalid)
+    }
+}
+
+/// Parse `tokens` as a Rust file, retaining their spans in the error.
+pub(crate) fn parse(tokens: &proc_macro2::TokenStream) -> Result<(), syn::Error> {
+    syn::parse2::<syn::File>(tokens.clone())?;
+    Ok(())
+}
+
+/// The `error` of parsing tokens, located within their serialization `content` dumped to `dump`.
+///
+/// The spans of the tokens refer to the input rather than the dump, so `content` is parsed again
+/// for a location within it, which is unknown if `content` parses after all.
+pub(crate) fn dumped(dump: &Path, content: &str, error: &syn::Error) -> InvalidCode {
+    match syn::parse_file(content) {
+        Err(e) => InvalidCode::new(Some(dump), &e),
+        Ok(_) => InvalidCode {
+            path: Some(dump.to_owned()),
+            line: 0,
+            column: 0,
+            message: error.to_string(),
+        },
+    }
+}
+
+/// Read the written file at `path` back and ensure it parses as a Rust file.
+pub(crate) fn read_back(path: &Path) -> Result<(), std::io::Error> {
+    let content = fs::read_to_string(path)?;
+    syn::parse_file(&content).map_err(|e| InvalidCode::new(Some(path), &e))?;
+    Ok(())
+}
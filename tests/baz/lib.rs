@@ -18,3 +18,23 @@ fn baz2(input: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
         .write_to_out_dir(modified).expect("No IO error happens. qed");
     expanded
 }
+
+#[proc_macro_attribute]
+pub fn baz_merged(_attr: proc_macro::TokenStream, input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = proc_macro2::TokenStream::from(input);
+    let modified = quote::quote!{
+        #[derive(Debug, Clone, Copy)]
+        #input
+    };
+
+    Expander::new("baz")
+        .verbose(true)
+        .merge(true)
+        .write_to_out_dir(modified).expect("No IO error happens. qed").into()
+}
+
+#[proc_macro]
+pub fn include_merged(_input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    Expander::new("baz")
+        .include_merged_out_dir().expect("No IO error happens. qed").into()
+}
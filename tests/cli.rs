@@ -0,0 +1,123 @@
+//! Runs the `expander-cli` binary against manifests in a temporary directory.
+#![cfg(feature = "cli")]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn cli(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_expander-cli"))
+        .args(args)
+        .output()
+        .expect("Binary is built. qed")
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Temporary directory is writable. qed");
+    dir
+}
+
+/// Write a manifest of `(path, digest, process)` entries to `path`.
+fn write_manifest(path: &Path, entries: &[(&Path, &str, u32)]) {
+    let entries = entries
+        .iter()
+        .map(|(file, digest, process)| {
+            serde_json::json!({
+                "path": file,
+                "crate_name": "krate",
+                "digest": digest,
+                "size": 13,
+                "process": process,
+            })
+        })
+        .collect::<Vec<_>>();
+    std::fs::write(path, serde_json::json!({ "entries": entries }).to_string())
+        .expect("Temporary directory is writable. qed");
+}
+
+#[test]
+fn arguments() {
+    let dir = temp_dir("cli_arguments");
+    let manifest = dir.join("expander-manifest.json");
+    write_manifest(&manifest, &[]);
+
+    assert!(stdout(&cli(&["help"])).starts_with("Usage: expander-cli"));
+    assert!(
+        stdout(&cli(&["--manifest", manifest.to_str().unwrap(), "list"]))
+            .contains("0 expansions, 0 bytes in total")
+    );
+    for invalid in [
+        &["--manifest"][..],
+        &["clean", "--force"],
+        &["show"],
+        &["diff", "old"],
+        &["unknown"],
+    ] {
+        let output = cli(invalid);
+        assert!(!output.status.success(), "{:?}", invalid);
+        assert!(String::from_utf8_lossy(&output.stderr).starts_with("expander-cli: "));
+    }
+}
+
+#[test]
+fn diff() {
+    let dir = temp_dir("cli_diff");
+    let (a, b, c) = (dir.join("a.rs"), dir.join("b.rs"), dir.join("c.rs"));
+    let (old, new) = (dir.join("old.json"), dir.join("new.json"));
+    write_manifest(&old, &[(&a, "1", 1), (&b, "1", 1)]);
+    write_manifest(&new, &[(&b, "2", 2), (&c, "1", 2)]);
+
+    let diff = stdout(&cli(&[
+        "diff",
+        old.to_str().unwrap(),
+        new.to_str().unwrap(),
+    ]));
+    assert_eq!(
+        diff,
+        format!(
+            "- {} (13 bytes)\n~ {} (13 -> 13 bytes)\n+ {} (13 bytes)\n2 -> 2 expansions, 26 -> 26 bytes in total\n",
+            a.display(),
+            b.display(),
+            c.display()
+        )
+    );
+}
+
+#[test]
+fn clean_dry_run() {
+    let dir = temp_dir("cli_clean");
+    let manifest = dir.join("expander-manifest.json");
+    let superseded = dir.join("gen-0123456789ab.rs");
+    let sidecar = dir.join("gen-0123456789ab.rs.meta.json");
+    let current = dir.join("gen-0123456789ac.rs");
+    let foreign = dir.join("foreign-0123456789ab.rs");
+    for file in [&superseded, &sidecar, &current, &foreign] {
+        std::fs::write(file, "pub struct A;").expect("Temporary directory is writable. qed");
+    }
+    write_manifest(&manifest, &[(&superseded, "1", 1), (&current, "2", 2)]);
+    let manifest = manifest.to_str().unwrap();
+
+    let removed = format!(
+        "removing {}\nremoving {}\n",
+        superseded.display(),
+        sidecar.display()
+    );
+    assert_eq!(
+        stdout(&cli(&["--manifest", manifest, "clean", "--dry-run"])),
+        removed
+    );
+    assert!([&superseded, &sidecar, &current, &foreign]
+        .iter()
+        .all(|file| file.is_file()));
+
+    assert_eq!(stdout(&cli(&["--manifest", manifest, "clean"])), removed);
+    assert!(!superseded.exists() && !sidecar.exists());
+    assert!(current.is_file() && foreign.is_file());
+    assert!(stdout(&cli(&["--manifest", manifest, "list"])).contains("1 expansions"));
+}
@@ -0,0 +1 @@
+struct Unit;
@@ -4,8 +4,18 @@ struct A;
 #[baz::baz]
 struct B;
 
+#[baz::baz_merged]
+struct C;
+
+#[baz::baz_merged]
+struct D;
+
 #[test]
 fn main() {
     let _a = A;
     let _b = B;
+    let _c = C;
+    let _d = D;
 }
+
+baz::include_merged!();
@@ -0,0 +1,3 @@
+pub struct Snapshot {
+    x: [u8; 32],
+}